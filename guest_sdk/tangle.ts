@@ -0,0 +1,20 @@
+// This file is AssemblyScript
+// Helpers for code running inside Tangle. Copy this file into your project and import from it.
+
+@external("wasm_guardian", "caller_id")
+declare function wasm_guardian_caller_id(): f64;
+
+@external("wasm_guardian", "event_time")
+declare function wasm_guardian_event_time(): f64;
+
+// The id of the peer whose call is running. `0` for calls Tangle makes itself, like `fixed_update`.
+// The same on every peer, including when the call is replayed after a rollback.
+export function caller_id(): f64 {
+    return wasm_guardian_caller_id();
+}
+
+// The time in milliseconds the running call was scheduled for.
+// The same on every peer, including when the call is replayed after a rollback.
+export function event_time(): f64 {
+    return wasm_guardian_event_time();
+}
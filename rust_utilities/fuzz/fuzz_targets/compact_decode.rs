@@ -0,0 +1,32 @@
+//! Decodes a sequence of compact messages on one connection, with export signatures chosen by
+//! the first byte.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_utilities::compact_encoding::{ArgType, CompactDecoder};
+
+const SIGNATURES: &[&[ArgType]] = &[
+    &[],
+    &[ArgType::I32],
+    &[ArgType::I64, ArgType::F64],
+    &[ArgType::F32, ArgType::I32, ArgType::I64, ArgType::F64],
+];
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&export_count, messages)) = data.split_first() else {
+        return;
+    };
+    let signature_of = |index: u32| {
+        (index < export_count as u32).then(|| SIGNATURES[index as usize % SIGNATURES.len()])
+    };
+
+    // Each message is prefixed with its length, as separate messages on a data channel.
+    let mut decoder = CompactDecoder::new();
+    let mut messages = messages;
+    while let Some((&length, rest)) = messages.split_first() {
+        let length = (length as usize).min(rest.len());
+        let _ = decoder.decode(&rest[..length], signature_of);
+        messages = &rest[length..];
+    }
+});
@@ -0,0 +1,16 @@
+//! Messages come from peers. Anything that decodes must encode to bytes that decode to the
+//! same message.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_utilities::message_encoding::{decode_message, encode_message};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(message) = decode_message(data) {
+        // Compared as bytes because messages can hold NaNs.
+        let encoded = encode_message(&message);
+        let decoded = decode_message(&encoded).expect("an encoded message must decode");
+        assert_eq!(encoded, encode_message(&decoded));
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+const LIMIT: usize = 1 << 20;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(output) = rust_utilities::gzip_decode_with_limit(data, LIMIT) {
+        assert!(output.len() <= LIMIT);
+    }
+});
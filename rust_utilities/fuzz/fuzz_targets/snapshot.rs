@@ -0,0 +1,43 @@
+//! Snapshots come from peers when joining a room. Reads them directly, and through the
+//! `bake_snapshot` export the host calls, including its buffer handling.
+
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use rust_utilities::message_encoding::MessageReader;
+
+fn module() -> &'static [u8] {
+    static MODULE: OnceLock<Vec<u8>> = OnceLock::new();
+    MODULE.get_or_init(|| {
+        wat::parse_str(
+            r#"(module
+                (memory (export "memory") 1 2)
+                (global (export "wg_global_0") (mut i32) (i32.const 0))
+                (global (export "wg_global_1") (mut i64) (i64.const 0))
+                (global (export "constant") f64 (f64.const 0)))"#,
+        )
+        .unwrap()
+    })
+}
+
+fn write_buffer(data: &[u8]) -> u32 {
+    let handle = rust_utilities::buffer_new(data.len());
+    let pointer = rust_utilities::buffer_ptr(handle);
+    unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), pointer, data.len()) };
+    handle
+}
+
+fuzz_target!(|data: &[u8]| {
+    let _ = MessageReader::new(data).read_wasm_snapshot();
+    let _ = MessageReader::new(data).read_time_machine_state();
+
+    let input = write_buffer(module());
+    let output = rust_utilities::buffer_new(0);
+    let snapshot = write_buffer(data);
+    rust_utilities::bake_snapshot(input, output, snapshot);
+    for handle in [input, output, snapshot] {
+        rust_utilities::buffer_free(handle);
+    }
+});
@@ -0,0 +1,159 @@
+//! The memory each call read and wrote, reported by modules transformed with `track_reads` and
+//! `track_changes`, so a host can tell whether a call that arrives late changes anything the
+//! calls after it saw, and only roll back when it does.
+//!
+//! Encoded for the host as little-endian u32s: the number of read ranges, each range's
+//! address and size, then the same for written ranges.
+
+use crate::ErrorCode;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessSet {
+    /// Sorted, non-overlapping `(start, end)` ranges.
+    reads: Vec<(u64, u64)>,
+    writes: Vec<(u64, u64)>,
+}
+
+impl AccessSet {
+    pub fn read(&mut self, address: u32, size: u32) {
+        insert(&mut self.reads, address, size);
+    }
+
+    pub fn write(&mut self, address: u32, size: u32) {
+        insert(&mut self.writes, address, size);
+    }
+
+    /// Whether running `self` before `later`, instead of after it, could change what either
+    /// of them does: if one writes memory that the other reads or writes.
+    pub fn conflicts_with(&self, later: &AccessSet) -> bool {
+        overlaps(&self.writes, &later.reads)
+            || overlaps(&self.writes, &later.writes)
+            || overlaps(&self.reads, &later.writes)
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        for ranges in [&self.reads, &self.writes] {
+            out.extend_from_slice(&(ranges.len() as u32).to_le_bytes());
+            for (start, end) in ranges {
+                out.extend_from_slice(&(*start as u32).to_le_bytes());
+                let size = (end - start).min(u32::MAX as u64) as u32;
+                out.extend_from_slice(&size.to_le_bytes());
+            }
+        }
+    }
+
+    /// Reads one set from the start of `data` and returns the rest.
+    pub fn decode(data: &[u8]) -> Result<(AccessSet, &[u8]), ErrorCode> {
+        let mut data = data;
+        let mut read_u32 = || -> Result<u32, ErrorCode> {
+            let (value, rest) = data.split_first_chunk::<4>().ok_or(ErrorCode::Truncated)?;
+            data = rest;
+            Ok(u32::from_le_bytes(*value))
+        };
+        let mut set = AccessSet::default();
+        for write in [false, true] {
+            let count = read_u32()?;
+            for _ in 0..count {
+                let address = read_u32()?;
+                let size = read_u32()?;
+                if write {
+                    set.write(address, size);
+                } else {
+                    set.read(address, size);
+                }
+            }
+        }
+        Ok((set, data))
+    }
+}
+
+/// Returns the index of the first set in `later` that `late` conflicts with, where `later`
+/// holds the sets of the calls that ran after `late` should have, in order.
+pub fn first_conflict(late: &AccessSet, later: &[AccessSet]) -> Option<usize> {
+    later.iter().position(|set| late.conflicts_with(set))
+}
+
+/// Adds a range, merging it with any it overlaps or touches.
+fn insert(ranges: &mut Vec<(u64, u64)>, address: u32, size: u32) {
+    if size == 0 {
+        return;
+    }
+    let mut start = address as u64;
+    let mut end = start + size as u64;
+    let first = ranges.partition_point(|range| range.1 < start);
+    let mut last = first;
+    while last < ranges.len() && ranges[last].0 <= end {
+        start = start.min(ranges[last].0);
+        end = end.max(ranges[last].1);
+        last += 1;
+    }
+    ranges.splice(first..last, [(start, end)]);
+}
+
+fn overlaps(a: &[(u64, u64)], b: &[(u64, u64)]) -> bool {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].0 < b[j].1 && b[j].0 < a[i].1 {
+            return true;
+        }
+        if a[i].1 <= b[j].1 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    false
+}
+
+#[test]
+fn ranges_merge() {
+    let mut set = AccessSet::default();
+    set.write(10, 4);
+    set.write(20, 4);
+    set.write(14, 6);
+    set.write(0, 0);
+    set.write(100, 1);
+    assert_eq!(set.writes, [(10, 24), (100, 101)]);
+}
+
+#[test]
+fn conflicts() {
+    let mut late = AccessSet::default();
+    late.read(0, 8);
+    late.write(100, 4);
+
+    let mut reads_written = AccessSet::default();
+    reads_written.read(102, 1);
+    let mut writes_read = AccessSet::default();
+    writes_read.write(4, 4);
+    let mut adjacent = AccessSet::default();
+    adjacent.read(104, 4);
+    adjacent.write(8, 4);
+    let mut only_reads = AccessSet::default();
+    only_reads.read(0, 8);
+
+    assert!(late.conflicts_with(&reads_written));
+    assert!(late.conflicts_with(&writes_read));
+    assert!(!late.conflicts_with(&adjacent));
+    assert!(!late.conflicts_with(&only_reads));
+    assert_eq!(
+        first_conflict(&late, &[adjacent, only_reads, writes_read]),
+        Some(2)
+    );
+}
+
+#[test]
+fn encoding_round_trips() {
+    let mut set = AccessSet::default();
+    set.read(8, 4);
+    set.write(u32::MAX - 3, 4);
+    set.write(0, 16);
+    let mut encoded = Vec::new();
+    set.encode(&mut encoded);
+    encoded.push(7);
+    assert_eq!(AccessSet::decode(&encoded), Ok((set, &[7][..])));
+    assert_eq!(
+        AccessSet::decode(&encoded[..encoded.len() - 3]),
+        Err(ErrorCode::Truncated)
+    );
+}
@@ -0,0 +1,187 @@
+use core::cell::RefCell;
+
+/// Identifies a buffer owned by `rust_utilities` on behalf of the host.
+/// `0` is never a valid handle so the host can use it as a sentinel.
+pub type BufferHandle = u32;
+
+/// Status returned by every export that operates on buffers.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Ok = 0,
+    /// The handle does not refer to a live buffer.
+    InvalidHandle = 1,
+    /// The input buffer could not be processed by the requested operation.
+    InvalidInput = 2,
+    /// Decoding would produce more bytes than the caller allowed.
+    OutputTooLarge = 3,
+    /// The input ended before a complete stream was read.
+    Truncated = 4,
+    /// The input is not valid for the requested encoding.
+    Corrupt = 5,
+    /// The input was written by an incompatible version of the encoding.
+    UnsupportedVersion = 6,
+}
+
+impl From<Result<(), ErrorCode>> for ErrorCode {
+    fn from(result: Result<(), ErrorCode>) -> Self {
+        match result {
+            Ok(()) => ErrorCode::Ok,
+            Err(e) => e,
+        }
+    }
+}
+
+thread_local! {
+    /// Every buffer the host has allocated and not yet freed.
+    /// Handle `n` refers to slot `n - 1`. Freed slots are reused.
+    static BUFFERS: RefCell<Vec<Option<Vec<u8>>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Stores `data` in a new buffer and returns its handle.
+pub fn allocate(data: Vec<u8>) -> BufferHandle {
+    BUFFERS.with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        let index = match buffers.iter().position(|b| b.is_none()) {
+            Some(index) => {
+                buffers[index] = Some(data);
+                index
+            }
+            None => {
+                buffers.push(Some(data));
+                buffers.len() - 1
+            }
+        };
+        (index + 1) as BufferHandle
+    })
+}
+
+/// Releases a buffer. The handle may be reused by a later `allocate`.
+pub fn free(handle: BufferHandle) -> Result<(), ErrorCode> {
+    BUFFERS.with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        let slot = slot_index(handle)
+            .and_then(|index| buffers.get_mut(index))
+            .filter(|b| b.is_some())
+            .ok_or(ErrorCode::InvalidHandle)?;
+        *slot = None;
+        Ok(())
+    })
+}
+
+/// Runs `f` with mutable access to the buffer referred to by `handle`.
+pub fn with_buffer<R>(
+    handle: BufferHandle,
+    f: impl FnOnce(&mut Vec<u8>) -> R,
+) -> Result<R, ErrorCode> {
+    BUFFERS.with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        let buffer = slot_index(handle)
+            .and_then(|index| buffers.get_mut(index))
+            .and_then(|b| b.as_mut())
+            .ok_or(ErrorCode::InvalidHandle)?;
+        Ok(f(buffer))
+    })
+}
+
+/// Reads the `input` buffer, runs `operation` on it, and replaces the contents of `output`
+/// with the result. `input` and `output` may be the same handle.
+///
+/// The output handle is checked before the operation runs so that invalid handles
+/// don't waste work.
+pub fn run_operation(
+    input: BufferHandle,
+    output: BufferHandle,
+    operation: impl FnOnce(&[u8]) -> Result<Vec<u8>, ErrorCode>,
+) -> ErrorCode {
+    run_operation_with_outputs(input, [output], |input| Ok([operation(input)?]))
+}
+
+/// The same as [run_operation] for operations that produce more than one result.
+pub fn run_operation_with_outputs<const N: usize>(
+    input: BufferHandle,
+    outputs: [BufferHandle; N],
+    operation: impl FnOnce(&[u8]) -> Result<[Vec<u8>; N], ErrorCode>,
+) -> ErrorCode {
+    let result = outputs
+        .iter()
+        .try_for_each(|output| with_buffer(*output, |_| ()))
+        .and_then(|_| with_buffer(input, |input| operation(input)))
+        .and_then(|result| result);
+    match result {
+        Ok(results) => outputs
+            .into_iter()
+            .zip(results)
+            .try_for_each(|(output, result)| with_buffer(output, |output| *output = result))
+            .into(),
+        Err(e) => e,
+    }
+}
+
+fn slot_index(handle: BufferHandle) -> Option<usize> {
+    (handle as usize).checked_sub(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each test runs on its own thread, so starts with no buffers.
+
+    #[test]
+    fn allocate_write_read() {
+        let handle = allocate(vec![1, 2]);
+        assert_ne!(handle, 0);
+        with_buffer(handle, |b| b.push(3)).unwrap();
+        assert_eq!(with_buffer(handle, |b| b.clone()), Ok(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn free_reuses_handle() {
+        let a = allocate(vec![1]);
+        let b = allocate(vec![2]);
+        assert_ne!(a, b);
+        free(a).unwrap();
+        assert_eq!(allocate(vec![3]), a);
+        assert_eq!(with_buffer(a, |b| b.clone()), Ok(vec![3]));
+        assert_eq!(with_buffer(b, |b| b.clone()), Ok(vec![2]));
+    }
+
+    #[test]
+    fn invalid_handles() {
+        let handle = allocate(Vec::new());
+        let input = allocate(vec![1]);
+        free(handle).unwrap();
+        assert_eq!(free(handle), Err(ErrorCode::InvalidHandle));
+        assert_eq!(with_buffer(handle, |_| ()), Err(ErrorCode::InvalidHandle));
+        assert_eq!(with_buffer(0, |_| ()), Err(ErrorCode::InvalidHandle));
+        assert_eq!(with_buffer(99, |_| ()), Err(ErrorCode::InvalidHandle));
+
+        let mut ran = false;
+        let result = run_operation(input, handle, |input| {
+            ran = true;
+            Ok(input.to_vec())
+        });
+        assert_eq!(result, ErrorCode::InvalidHandle);
+        assert!(!ran);
+        assert_eq!(
+            run_operation(handle, input, |input| Ok(input.to_vec())),
+            ErrorCode::InvalidHandle
+        );
+    }
+
+    #[test]
+    fn operation_in_place() {
+        let handle = allocate(vec![1, 2, 3]);
+        let result = run_operation(handle, handle, |input| {
+            Ok(input.iter().rev().copied().collect())
+        });
+        assert_eq!(result, ErrorCode::Ok);
+        assert_eq!(with_buffer(handle, |b| b.clone()), Ok(vec![3, 2, 1]));
+
+        // A failed operation leaves the buffer as it was.
+        let result = run_operation(handle, handle, |_| Err(ErrorCode::InvalidInput));
+        assert_eq!(result, ErrorCode::InvalidInput);
+        assert_eq!(with_buffer(handle, |b| b.clone()), Ok(vec![3, 2, 1]));
+    }
+}
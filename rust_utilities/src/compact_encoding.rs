@@ -0,0 +1,362 @@
+//! A smaller encoding for the two messages sent most often: `WasmCall` and `TimeProgressed`.
+//!
+//! Layout after the `MessageType` byte:
+//!
+//! * `CompactWasmCall`: version, varint export index, time, varint argument count, arguments.
+//! * `CompactTimeProgressed`: version, time.
+//!
+//! Times are written relative to the time of the previous message in the same direction,
+//! so each peer connection needs its own `CompactEncoder` and `CompactDecoder`.
+//! Arguments are written according to the export's parameter types.
+
+use crate::message_encoding::{MessageReader, MessageType, MessageWriter, TaggedNumber};
+use crate::ErrorCode;
+
+/// Messages with any other version are rejected with `ErrorCode::UnsupportedVersion`.
+pub const COMPACT_ENCODING_VERSION: u8 = 1;
+
+/// The Wasm parameter types an export can accept from the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompactMessage {
+    WasmCall {
+        function_index: u32,
+        time: f64,
+        /// `I64` for i64 parameters and `F64` for everything else.
+        args: Vec<TaggedNumber>,
+    },
+    TimeProgressed {
+        time: f64,
+    },
+}
+
+#[repr(u8)]
+enum TimeTag {
+    /// A non-negative whole number of milliseconds after the previous time, as a varint.
+    DeltaVarint = 0,
+    /// An offset from the previous time that is exactly representable as an f32.
+    DeltaF32 = 1,
+    /// The full time.
+    Absolute = 2,
+}
+
+#[derive(Default)]
+pub struct CompactEncoder {
+    previous_time: f64,
+}
+
+impl CompactEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `signature_of` returns the parameter types of an export.
+    ///
+    /// Fails with `ErrorCode::InvalidInput` if the export has no signature, the argument count
+    /// doesn't match, or an argument can't be represented exactly as its parameter type.
+    pub fn encode<'s>(
+        &mut self,
+        message: &CompactMessage,
+        signature_of: impl Fn(u32) -> Option<&'s [ArgType]>,
+    ) -> Result<Vec<u8>, ErrorCode> {
+        let mut writer = MessageWriter::new();
+        match message {
+            CompactMessage::WasmCall {
+                function_index,
+                time,
+                args,
+            } => {
+                let signature = signature_of(*function_index).ok_or(ErrorCode::InvalidInput)?;
+                if signature.len() != args.len() {
+                    return Err(ErrorCode::InvalidInput);
+                }
+
+                // Arguments are written first so that a failure doesn't advance `previous_time`.
+                let mut args_writer = MessageWriter::new();
+                for (arg, arg_type) in args.iter().zip(signature) {
+                    write_arg(&mut args_writer, *arg, *arg_type)?;
+                }
+
+                writer.write_u8(MessageType::CompactWasmCall as u8);
+                writer.write_u8(COMPACT_ENCODING_VERSION);
+                writer.write_var_u32(*function_index);
+                self.write_time(&mut writer, *time);
+                writer.write_var_u32(args.len() as u32);
+                writer.write_raw_bytes(&args_writer.output);
+            }
+            CompactMessage::TimeProgressed { time } => {
+                writer.write_u8(MessageType::CompactTimeProgressed as u8);
+                writer.write_u8(COMPACT_ENCODING_VERSION);
+                self.write_time(&mut writer, *time);
+            }
+        }
+        Ok(writer.output)
+    }
+
+    fn write_time(&mut self, writer: &mut MessageWriter, time: f64) {
+        let previous = self.previous_time;
+        self.previous_time = time;
+
+        // Each candidate is only used if decoding it reproduces `time` bit for bit.
+        let delta = time - previous;
+        if (0.0..=MAX_SAFE_INTEGER).contains(&delta)
+            && delta.fract() == 0.0
+            && (previous + delta).to_bits() == time.to_bits()
+        {
+            writer.write_u8(TimeTag::DeltaVarint as u8);
+            writer.write_var_u64(delta as u64);
+            return;
+        }
+
+        let delta_f32 = delta as f32;
+        if (previous + delta_f32 as f64).to_bits() == time.to_bits() {
+            writer.write_u8(TimeTag::DeltaF32 as u8);
+            writer.write_f32(delta_f32);
+            return;
+        }
+
+        writer.write_u8(TimeTag::Absolute as u8);
+        writer.write_f64(time);
+    }
+}
+
+#[derive(Default)]
+pub struct CompactDecoder {
+    previous_time: f64,
+}
+
+impl CompactDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes a complete compact message, including its leading `MessageType` byte.
+    ///
+    /// Calls to exports without a signature, or with the wrong number of arguments,
+    /// are rejected as `ErrorCode::Corrupt`.
+    pub fn decode<'s>(
+        &mut self,
+        data: &[u8],
+        signature_of: impl Fn(u32) -> Option<&'s [ArgType]>,
+    ) -> Result<CompactMessage, ErrorCode> {
+        let mut reader = MessageReader::new(data);
+        let message_type = MessageType::from_u8(reader.read_u8()?);
+        if !matches!(
+            message_type,
+            Some(MessageType::CompactWasmCall) | Some(MessageType::CompactTimeProgressed)
+        ) {
+            return Err(ErrorCode::Corrupt);
+        }
+        if reader.read_u8()? != COMPACT_ENCODING_VERSION {
+            return Err(ErrorCode::UnsupportedVersion);
+        }
+
+        let message = if message_type == Some(MessageType::CompactWasmCall) {
+            let function_index = reader.read_var_u32()?;
+            let time = self.read_time(&mut reader)?;
+            let signature = signature_of(function_index).ok_or(ErrorCode::Corrupt)?;
+            if reader.read_var_u32()? as usize != signature.len() {
+                return Err(ErrorCode::Corrupt);
+            }
+            let args = signature
+                .iter()
+                .map(|arg_type| read_arg(&mut reader, *arg_type))
+                .collect::<Result<_, _>>()?;
+            CompactMessage::WasmCall {
+                function_index,
+                time,
+                args,
+            }
+        } else {
+            CompactMessage::TimeProgressed {
+                time: self.read_time(&mut reader)?,
+            }
+        };
+
+        if reader.offset != data.len() {
+            return Err(ErrorCode::Corrupt);
+        }
+        Ok(message)
+    }
+
+    fn read_time(&mut self, reader: &mut MessageReader) -> Result<f64, ErrorCode> {
+        let tag = reader.read_u8()?;
+        let time = if tag == TimeTag::DeltaVarint as u8 {
+            self.previous_time + reader.read_var_u64()? as f64
+        } else if tag == TimeTag::DeltaF32 as u8 {
+            self.previous_time + reader.read_f32()? as f64
+        } else if tag == TimeTag::Absolute as u8 {
+            reader.read_f64()?
+        } else {
+            return Err(ErrorCode::Corrupt);
+        };
+        self.previous_time = time;
+        Ok(time)
+    }
+}
+
+/// The largest integer an f64 can represent along with all smaller integers.
+const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
+fn write_arg(
+    writer: &mut MessageWriter,
+    arg: TaggedNumber,
+    arg_type: ArgType,
+) -> Result<(), ErrorCode> {
+    match (arg_type, arg) {
+        (ArgType::I32, TaggedNumber::F64(v)) => {
+            let i = v as i32;
+            if i as f64 != v || (v == 0.0 && v.is_sign_negative()) {
+                return Err(ErrorCode::InvalidInput);
+            }
+            writer.write_var_i64(i as i64);
+        }
+        (ArgType::I64, TaggedNumber::I64(v)) => writer.write_var_i64(v),
+        (ArgType::F32, TaggedNumber::F64(v)) => {
+            let f = v as f32;
+            if (f as f64).to_bits() != v.to_bits() {
+                return Err(ErrorCode::InvalidInput);
+            }
+            writer.write_f32(f);
+        }
+        (ArgType::F64, TaggedNumber::F64(v)) => writer.write_f64(v),
+        _ => return Err(ErrorCode::InvalidInput),
+    }
+    Ok(())
+}
+
+fn read_arg(reader: &mut MessageReader, arg_type: ArgType) -> Result<TaggedNumber, ErrorCode> {
+    Ok(match arg_type {
+        ArgType::I32 => {
+            let v: i32 = reader
+                .read_var_i64()?
+                .try_into()
+                .map_err(|_| ErrorCode::Corrupt)?;
+            TaggedNumber::F64(v as f64)
+        }
+        ArgType::I64 => TaggedNumber::I64(reader.read_var_i64()?),
+        ArgType::F32 => TaggedNumber::F64(reader.read_f32()? as f64),
+        ArgType::F64 => TaggedNumber::F64(reader.read_f64()?),
+    })
+}
+
+#[cfg(test)]
+const TEST_SIGNATURES: &[&[ArgType]] = &[
+    &[],
+    &[ArgType::I32, ArgType::I64, ArgType::F32, ArgType::F64],
+];
+
+#[cfg(test)]
+fn test_signature_of(index: u32) -> Option<&'static [ArgType]> {
+    TEST_SIGNATURES.get(index as usize).copied()
+}
+
+#[test]
+fn golden_compact_wasm_call() {
+    let message = CompactMessage::WasmCall {
+        function_index: 1,
+        time: 20.0,
+        args: vec![
+            TaggedNumber::F64(-1.0),
+            TaggedNumber::I64(300),
+            TaggedNumber::F64(0.5),
+            TaggedNumber::F64(0.1),
+        ],
+    };
+    let bytes = CompactEncoder::new()
+        .encode(&message, test_signature_of)
+        .unwrap();
+    assert_eq!(
+        bytes,
+        [
+            7, // CompactWasmCall
+            COMPACT_ENCODING_VERSION,
+            1, // function_index
+            0,
+            20,   // DeltaVarint 20
+            4,    // args length
+            0x7F, // i32 -1
+            0xAC,
+            0x02, // i64 300
+            0x3F,
+            0,
+            0,
+            0, // f32 0.5
+            0x3F,
+            0xB9,
+            0x99,
+            0x99,
+            0x99,
+            0x99,
+            0x99,
+            0x9A, // f64 0.1
+        ]
+    );
+    assert_eq!(
+        CompactDecoder::new().decode(&bytes, test_signature_of),
+        Ok(message)
+    );
+}
+
+#[test]
+fn compact_times_round_trip() {
+    let mut encoder = CompactEncoder::new();
+    let mut decoder = CompactDecoder::new();
+    for time in [0.0, 16.0, 16.0001, 16.5, 10.0, 1e300, -3.25, 1234567.891] {
+        let message = CompactMessage::TimeProgressed { time };
+        let bytes = encoder.encode(&message, test_signature_of).unwrap();
+        let decoded = decoder.decode(&bytes, test_signature_of).unwrap();
+        assert_eq!(decoded, message);
+    }
+}
+
+#[test]
+fn compact_rejects_bad_input() {
+    let mut encoder = CompactEncoder::new();
+    let call = |args| CompactMessage::WasmCall {
+        function_index: 1,
+        time: 0.0,
+        args,
+    };
+    // Not representable as the parameter's type.
+    let args = vec![
+        TaggedNumber::F64(1.5),
+        TaggedNumber::I64(0),
+        TaggedNumber::F64(0.0),
+        TaggedNumber::F64(0.0),
+    ];
+    assert_eq!(
+        encoder.encode(&call(args), test_signature_of),
+        Err(ErrorCode::InvalidInput)
+    );
+    // Wrong argument count.
+    assert_eq!(
+        encoder.encode(&call(vec![]), test_signature_of),
+        Err(ErrorCode::InvalidInput)
+    );
+
+    let mut decoder = CompactDecoder::new();
+    assert_eq!(
+        decoder.decode(&[8, 0, 0, 0], test_signature_of),
+        Err(ErrorCode::UnsupportedVersion)
+    );
+    assert_eq!(
+        decoder.decode(&[8, COMPACT_ENCODING_VERSION, 9], test_signature_of),
+        Err(ErrorCode::Corrupt)
+    );
+    // Export 5 has no signature.
+    assert_eq!(
+        decoder.decode(
+            &[7, COMPACT_ENCODING_VERSION, 5, 0, 0, 0],
+            test_signature_of
+        ),
+        Err(ErrorCode::Corrupt)
+    );
+}
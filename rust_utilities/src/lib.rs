@@ -1,81 +1,395 @@
-use core::cell::RefCell;
 use std::io::Write;
 
-thread_local! {
-    /// Data sent from the host.
-    /// Unique to this Wasm thread.
-    pub static DATA_FROM_HOST: RefCell<Vec<u8>> = RefCell::new(Vec::new());
-    pub static DATA_SWAP: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+pub mod access_sets;
+mod buffers;
+pub mod compact_encoding;
+pub mod message_encoding;
 
+pub use buffers::{BufferHandle, ErrorCode};
+
+/// Allocates a buffer of `len` zeroed bytes and returns its handle.
+#[no_mangle]
+pub extern "C" fn buffer_new(len: usize) -> BufferHandle {
+    setup_panic_hook();
+    buffers::allocate(vec![0; len])
 }
 
-/// Called by the host to reserve scratch space to pass data into kwasm.
-/// returns a pointer to the allocated data.
+/// Resizes a buffer, zero-filling any new bytes.
+/// Pointers previously returned by `buffer_ptr` for this handle are invalidated.
 #[no_mangle]
-pub extern "C" fn reserve_space(space: usize) -> *mut u8 {
-    DATA_FROM_HOST.with(|d| {
-        let mut d = d.borrow_mut();
-        d.clear();
-        d.reserve(space);
+pub extern "C" fn buffer_resize(handle: BufferHandle, len: usize) -> ErrorCode {
+    buffers::with_buffer(handle, |b| b.resize(len, 0)).into()
+}
 
-        // This is obviously unsafe, but zeroing the memory was taking a few milliseconds
-        // for large values.
-        unsafe {
-            d.set_len(space);
+/// Returns a pointer to a buffer's data, or null if the handle is invalid.
+/// The pointer is only valid until the buffer is resized, freed, or written by an operation.
+#[no_mangle]
+pub extern "C" fn buffer_ptr(handle: BufferHandle) -> *mut u8 {
+    buffers::with_buffer(handle, |b| b.as_mut_ptr()).unwrap_or(std::ptr::null_mut())
+}
+
+/// Returns the length of a buffer, or 0 if the handle is invalid.
+#[no_mangle]
+pub extern "C" fn buffer_len(handle: BufferHandle) -> usize {
+    buffers::with_buffer(handle, |b| b.len()).unwrap_or(0)
+}
+
+#[no_mangle]
+pub extern "C" fn buffer_free(handle: BufferHandle) -> ErrorCode {
+    buffers::free(handle).into()
+}
+
+/// Transforms the Wasm binary in `input` and writes the result to `output`.
+/// A JSON manifest of the transformed module's exports is written to `manifest_output`, and
+/// JSON statistics about what was instrumented to `statistics_output`. `code_offsets_output`
+/// gets the offsets `rewrite_source_map` needs to update the module's source map.
+///
+/// If `dispatch_allowlist` isn't `0` a `wg_dispatch` export is generated. The buffer holds the
+/// names of the exports it may call, separated by newlines, and may use `*` wildcards.
+///
+/// `recorded_imports` is `0` or a buffer of newline-separated `module.name` patterns for imports
+/// whose results should be recorded and replayed.
+///
+/// If `instrument_include` or `instrument_exclude` isn't `0` only some functions are instrumented
+/// for `track_changes`. They're buffers of newline-separated patterns, see
+/// `wasm_guardian::InstrumentationFilter`.
+///
+/// A module that was already transformed with the same options is written unchanged, with empty
+/// statistics and code offsets, and one transformed with different options fails with `InvalidInput`.
+#[no_mangle]
+pub extern "C" fn prepare_wasm(
+    input: BufferHandle,
+    output: BufferHandle,
+    manifest_output: BufferHandle,
+    statistics_output: BufferHandle,
+    code_offsets_output: BufferHandle,
+    export_globals: bool,
+    track_changes: bool,
+    track_reads: bool,
+    watchpoints: bool,
+    invocation_context: bool,
+    deterministic_random: bool,
+    wasi: bool,
+    dispatch_allowlist: BufferHandle,
+    recorded_imports: BufferHandle,
+    instrument_include: BufferHandle,
+    instrument_exclude: BufferHandle,
+) -> ErrorCode {
+    setup_panic_hook();
+
+    let dispatch = if dispatch_allowlist == 0 {
+        None
+    } else {
+        match read_lines(dispatch_allowlist) {
+            Ok(allowlist) => Some(wasm_guardian::DispatchOptions { allowlist }),
+            Err(e) => return e,
         }
-        //d.resize(space, 0);
-        d.as_mut_ptr()
-    })
+    };
+    let recorded_imports = if recorded_imports == 0 {
+        Vec::new()
+    } else {
+        match read_lines(recorded_imports) {
+            Ok(recorded_imports) => recorded_imports,
+            Err(e) => return e,
+        }
+    };
+    let instrumentation_filter = if instrument_include == 0 && instrument_exclude == 0 {
+        None
+    } else {
+        let mut filter = wasm_guardian::InstrumentationFilter::default();
+        for (buffer, patterns) in [
+            (instrument_include, &mut filter.include),
+            (instrument_exclude, &mut filter.exclude),
+        ] {
+            if buffer != 0 {
+                match read_lines(buffer) {
+                    Ok(lines) => *patterns = lines,
+                    Err(e) => return e,
+                }
+            }
+        }
+        Some(filter)
+    };
+    let options = wasm_guardian::TransformOptions {
+        export_globals,
+        track_changes,
+        track_reads,
+        watchpoints,
+        invocation_context,
+        deterministic_random,
+        wasi,
+        recorded_imports,
+        dispatch,
+        instrumentation_filter,
+    };
+
+    buffers::run_operation_with_outputs(
+        input,
+        [
+            output,
+            manifest_output,
+            statistics_output,
+            code_offsets_output,
+        ],
+        |input| {
+            let output = wasm_guardian::transform_wasm(input, &options)
+                .map_err(|_| ErrorCode::InvalidInput)?;
+            let statistics = output
+                .statistics
+                .map(|statistics| statistics.to_json().into_bytes())
+                .unwrap_or_default();
+            let code_offsets = output
+                .code_offsets
+                .map(|code_offsets| code_offsets.to_bytes())
+                .unwrap_or_default();
+            Ok([
+                output.wasm,
+                output.manifest.to_json().into_bytes(),
+                statistics,
+                code_offsets,
+            ])
+        },
+    )
 }
 
+/// Calls `wg_dispatch` in a module from `prepare_wasm` the way a host does: by the export's
+/// index in the manifest, with the arguments in the `wg_dispatch_arg_` globals.
+#[test]
+fn dispatch_end_to_end() {
+    use wasm_guardian::{DISPATCH_BAD_ARGS, DISPATCH_NOT_ALLOWED, DISPATCH_OK};
+    use wasmi::{Engine, Linker, Module, Store, Value};
+
+    let wasm = wat::parse_str(
+        r#"(module
+            (memory (export "memory") 1)
+            (func (export "add") (param i32 f64)
+                (i32.store (i32.const 0)
+                    (i32.add (local.get 0) (i32.trunc_f64_s (local.get 1)))))
+            (func (export "hidden")
+                (i32.store (i32.const 0) (i32.const -1))))"#,
+    )
+    .unwrap();
+    let input = buffers::allocate(wasm);
+    let outputs = [(); 4].map(|_| buffers::allocate(Vec::new()));
+    let allowlist = buffers::allocate(b"add\nwg_*".to_vec());
+    let [output, manifest, statistics, code_offsets] = outputs;
+    let result = prepare_wasm(
+        input,
+        output,
+        manifest,
+        statistics,
+        code_offsets,
+        true,
+        false,
+        false,
+        false,
+        true,
+        true,
+        true,
+        allowlist,
+        0,
+        0,
+        0,
+    );
+    assert_eq!(result, ErrorCode::Ok);
+    let processed = buffers::with_buffer(output, |b| b.clone()).unwrap();
+    let manifest = buffers::with_buffer(manifest, |b| String::from_utf8(b.clone()))
+        .unwrap()
+        .unwrap();
+
+    let engine = Engine::default();
+    let module = Module::new(&engine, &processed[..]).unwrap();
+    let mut store = Store::new(&engine, ());
+    let instance = Linker::<()>::new(&engine)
+        .instantiate(&mut store, &module)
+        .unwrap()
+        .start(&mut store)
+        .unwrap();
+    // Exports are listed first in the manifest, in the order of `Object.keys(instance.exports)`.
+    let index = |name: &str| {
+        let entry = format!("{{\"name\":\"{}\"", name);
+        manifest[..manifest.find(&entry).unwrap()]
+            .matches("{\"name\":")
+            .count() as i32
+    };
+    let (add, hidden, dispatch_index) = (
+        index("add"),
+        index("hidden"),
+        index(wasm_guardian::DISPATCH_EXPORT_NAME),
+    );
+    let dispatch = instance
+        .get_typed_func::<(i32, i32), i32>(&store, wasm_guardian::DISPATCH_EXPORT_NAME)
+        .unwrap();
+    let memory = instance.get_memory(&store, "memory").unwrap();
+    let mut call = |index: i32, args: &[i64]| {
+        for (i, arg) in args.iter().enumerate() {
+            let name = format!("{}{}", wasm_guardian::DISPATCH_ARG_GLOBAL_PREFIX, i);
+            let global = instance.get_global(&store, &name).unwrap();
+            global.set(&mut store, Value::I64(*arg)).unwrap();
+        }
+        let status = dispatch
+            .call(&mut store, (index, args.len() as i32))
+            .unwrap();
+        let stored = i32::from_le_bytes(memory.data(&store)[0..4].try_into().unwrap());
+        (status, stored)
+    };
+
+    assert_eq!(call(add, &[5, 2.5f64.to_bits() as i64]), (DISPATCH_OK, 7));
+    assert_eq!(call(add, &[1]), (DISPATCH_BAD_ARGS, 7));
+    assert_eq!(call(hidden, &[]), (DISPATCH_NOT_ALLOWED, 7));
+    // Generated exports can't be allowed.
+    assert_eq!(call(dispatch_index, &[]), (DISPATCH_NOT_ALLOWED, 7));
+    assert_eq!(call(-1, &[]), (DISPATCH_NOT_ALLOWED, 7));
+}
+
+/// Rewrites the source map in `input`, made for a Wasm binary before `prepare_wasm`, so that it
+/// describes the binary `prepare_wasm` returned. `code_offsets` is `prepare_wasm`'s fourth
+/// output.
+///
+/// Fails with `InvalidInput` if `code_offsets` or the source map can't be read.
 #[no_mangle]
-pub extern "C" fn prepare_wasm(export_globals: bool, track_changes: bool) {
+pub extern "C" fn rewrite_source_map(
+    input: BufferHandle,
+    output: BufferHandle,
+    code_offsets: BufferHandle,
+) -> ErrorCode {
     setup_panic_hook();
-    DATA_FROM_HOST.with(|d| {
-        let mut d = d.borrow_mut();
 
-        let output =
-            wasm_guardian::transform_wasm_to_track_changes(&d, export_globals, track_changes);
-        *d = output;
+    let code_offsets = match buffers::with_buffer(code_offsets, |b| {
+        wasm_guardian::CodeOffsets::from_bytes(b).ok_or(ErrorCode::InvalidInput)
+    }) {
+        Ok(Ok(code_offsets)) => code_offsets,
+        Ok(Err(e)) | Err(e) => return e,
+    };
+    buffers::run_operation(input, output, |input| {
+        let source_map = std::str::from_utf8(input).map_err(|_| ErrorCode::InvalidInput)?;
+        let source_map = wasm_guardian::rewrite_source_map(source_map, &code_offsets)
+            .ok_or(ErrorCode::InvalidInput)?;
+        Ok(source_map.into_bytes())
+    })
+}
+
+/// Writes the provenance of a module transformed by `prepare_wasm` to `output` as JSON.
+/// `output` is left empty if the module wasn't transformed.
+#[no_mangle]
+pub extern "C" fn read_provenance(input: BufferHandle, output: BufferHandle) -> ErrorCode {
+    setup_panic_hook();
+    buffers::run_operation(input, output, |input| {
+        Ok(wasm_guardian::Provenance::read(input)
+            .map(|provenance| provenance.to_json().into_bytes())
+            .unwrap_or_default())
     })
 }
 
+/// Writes a copy of the Wasm binary in `input` that starts in the state of `snapshot` to `output`.
+///
+/// `snapshot` is a `WasmSnapshot` as written by `MessageWriterReader.write_wasm_snapshot`.
+/// Its time stamp is ignored.
 #[no_mangle]
-pub extern "C" fn get_output_ptr() -> *mut u8 {
+pub extern "C" fn bake_snapshot(
+    input: BufferHandle,
+    output: BufferHandle,
+    snapshot: BufferHandle,
+) -> ErrorCode {
     setup_panic_hook();
-    DATA_FROM_HOST.with(|d| {
-        let mut d = d.borrow_mut();
-        d.as_mut_ptr()
+
+    let snapshot = buffers::with_buffer(snapshot, |b| {
+        message_encoding::MessageReader::new(b).read_wasm_snapshot()
+    });
+    let snapshot = match snapshot {
+        Ok(Ok(snapshot)) => snapshot,
+        Ok(Err(e)) | Err(e) => return e,
+    };
+    let snapshot = wasm_guardian::Snapshot {
+        memory: snapshot.memory,
+        globals: snapshot
+            .globals
+            .into_iter()
+            .map(|(index, value)| {
+                let value = match value {
+                    message_encoding::TaggedNumber::F64(v) => wasm_guardian::SnapshotValue::F64(v),
+                    message_encoding::TaggedNumber::I64(v) => wasm_guardian::SnapshotValue::I64(v),
+                };
+                (index, value)
+            })
+            .collect(),
+    };
+
+    buffers::run_operation(input, output, |input| {
+        wasm_guardian::bake_snapshot(input, &snapshot).map_err(|_| ErrorCode::InvalidInput)
     })
 }
 
+/// Compares the Wasm binary a room is running, `old`, with a new version of it in `input`.
+/// `memory_pages` is the running instance's memory size in pages.
+///
+/// Writes the new binary prepared to start from the old one's state to `output`, and a JSON
+/// report of whether the state can be carried over, with global and export remappings,
+/// to `report_output`.
 #[no_mangle]
-pub extern "C" fn get_output_len() -> usize {
+pub extern "C" fn prepare_reload(
+    input: BufferHandle,
+    output: BufferHandle,
+    report_output: BufferHandle,
+    old: BufferHandle,
+    memory_pages: u32,
+) -> ErrorCode {
     setup_panic_hook();
-    DATA_FROM_HOST.with(|d| {
-        let d = d.borrow();
-        d.len()
+
+    let old = match buffers::with_buffer(old, |b| b.clone()) {
+        Ok(old) => old,
+        Err(e) => return e,
+    };
+    buffers::run_operation_with_outputs(input, [output, report_output], |input| {
+        let output = wasm_guardian::compare_modules(&old, input, memory_pages)
+            .map_err(|_| ErrorCode::InvalidInput)?;
+        Ok([output.wasm, output.report.to_json().into_bytes()])
+    })
+}
+
+/// Reads a buffer of newline-separated strings, skipping empty lines.
+fn read_lines(handle: BufferHandle) -> Result<Vec<String>, ErrorCode> {
+    buffers::with_buffer(handle, |b| {
+        String::from_utf8_lossy(b)
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_string())
+            .collect()
     })
 }
 
+#[cfg(target_arch = "wasm32")]
 extern "C" {
     pub(crate) fn external_log(data: *const u8, data_length: u32);
     pub(crate) fn external_error(data: *const u8, data_length: u32);
 }
 
+#[cfg(target_arch = "wasm32")]
 pub fn log(s: &str) {
     unsafe {
         external_log(s.as_ptr(), s.len() as _);
     }
 }
 
+#[cfg(target_arch = "wasm32")]
 pub fn error(s: &str) {
     unsafe {
         external_error(s.as_ptr(), s.len() as _);
     }
 }
 
-fn hook_impl(info: &std::panic::PanicInfo) {
+// Native tools and tests link this crate as an rlib and have no host to log to.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn log(s: &str) {
+    println!("{}", s);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn error(s: &str) {
+    eprintln!("{}", s);
+}
+
+fn hook_impl(info: &std::panic::PanicHookInfo) {
     let message = info.to_string();
     error(&message);
 }
@@ -89,61 +403,148 @@ pub fn setup_panic_hook() {
     });
 }
 
-// Hashes data in DATA_FROM_HOST and returns it in DATA_FROM_HOST
+/// Checks whether a call that arrived late conflicts with the calls that already ran after it.
+///
+/// `input` is the late call's [access_sets::AccessSet] and `later` holds the sets of the calls
+/// after it, one after another. Writes the index of the first conflicting call as a
+/// little-endian u32, or nothing if there's no conflict and the late call can run without
+/// rolling back.
 #[no_mangle]
-pub extern "C" fn xxh3_128_bit_hash() {
-    DATA_FROM_HOST.with(|d| {
-        let mut d = d.borrow_mut();
-        let result = xxhash_rust::xxh3::xxh3_128(&d);
-        d.clear();
-        d.write(&result.to_be_bytes()).unwrap();
+pub extern "C" fn access_sets_conflict(
+    input: BufferHandle,
+    output: BufferHandle,
+    later: BufferHandle,
+) -> ErrorCode {
+    setup_panic_hook();
+
+    let later = match buffers::with_buffer(later, |b| b.clone()) {
+        Ok(later) => later,
+        Err(e) => return e,
+    };
+    buffers::run_operation(input, output, |input| {
+        let (late, rest) = access_sets::AccessSet::decode(input)?;
+        if !rest.is_empty() {
+            return Err(ErrorCode::Corrupt);
+        }
+        let mut later_sets = Vec::new();
+        let mut rest = &later[..];
+        while !rest.is_empty() {
+            let (set, remaining) = access_sets::AccessSet::decode(rest)?;
+            later_sets.push(set);
+            rest = remaining;
+        }
+        Ok(access_sets::first_conflict(&late, &later_sets)
+            .map(|index| (index as u32).to_le_bytes().to_vec())
+            .unwrap_or_default())
     })
 }
 
-/// Write data with reserve_space and then returns the new data location as a pointer and length written
-/// to data.
+/// Writes the 128 bit xxh3 hash of `input` to `output` as 16 big-endian bytes.
 #[no_mangle]
-pub extern "C" fn gzip_encode() {
-    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+pub extern "C" fn xxh3_128_bit_hash(input: BufferHandle, output: BufferHandle) -> ErrorCode {
+    buffers::run_operation(input, output, |input| {
+        Ok(xxhash_rust::xxh3::xxh3_128(input).to_be_bytes().to_vec())
+    })
+}
 
-    DATA_FROM_HOST.with(|d| {
-        let mut input = d.borrow_mut();
-        let input: &mut Vec<u8> = &mut input;
-        encoder.write_all(&input).unwrap();
-        let result = encoder.finish().unwrap();
+/// Writes the semantic hash of the Wasm module in `input` to `output` as 16 big-endian bytes.
+/// See [wasm_guardian::semantic_hash].
+#[no_mangle]
+pub extern "C" fn semantic_hash(input: BufferHandle, output: BufferHandle) -> ErrorCode {
+    setup_panic_hook();
+    buffers::run_operation(input, output, |input| {
+        Ok(wasm_guardian::semantic_hash(input).to_be_bytes().to_vec())
+    })
+}
 
-        input.clear();
-        input
-            .write(&(result.as_ptr() as u32).to_le_bytes())
-            .unwrap();
-        input.write(&(result.len() as u32).to_le_bytes()).unwrap();
+/// Writes a short UTF-8 identifier derived from the semantic hash of the Wasm module in `input`.
+#[no_mangle]
+pub extern "C" fn room_id(input: BufferHandle, output: BufferHandle) -> ErrorCode {
+    setup_panic_hook();
+    buffers::run_operation(input, output, |input| {
+        Ok(wasm_guardian::room_id(wasm_guardian::semantic_hash(input)).into_bytes())
+    })
+}
 
-        DATA_SWAP.with(|d| {
-            d.replace(result);
-        });
-    });
+#[no_mangle]
+pub extern "C" fn gzip_encode(input: BufferHandle, output: BufferHandle) -> ErrorCode {
+    setup_panic_hook();
+    buffers::run_operation(input, output, |input| {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder
+            .write_all(input)
+            .and_then(|_| encoder.finish())
+            .map_err(|_| ErrorCode::InvalidInput)
+    })
 }
 
+/// Decompresses `input` into `output`.
+///
+/// `input` comes from peers so it is untrusted. Decoding stops with `ErrorCode::OutputTooLarge`
+/// once more than `max_output_size` bytes would be produced.
 #[no_mangle]
-pub extern "C" fn gzip_decode() {
+pub extern "C" fn gzip_decode(
+    input: BufferHandle,
+    output: BufferHandle,
+    max_output_size: usize,
+) -> ErrorCode {
+    setup_panic_hook();
+    buffers::run_operation(input, output, |input| {
+        gzip_decode_with_limit(input, max_output_size)
+    })
+}
+
+pub fn gzip_decode_with_limit(input: &[u8], max_output_size: usize) -> Result<Vec<u8>, ErrorCode> {
     use std::io::Read;
-    DATA_FROM_HOST.with(|d| {
-        let mut input = d.borrow_mut();
 
-        DATA_SWAP.with(|d| {
-            let mut d = d.borrow_mut();
+    // Read one byte past the limit so that output of exactly `max_output_size` is accepted.
+    // The `bufread` decoder reads `input` directly, so after an error the unread remainder
+    // tells a stream that ran out of input apart from one with invalid data.
+    let mut decoder =
+        flate2::bufread::GzDecoder::new(input).take((max_output_size as u64).saturating_add(1));
+    let mut result = Vec::new();
+    if let Err(e) = decoder.read_to_end(&mut result) {
+        let input_exhausted = decoder.get_ref().get_ref().is_empty();
+        return Err(
+            if e.kind() == std::io::ErrorKind::UnexpectedEof || input_exhausted {
+                ErrorCode::Truncated
+            } else {
+                ErrorCode::Corrupt
+            },
+        );
+    }
 
-            {
-                let mut decoder = flate2::read::GzDecoder::new(input.as_slice());
-                d.clear();
-                decoder.read_to_end(&mut d).unwrap();
-            }
+    if result.len() > max_output_size {
+        return Err(ErrorCode::OutputTooLarge);
+    }
+    Ok(result)
+}
 
-            input.clear();
-            input.write(&(d.as_ptr() as u32).to_le_bytes()).unwrap();
-            input.write(&(d.len() as u32).to_le_bytes()).unwrap();
-        });
-    });
+#[test]
+fn gzip_decode_limits() {
+    use std::io::Write;
+
+    let data = vec![7; 1000];
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+    encoder.write_all(&data).unwrap();
+    let encoded = encoder.finish().unwrap();
+
+    assert_eq!(gzip_decode_with_limit(&encoded, 1000), Ok(data));
+    assert_eq!(
+        gzip_decode_with_limit(&encoded, 999),
+        Err(ErrorCode::OutputTooLarge)
+    );
+    assert_eq!(
+        gzip_decode_with_limit(&encoded[..encoded.len() - 4], 1000),
+        Err(ErrorCode::Truncated)
+    );
+    let mut corrupt = encoded.clone();
+    // The first byte of the deflate stream, which sets an invalid block type.
+    corrupt[10] = 0xFF;
+    assert_eq!(
+        gzip_decode_with_limit(&corrupt, 1000),
+        Err(ErrorCode::Corrupt)
+    );
 }
 
 /*
@@ -0,0 +1,670 @@
+//! A Rust implementation of the binary format written by `MessageWriterReader` in `tangle_ts`.
+//!
+//! All multi-byte values are big-endian, matching the `DataView` defaults used by TypeScript.
+
+use crate::ErrorCode;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    WasmCall = 0,
+    TimeProgressed = 1,
+    RequestState = 2,
+    SetProgram = 3,
+    SetHeap = 4,
+    Ping = 5,
+    Pong = 6,
+    /// Encoded by `compact_encoding`.
+    CompactWasmCall = 7,
+    /// Encoded by `compact_encoding`.
+    CompactTimeProgressed = 8,
+    RecordedResults = 9,
+}
+
+impl MessageType {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => MessageType::WasmCall,
+            1 => MessageType::TimeProgressed,
+            2 => MessageType::RequestState,
+            3 => MessageType::SetProgram,
+            4 => MessageType::SetHeap,
+            5 => MessageType::Ping,
+            6 => MessageType::Pong,
+            7 => MessageType::CompactWasmCall,
+            8 => MessageType::CompactTimeProgressed,
+            9 => MessageType::RecordedResults,
+            _ => return None,
+        })
+    }
+}
+
+#[repr(u8)]
+enum NumberTag {
+    F64 = 0,
+    I64 = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeStamp {
+    pub time: f64,
+    pub player_id: f64,
+}
+
+/// A Wasm global's value. TypeScript represents i64 globals as `bigint` and everything else as `number`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaggedNumber {
+    F64(f64),
+    I64(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WasmSnapshot {
+    pub memory: Vec<u8>,
+    /// The index in the exports and the value to set the export to.
+    pub globals: Vec<(u32, TaggedNumber)>,
+    pub time_stamp: TimeStamp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionCall {
+    pub function_export_index: u32,
+    pub args: Vec<f64>,
+    pub time_stamp: TimeStamp,
+    /// Results of recorded imports, in the order the call made them.
+    pub recorded_results: Vec<TaggedNumber>,
+}
+
+/// The state a `TimeMachine` sends to a peer that requests it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeMachineState {
+    pub fixed_update_time: f64,
+    pub target_time: f64,
+    pub current_simulation_time: TimeStamp,
+    pub events: Vec<FunctionCall>,
+    pub snapshot: WasmSnapshot,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    WasmCall {
+        function_index: u32,
+        time: f64,
+        args: Vec<f64>,
+    },
+    TimeProgressed {
+        time: f64,
+    },
+    RequestState,
+    SetHeap(TimeMachineState),
+    Ping {
+        time_sent: f64,
+    },
+    Pong {
+        time_sent: f64,
+        current_time: f64,
+    },
+    /// The results of recorded imports made by the sender's call at `time`.
+    RecordedResults {
+        time: f64,
+        results: Vec<TaggedNumber>,
+    },
+}
+
+#[derive(Default)]
+pub struct MessageWriter {
+    pub output: Vec<u8>,
+}
+
+impl MessageWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_raw_bytes(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+
+    pub fn write_string(&mut self, string: &str) {
+        self.write_u32(string.len() as u32);
+        self.write_raw_bytes(string.as_bytes());
+    }
+
+    pub fn write_u8(&mut self, v: u8) {
+        self.output.push(v);
+    }
+
+    pub fn write_u16(&mut self, v: u16) {
+        self.write_raw_bytes(&v.to_be_bytes());
+    }
+
+    pub fn write_u32(&mut self, v: u32) {
+        self.write_raw_bytes(&v.to_be_bytes());
+    }
+
+    pub fn write_f32(&mut self, v: f32) {
+        self.write_raw_bytes(&v.to_be_bytes());
+    }
+
+    pub fn write_f64(&mut self, v: f64) {
+        self.write_raw_bytes(&v.to_be_bytes());
+    }
+
+    pub fn write_i64(&mut self, v: i64) {
+        self.write_raw_bytes(&v.to_be_bytes());
+    }
+
+    /// Writes an unsigned LEB128 varint.
+    pub fn write_var_u64(&mut self, mut v: u64) {
+        loop {
+            let byte = (v & 0x7F) as u8;
+            v >>= 7;
+            if v == 0 {
+                self.write_u8(byte);
+                return;
+            }
+            self.write_u8(byte | 0x80);
+        }
+    }
+
+    pub fn write_var_u32(&mut self, v: u32) {
+        self.write_var_u64(v as u64);
+    }
+
+    /// Writes a signed LEB128 varint.
+    pub fn write_var_i64(&mut self, mut v: i64) {
+        loop {
+            let byte = (v & 0x7F) as u8;
+            v >>= 7;
+            let done = (v == 0 && byte & 0x40 == 0) || (v == -1 && byte & 0x40 != 0);
+            if done {
+                self.write_u8(byte);
+                return;
+            }
+            self.write_u8(byte | 0x80);
+        }
+    }
+
+    pub fn write_tagged_number(&mut self, number: TaggedNumber) {
+        match number {
+            TaggedNumber::F64(v) => {
+                self.write_u8(NumberTag::F64 as u8);
+                self.write_f64(v);
+            }
+            TaggedNumber::I64(v) => {
+                self.write_u8(NumberTag::I64 as u8);
+                self.write_i64(v);
+            }
+        }
+    }
+
+    pub fn write_time_stamp(&mut self, time_stamp: &TimeStamp) {
+        self.write_f64(time_stamp.time);
+        self.write_f64(time_stamp.player_id);
+    }
+
+    pub fn write_wasm_snapshot(&mut self, snapshot: &WasmSnapshot) {
+        self.write_time_stamp(&snapshot.time_stamp);
+
+        // Encode all mutable globals
+        self.write_u16(snapshot.globals.len() as u16);
+        for (index, value) in &snapshot.globals {
+            self.write_u32(*index);
+            self.write_tagged_number(*value);
+        }
+        self.write_u32(snapshot.memory.len() as u32);
+        self.write_raw_bytes(&snapshot.memory);
+    }
+
+    pub fn write_tagged_numbers(&mut self, numbers: &[TaggedNumber]) {
+        self.write_u16(numbers.len() as u16);
+        for number in numbers {
+            self.write_tagged_number(*number);
+        }
+    }
+
+    /// Matches `TimeMachine.encode` without the leading message type byte.
+    pub fn write_time_machine_state(&mut self, state: &TimeMachineState) {
+        self.write_f64(state.fixed_update_time);
+        self.write_f64(state.target_time);
+        self.write_time_stamp(&state.current_simulation_time);
+
+        self.write_u32(state.events.len() as u32);
+        for event in &state.events {
+            self.write_u32(event.function_export_index);
+            self.write_time_stamp(&event.time_stamp);
+            self.write_u8(event.args.len() as u8);
+            for arg in &event.args {
+                self.write_f64(*arg);
+            }
+            self.write_tagged_numbers(&event.recorded_results);
+        }
+
+        self.write_wasm_snapshot(&state.snapshot);
+    }
+
+    pub fn write_message(&mut self, message: &Message) {
+        match message {
+            Message::WasmCall {
+                function_index,
+                time,
+                args,
+            } => {
+                self.write_u8(MessageType::WasmCall as u8);
+                self.write_u32(*function_index);
+                self.write_f64(*time);
+                self.write_u8(args.len() as u8);
+                for arg in args {
+                    self.write_f64(*arg);
+                }
+            }
+            Message::TimeProgressed { time } => {
+                self.write_u8(MessageType::TimeProgressed as u8);
+                self.write_f64(*time);
+            }
+            Message::RequestState => {
+                self.write_u8(MessageType::RequestState as u8);
+            }
+            Message::SetHeap(state) => {
+                self.write_u8(MessageType::SetHeap as u8);
+                self.write_time_machine_state(state);
+            }
+            Message::Ping { time_sent } => {
+                self.write_u8(MessageType::Ping as u8);
+                self.write_f64(*time_sent);
+            }
+            Message::Pong {
+                time_sent,
+                current_time,
+            } => {
+                self.write_u8(MessageType::Pong as u8);
+                self.write_f64(*time_sent);
+                self.write_f64(*current_time);
+            }
+            Message::RecordedResults { time, results } => {
+                self.write_u8(MessageType::RecordedResults as u8);
+                self.write_f64(*time);
+                self.write_tagged_numbers(results);
+            }
+        }
+    }
+}
+
+pub struct MessageReader<'a> {
+    pub data: &'a [u8],
+    pub offset: usize,
+}
+
+impl<'a> MessageReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    pub fn read_remaining_raw_bytes(&mut self) -> &'a [u8] {
+        let result = &self.data[self.offset..];
+        self.offset = self.data.len();
+        result
+    }
+
+    pub fn read_fixed_raw_bytes(&mut self, length: usize) -> Result<&'a [u8], ErrorCode> {
+        let end = self
+            .offset
+            .checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(ErrorCode::Truncated)?;
+        let result = &self.data[self.offset..end];
+        self.offset = end;
+        Ok(result)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], ErrorCode> {
+        Ok(self.read_fixed_raw_bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn read_string(&mut self) -> Result<&'a str, ErrorCode> {
+        let length = self.read_u32()?;
+        let bytes = self.read_fixed_raw_bytes(length as usize)?;
+        std::str::from_utf8(bytes).map_err(|_| ErrorCode::Corrupt)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, ErrorCode> {
+        Ok(u8::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, ErrorCode> {
+        Ok(u16::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, ErrorCode> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, ErrorCode> {
+        Ok(f32::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, ErrorCode> {
+        Ok(f64::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64, ErrorCode> {
+        Ok(i64::from_be_bytes(self.read_array()?))
+    }
+
+    /// Reads an unsigned LEB128 varint. Encodings longer than 10 bytes or with bits past
+    /// the 64th set are rejected as `ErrorCode::Corrupt`.
+    pub fn read_var_u64(&mut self) -> Result<u64, ErrorCode> {
+        let mut result = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift == 63 && byte > 1 {
+                return Err(ErrorCode::Corrupt);
+            }
+            result |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+            shift += 7;
+        }
+    }
+
+    pub fn read_var_u32(&mut self) -> Result<u32, ErrorCode> {
+        self.read_var_u64()?
+            .try_into()
+            .map_err(|_| ErrorCode::Corrupt)
+    }
+
+    /// Reads a signed LEB128 varint.
+    pub fn read_var_i64(&mut self) -> Result<i64, ErrorCode> {
+        let mut result = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift == 63 && byte != 0 && byte != 0x7F {
+                return Err(ErrorCode::Corrupt);
+            }
+            result |= ((byte & 0x7F) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    result |= -1 << shift;
+                }
+                return Ok(result);
+            }
+        }
+    }
+
+    pub fn read_tagged_number(&mut self) -> Result<TaggedNumber, ErrorCode> {
+        let tag = self.read_u8()?;
+        if tag == NumberTag::F64 as u8 {
+            Ok(TaggedNumber::F64(self.read_f64()?))
+        } else if tag == NumberTag::I64 as u8 {
+            Ok(TaggedNumber::I64(self.read_i64()?))
+        } else {
+            Err(ErrorCode::Corrupt)
+        }
+    }
+
+    pub fn read_time_stamp(&mut self) -> Result<TimeStamp, ErrorCode> {
+        Ok(TimeStamp {
+            time: self.read_f64()?,
+            player_id: self.read_f64()?,
+        })
+    }
+
+    pub fn read_wasm_snapshot(&mut self) -> Result<WasmSnapshot, ErrorCode> {
+        let time_stamp = self.read_time_stamp()?;
+
+        let globals_length = self.read_u16()?;
+        let mut globals = Vec::with_capacity(globals_length as usize);
+        for _ in 0..globals_length {
+            let index = self.read_u32()?;
+            let value = self.read_tagged_number()?;
+            globals.push((index, value));
+        }
+
+        let bytes_length = self.read_u32()?;
+        let memory = self.read_fixed_raw_bytes(bytes_length as usize)?.to_vec();
+
+        Ok(WasmSnapshot {
+            memory,
+            globals,
+            time_stamp,
+        })
+    }
+
+    pub fn read_time_machine_state(&mut self) -> Result<TimeMachineState, ErrorCode> {
+        let fixed_update_time = self.read_f64()?;
+        let target_time = self.read_f64()?;
+        let current_simulation_time = self.read_time_stamp()?;
+
+        // The length comes from a peer so don't trust it for preallocation.
+        let events_length = self.read_u32()?;
+        let mut events = Vec::new();
+        for _ in 0..events_length {
+            let function_export_index = self.read_u32()?;
+            let time_stamp = self.read_time_stamp()?;
+            let args = self.read_f64_args()?;
+            let recorded_results = self.read_tagged_numbers()?;
+            events.push(FunctionCall {
+                function_export_index,
+                args,
+                time_stamp,
+                recorded_results,
+            });
+        }
+
+        let snapshot = self.read_wasm_snapshot()?;
+
+        Ok(TimeMachineState {
+            fixed_update_time,
+            target_time,
+            current_simulation_time,
+            events,
+            snapshot,
+        })
+    }
+
+    pub fn read_tagged_numbers(&mut self) -> Result<Vec<TaggedNumber>, ErrorCode> {
+        let length = self.read_u16()?;
+        (0..length).map(|_| self.read_tagged_number()).collect()
+    }
+
+    fn read_f64_args(&mut self) -> Result<Vec<f64>, ErrorCode> {
+        let args_length = self.read_u8()?;
+        (0..args_length).map(|_| self.read_f64()).collect()
+    }
+
+    /// Reads a message including its leading message type byte.
+    /// `SetProgram` has no defined encoding and is rejected as `ErrorCode::Corrupt`,
+    /// as are compact messages, which must be read with `compact_encoding`.
+    pub fn read_message(&mut self) -> Result<Message, ErrorCode> {
+        let message_type = MessageType::from_u8(self.read_u8()?).ok_or(ErrorCode::Corrupt)?;
+        Ok(match message_type {
+            MessageType::WasmCall => Message::WasmCall {
+                function_index: self.read_u32()?,
+                time: self.read_f64()?,
+                args: self.read_f64_args()?,
+            },
+            MessageType::TimeProgressed => Message::TimeProgressed {
+                time: self.read_f64()?,
+            },
+            MessageType::RequestState => Message::RequestState,
+            MessageType::SetHeap => Message::SetHeap(self.read_time_machine_state()?),
+            MessageType::Ping => Message::Ping {
+                time_sent: self.read_f64()?,
+            },
+            MessageType::Pong => Message::Pong {
+                time_sent: self.read_f64()?,
+                current_time: self.read_f64()?,
+            },
+            MessageType::RecordedResults => Message::RecordedResults {
+                time: self.read_f64()?,
+                results: self.read_tagged_numbers()?,
+            },
+            MessageType::SetProgram
+            | MessageType::CompactWasmCall
+            | MessageType::CompactTimeProgressed => return Err(ErrorCode::Corrupt),
+        })
+    }
+}
+
+pub fn encode_message(message: &Message) -> Vec<u8> {
+    let mut writer = MessageWriter::new();
+    writer.write_message(message);
+    writer.output
+}
+
+/// Decodes a complete message. Trailing bytes are rejected as `ErrorCode::Corrupt`.
+pub fn decode_message(data: &[u8]) -> Result<Message, ErrorCode> {
+    let mut reader = MessageReader::new(data);
+    let message = reader.read_message()?;
+    if reader.offset != data.len() {
+        return Err(ErrorCode::Corrupt);
+    }
+    Ok(message)
+}
+
+// These byte layouts are written out by hand from `MessageWriterReader` and `tangle.ts`.
+// If one of these tests fails the Rust and TypeScript encodings have diverged.
+
+#[cfg(test)]
+fn check_golden(message: Message, golden: &[u8]) {
+    assert_eq!(encode_message(&message), golden);
+    assert_eq!(decode_message(golden), Ok(message));
+}
+
+#[test]
+fn golden_wasm_call() {
+    check_golden(
+        Message::WasmCall {
+            function_index: 3,
+            time: 1.5,
+            args: vec![2.0, -1.0],
+        },
+        &[
+            0, // WasmCall
+            0, 0, 0, 3, // function_index
+            0x3F, 0xF8, 0, 0, 0, 0, 0, 0, // time
+            2, // args length
+            0x40, 0, 0, 0, 0, 0, 0, 0, // 2.0
+            0xBF, 0xF0, 0, 0, 0, 0, 0, 0, // -1.0
+        ],
+    );
+}
+
+#[test]
+fn golden_time_progressed() {
+    check_golden(
+        Message::TimeProgressed { time: 2.0 },
+        &[1, 0x40, 0, 0, 0, 0, 0, 0, 0],
+    );
+}
+
+#[test]
+fn golden_request_state() {
+    check_golden(Message::RequestState, &[2]);
+}
+
+#[test]
+fn golden_ping_pong() {
+    check_golden(
+        Message::Ping { time_sent: 1.0 },
+        &[5, 0x3F, 0xF0, 0, 0, 0, 0, 0, 0],
+    );
+    check_golden(
+        Message::Pong {
+            time_sent: 1.0,
+            current_time: -2.0,
+        },
+        &[
+            6, // Pong
+            0x3F, 0xF0, 0, 0, 0, 0, 0, 0, // time_sent
+            0xC0, 0, 0, 0, 0, 0, 0, 0, // current_time
+        ],
+    );
+}
+
+#[test]
+fn golden_set_heap() {
+    let time_stamp = TimeStamp {
+        time: 1.0,
+        player_id: 2.0,
+    };
+    check_golden(
+        Message::SetHeap(TimeMachineState {
+            fixed_update_time: 1.0,
+            target_time: 2.0,
+            current_simulation_time: time_stamp,
+            events: vec![FunctionCall {
+                function_export_index: 7,
+                args: vec![],
+                time_stamp,
+                recorded_results: vec![TaggedNumber::F64(1.0)],
+            }],
+            snapshot: WasmSnapshot {
+                memory: vec![0xAA, 0xBB],
+                globals: vec![(4, TaggedNumber::F64(1.0)), (5, TaggedNumber::I64(-1))],
+                time_stamp,
+            },
+        }),
+        &[
+            4, // SetHeap
+            0x3F, 0xF0, 0, 0, 0, 0, 0, 0, // fixed_update_time
+            0x40, 0, 0, 0, 0, 0, 0, 0, // target_time
+            0x3F, 0xF0, 0, 0, 0, 0, 0, 0, // current_simulation_time.time
+            0x40, 0, 0, 0, 0, 0, 0, 0, // current_simulation_time.player_id
+            0, 0, 0, 1, // events length
+            0, 0, 0, 7, // function_export_index
+            0x3F, 0xF0, 0, 0, 0, 0, 0, 0, // time_stamp.time
+            0x40, 0, 0, 0, 0, 0, 0, 0, // time_stamp.player_id
+            0, // args length
+            0, 1, // recorded results length
+            0, 0x3F, 0xF0, 0, 0, 0, 0, 0, 0, // F64 1.0
+            0x3F, 0xF0, 0, 0, 0, 0, 0, 0, // snapshot time_stamp.time
+            0x40, 0, 0, 0, 0, 0, 0, 0, // snapshot time_stamp.player_id
+            0, 2, // globals length
+            0, 0, 0, 4, // global index
+            0, 0x3F, 0xF0, 0, 0, 0, 0, 0, 0, // F64 1.0
+            0, 0, 0, 5, // global index
+            1, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // I64 -1
+            0, 0, 0, 2, // memory length
+            0xAA, 0xBB,
+        ],
+    );
+}
+
+#[test]
+fn golden_recorded_results() {
+    check_golden(
+        Message::RecordedResults {
+            time: 2.0,
+            results: vec![TaggedNumber::I64(1), TaggedNumber::F64(1.0)],
+        },
+        &[
+            9, // RecordedResults
+            0x40, 0, 0, 0, 0, 0, 0, 0, // time
+            0, 2, // results length
+            1, 0, 0, 0, 0, 0, 0, 0, 1, // I64 1
+            0, 0x3F, 0xF0, 0, 0, 0, 0, 0, 0, // F64 1.0
+        ],
+    );
+}
+
+#[test]
+fn golden_string() {
+    let mut writer = MessageWriter::new();
+    writer.write_string("hi");
+    assert_eq!(writer.output, [0, 0, 0, 2, b'h', b'i']);
+    assert_eq!(MessageReader::new(&writer.output).read_string(), Ok("hi"));
+}
+
+#[test]
+fn decode_rejects_truncated_and_unknown() {
+    assert_eq!(decode_message(&[]), Err(ErrorCode::Truncated));
+    assert_eq!(decode_message(&[0, 0, 0]), Err(ErrorCode::Truncated));
+    assert_eq!(decode_message(&[3]), Err(ErrorCode::Corrupt));
+    assert_eq!(decode_message(&[200]), Err(ErrorCode::Corrupt));
+    assert_eq!(decode_message(&[2, 0]), Err(ErrorCode::Corrupt));
+}
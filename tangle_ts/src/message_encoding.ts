@@ -136,6 +136,22 @@ export class MessageWriterReader {
         }
     }
 
+    write_tagged_numbers(numbers: Array<number | bigint>) {
+        this.write_u16(numbers.length);
+        for (const number of numbers) {
+            this.write_tagged_number(number);
+        }
+    }
+
+    read_tagged_numbers(): Array<number | bigint> {
+        const length = this.read_u16();
+        const numbers = new Array(length);
+        for (let i = 0; i < length; i++) {
+            numbers[i] = this.read_tagged_number();
+        }
+        return numbers;
+    }
+
     write_wasm_snapshot(snapshot: WasmSnapshot): void {
         this.write_time_stamp(snapshot.time_stamp);
 
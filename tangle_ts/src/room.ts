@@ -8,6 +8,8 @@ export interface RoomConfiguration {
     on_peer_joined?: (peer_id: PeerId) => void;
     on_peer_left?: (peer_id: PeerId, time: number) => void;
     on_message?: (peer_id: PeerId, message: Uint8Array) => void;
+    // Compressed messages that would decode to more than this many bytes are discarded.
+    max_decoded_message_size?: number;
 }
 
 export type PeerId = number;
@@ -36,6 +38,10 @@ enum MessageType {
 
 const MAX_MESSAGE_SIZE = 16_000;
 
+// Used when the configuration doesn't set a limit, for example for guests that don't declare
+// a maximum memory size.
+const DEFAULT_MAX_DECODED_MESSAGE_SIZE = 64 * 1024 * 1024;
+
 // TODO: This is generated by ChatGPT. Audit it.
 function compute_id_from_ip(ipAddress: string): number {
     let uniqueNumber = 0;
@@ -373,7 +379,10 @@ export class Room {
                         }
                         case MessageType.SinglePartGzipped: {
                             // Call the user provided callback
-                            const data = this._rust_utilities.gzip_decode(message_data.subarray(1));
+                            const data = this._decode_message(peer_id, message_data.subarray(1));
+                            if (!data) {
+                                break;
+                            }
                             // TODO: This introduces a potential one-frame delay on incoming events.
                             setTimeout(() => {
                                 this._configuration.on_message?.(peer_id, data);
@@ -408,19 +417,31 @@ export class Room {
         peer.latest_message_offset += data.byteLength;
 
         if (peer.latest_message_offset == peer.latest_message_data.length) {
-            let data = peer.latest_message_data;
-            data = this._rust_utilities.gzip_decode(data);
+            const data = this._decode_message(peer.id, peer.latest_message_data);
 
             // TODO: This introduces a potential one-frame delay on incoming events.
             // Message received
-            setTimeout(() => {
-                this._configuration.on_message?.(peer.id, data);
-            }, this._artificial_delay);
+            if (data) {
+                setTimeout(() => {
+                    this._configuration.on_message?.(peer.id, data);
+                }, this._artificial_delay);
+            }
             peer.latest_message_offset = 0;
             peer.latest_message_data = new Uint8Array(0);
         }
     }
 
+    // Decompresses a message from a peer, or returns undefined if it is corrupt or too large.
+    private _decode_message(peer_id: PeerId, data: Uint8Array): Uint8Array | undefined {
+        const max_size = this._configuration.max_decoded_message_size ?? DEFAULT_MAX_DECODED_MESSAGE_SIZE;
+        try {
+            return this._rust_utilities.gzip_decode(data, max_size);
+        } catch (e) {
+            console.error("[room] Discarding undecodable message from peer: ", peer_id, e);
+            return undefined;
+        }
+    }
+
     private remove_peer(peer_id: PeerId, time: number) {
         const peer = this._peers.get(peer_id);
 
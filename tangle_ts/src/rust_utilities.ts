@@ -2,6 +2,112 @@ import { MessageWriterReader } from "./message_encoding";
 import { WasmSnapshot } from "./time_machine";
 
 const decoder = new TextDecoder();
+const encoder = new TextEncoder();
+
+// Mirrors `ErrorCode` in `rust_utilities`.
+enum ErrorCode {
+    Ok,
+    InvalidHandle,
+    InvalidInput,
+    OutputTooLarge,
+    Truncated,
+    Corrupt,
+    UnsupportedVersion,
+}
+
+export type WasmValueType = "i32" | "i64" | "f32" | "f64" | "v128" | "externref" | "funcref";
+
+// Mirrors `Manifest` in `wasm_guardian`.
+export type WasmExportManifest = { name: string } & (
+    // `side_effects` is empty for exports that provably change nothing.
+    { kind: "function", params: Array<WasmValueType>, results: Array<WasmValueType>, side_effects: Array<"writes_memory" | "writes_globals" | "calls_imports"> } |
+    // `index` and `original_name` refer to the global in the module before it was transformed.
+    { kind: "global", type: WasmValueType, mutable: boolean, index: number, original_name: string | null } |
+    { kind: "memory", initial_pages: number, maximum_pages: number | null, shared: boolean } |
+    { kind: "table" }
+);
+
+export type ProcessBinaryOptions = {
+    export_globals: boolean,
+    track_changes: boolean,
+    // Report the memory each call reads with `wasm_guardian.on_load(address, size)`.
+    track_reads?: boolean,
+    // For debugging: export `wg_watch(slot, address, size)` and call
+    // `wasm_guardian.on_watch_hit(func_index, address, size, value)` before writes to watched ranges.
+    watchpoints?: boolean,
+    // Export `wg_caller_id` and `wg_event_time` globals for the host to set before each call.
+    invocation_context?: boolean,
+    // Replace imported sources of randomness, like `env.seed`, with a generator in the module
+    // that's seeded by calling `wg_seed_random`.
+    deterministic_random?: boolean,
+    // Implement WASI preview1 imports inside the module. Implies `invocation_context` and `deterministic_random`.
+    wasi?: boolean,
+    // If provided a `wg_dispatch` export is generated that can only call the listed exports.
+    // Names may use `*` wildcards.
+    dispatch_allowlist?: Array<string>,
+    // Imports whose results are recorded by the calling peer and replayed everywhere else,
+    // as `module.name` patterns that may use `*` wildcards.
+    recorded_imports?: Array<string>,
+    // Only instrument some functions for `track_changes`: the included functions, or every entry
+    // point if none are, and everything they call, minus excluded entry points that tracked
+    // code doesn't call. Patterns match export or name-section names with `*` wildcards, or are
+    // `#` followed by a function index.
+    instrument_include?: Array<string>,
+    instrument_exclude?: Array<string>,
+};
+
+export type WasmManifest = {
+    // In the same order as `Object.keys(instance.exports)`.
+    exports: Array<WasmExportManifest>,
+    data_segments: Array<{ offset: number, length: number }>,
+    // Indexed by the `recorded_index` passed to `record_result_*` and `replay_result_*`.
+    recorded_imports: Array<{ module: string, name: string, result: WasmValueType | null }>,
+};
+
+// Mirrors `InstrumentationStatistics` in `wasm_guardian`.
+export type WasmInstrumentationStatistics = {
+    // Sizes of the code section in bytes.
+    code_size_before: number,
+    code_size_after: number,
+    // Instrumented instructions by name, like "i32.store8" or "memory.copy".
+    totals: Record<string, number>,
+    // Most instrumented first. `index` is `null` for functions added by the transform.
+    functions: Array<{ index: number | null, name: string | null, total: number, instructions: Record<string, number> }>,
+};
+
+// Memory ranges a call read and wrote, as `[address, size]`, from `on_load` and `on_store`.
+export type MemoryAccessSet = {
+    reads: Array<[number, number]>,
+    writes: Array<[number, number]>,
+};
+
+// Mirrors `Provenance` in `wasm_guardian`.
+export type WasmProvenance = {
+    // The version of `wasm_guardian` that processed the binary.
+    version: string,
+    // The `semantic_hash` of the binary before it was processed, as 32 hex digits, which is also
+    // the processed binary's `semantic_hash`. Nothing checks it.
+    original_hash: string,
+    options: Required<Omit<ProcessBinaryOptions, "dispatch_allowlist" | "instrument_include" | "instrument_exclude">> & {
+        dispatch_allowlist: Array<string> | null,
+        instrumentation_filter: { include: Array<string>, exclude: Array<string> } | null,
+    },
+};
+
+// Mirrors `ReloadReport` in `wasm_guardian`.
+export type ReloadReport = {
+    compatible: boolean,
+    incompatibilities: Array<
+        { kind: "export_removed" | "export_signature_changed", name: string } |
+        { kind: "global_removed", old_index: number } |
+        { kind: "global_type_changed", old_index: number, new_index: number } |
+        { kind: "memory_removed" | "memory_too_small" | "shared_memory_changed" | "data_segments_changed" }
+    >,
+    // `[old, new]` global indices, as in `WasmExportManifest`, for globals whose values carry over.
+    globals: Array<[number, number]>,
+    // `[old, new]` export indices for exports in both modules.
+    exports: Array<[number, number]>,
+};
 
 export class RustUtilities {
     private _rust_utilities: WebAssembly.WebAssemblyInstantiatedSource;
@@ -43,36 +149,60 @@ export class RustUtilities {
         return new RustUtilities(rust_utilities);
     }
 
-    gzip_decode(data_to_decode: Uint8Array) {
-        const memory = this._rust_utilities.instance.exports.memory as WebAssembly.Memory;
-        const instance = this._rust_utilities.instance.exports;
+    // Copies `data` into a new `rust_utilities` buffer and returns its handle.
+    private _write_buffer(data: Uint8Array): number {
+        const exports = this._rust_utilities.instance.exports;
+        const memory = exports.memory as WebAssembly.Memory;
 
-        const pointer = (instance.reserve_space as CallableFunction)(data_to_decode.byteLength);
-        const destination = new Uint8Array(memory.buffer, pointer, data_to_decode.byteLength);
-        destination.set(data_to_decode);
+        const handle = (exports.buffer_new as CallableFunction)(data.byteLength);
+        const pointer = (exports.buffer_ptr as CallableFunction)(handle);
+        new Uint8Array(memory.buffer, pointer, data.byteLength).set(data);
+        return handle;
+    }
 
-        (instance.gzip_decode as CallableFunction)();
-        const result_pointer = new Uint32Array(memory.buffer, pointer, 2);
-        const result_data = new Uint8Array(memory.buffer, result_pointer[0], result_pointer[1]);
-        return new Uint8Array(result_data);
+    // Copies a buffer's contents out of `rust_utilities` memory and frees the buffer.
+    private _take_buffer(handle: number): Uint8Array {
+        const exports = this._rust_utilities.instance.exports;
+        const memory = exports.memory as WebAssembly.Memory;
+
+        const pointer = (exports.buffer_ptr as CallableFunction)(handle);
+        const length = (exports.buffer_len as CallableFunction)(handle);
+        const result = new Uint8Array(new Uint8Array(memory.buffer, pointer, length));
+        (exports.buffer_free as CallableFunction)(handle);
+        return result;
     }
 
+    // Runs an operation export on `input` and returns its output.
+    // The input buffer is freed even if the operation fails.
+    private _run_operation(operation: string, input: number, ...args: Array<number | boolean>): Uint8Array {
+        return this._run_operation_with_outputs(operation, input, 1, ...args)[0];
+    }
 
-    // TODO: These are just helpers and aren't that related to the rest of the code in this:
-    gzip_encode(data_to_compress: Uint8Array) {
-        const memory = this._rust_utilities.instance.exports.memory as WebAssembly.Memory;
+    private _run_operation_with_outputs(operation: string, input: number, output_count: number, ...args: Array<number | boolean>): Array<Uint8Array> {
         const exports = this._rust_utilities.instance.exports;
 
-        const pointer = (exports.reserve_space as CallableFunction)(data_to_compress.byteLength);
-        const destination = new Uint8Array(memory.buffer, pointer, data_to_compress.byteLength);
-        destination.set(new Uint8Array(data_to_compress));
+        const outputs = [];
+        for (let i = 0; i < output_count; i++) {
+            outputs.push((exports.buffer_new as CallableFunction)(0));
+        }
+        const error_code: ErrorCode = (exports[operation] as CallableFunction)(input, ...outputs, ...args);
+        (exports.buffer_free as CallableFunction)(input);
+
+        const results = outputs.map((output) => this._take_buffer(output));
+        if (error_code != ErrorCode.Ok) {
+            throw new Error(`[tangle] rust_utilities.${operation} failed: ${ErrorCode[error_code]}`);
+        }
+        return results;
+    }
+
+    // Throws if the data is corrupt, truncated, or would decode to more than `max_output_size` bytes.
+    gzip_decode(data_to_decode: Uint8Array, max_output_size: number): Uint8Array {
+        return this._run_operation("gzip_decode", this._write_buffer(data_to_decode), max_output_size);
+    }
 
-        (exports.gzip_encode as CallableFunction)();
-        const result_pointer = new Uint32Array(memory.buffer, pointer, 2);
-        const result_data = new Uint8Array(memory.buffer, result_pointer[0], result_pointer[1]);
-        // console.log("COMPRESSED LENGTH: ", result_data.byteLength);
-        // console.log("COMPRESSION RATIO: ", data_to_compress.byteLength / result_data.byteLength);
-        return result_data;
+    // TODO: These are just helpers and aren't that related to the rest of the code in this:
+    gzip_encode(data_to_compress: Uint8Array): Uint8Array {
+        return this._run_operation("gzip_encode", this._write_buffer(data_to_compress));
     }
 
     hash_data(...data_to_hash: Array<Uint8Array>): Uint8Array {
@@ -81,20 +211,66 @@ export class RustUtilities {
             byteLength += data.byteLength;
         }
 
-        const memory = this._rust_utilities.instance.exports.memory as WebAssembly.Memory;
-        const instance = this._rust_utilities.instance.exports;
+        const exports = this._rust_utilities.instance.exports;
+        const memory = exports.memory as WebAssembly.Memory;
 
-        const pointer = (instance.reserve_space as CallableFunction)(byteLength);
+        const input = (exports.buffer_new as CallableFunction)(byteLength);
+        const pointer = (exports.buffer_ptr as CallableFunction)(input);
 
         let offset = 0;
         for (const data of data_to_hash) {
             const destination = new Uint8Array(memory.buffer, pointer + offset, data.byteLength);
-            destination.set(new Uint8Array(data));
+            destination.set(data);
             offset += data.byteLength;
         }
-        (instance.xxh3_128_bit_hash as CallableFunction)();
-        const hashed_result = new Uint8Array(new Uint8Array(memory.buffer, pointer, 16));
-        return hashed_result;
+        return this._run_operation("xxh3_128_bit_hash", input);
+    }
+
+    // Hashes only the parts of `wasm_binary` that affect how it runs, so builds that differ in
+    // names or debug info hash the same. A processed binary hashes the same as its original.
+    semantic_hash(wasm_binary: Uint8Array): Uint8Array {
+        return this._run_operation("semantic_hash", this._write_buffer(wasm_binary));
+    }
+
+    // A short URL-safe string form of `semantic_hash`.
+    room_id(wasm_binary: Uint8Array): string {
+        return decoder.decode(this._run_operation("room_id", this._write_buffer(wasm_binary)));
+    }
+
+    // Rewrites a source map for a binary passed to `process_binary` so that it describes the
+    // processed binary, so that devtools can still show source locations. `code_offsets` is
+    // returned by `process_binary`.
+    rewrite_source_map(source_map: string, code_offsets: Uint8Array): string {
+        const exports = this._rust_utilities.instance.exports;
+
+        const offsets = this._write_buffer(code_offsets);
+        try {
+            const result = this._run_operation("rewrite_source_map", this._write_buffer(encoder.encode(source_map)), offsets);
+            return decoder.decode(result);
+        } finally {
+            (exports.buffer_free as CallableFunction)(offsets);
+        }
+    }
+
+    // The index in `later` of the first call that `late` conflicts with, or `undefined` if
+    // `late` can run after them without rolling back. `later` are the calls that ran after
+    // `late` should have, in order.
+    access_sets_conflict(late: MemoryAccessSet, later: Array<MemoryAccessSet>): number | undefined {
+        const exports = this._rust_utilities.instance.exports;
+
+        const later_buffer = this._write_buffer(encode_access_sets(later));
+        try {
+            const result = this._run_operation("access_sets_conflict", this._write_buffer(encode_access_sets([late])), later_buffer);
+            return result.byteLength == 0 ? undefined : new DataView(result.buffer, result.byteOffset).getUint32(0, true);
+        } finally {
+            (exports.buffer_free as CallableFunction)(later_buffer);
+        }
+    }
+
+    // How `wasm_binary` was processed by `process_binary`, or `undefined` if it wasn't.
+    read_provenance(wasm_binary: Uint8Array): WasmProvenance | undefined {
+        const json = this._run_operation("read_provenance", this._write_buffer(wasm_binary));
+        return json.byteLength == 0 ? undefined : JSON.parse(decoder.decode(json));
     }
 
     hash_snapshot(wasm_snapshot: WasmSnapshot): Uint8Array {
@@ -114,24 +290,96 @@ export class RustUtilities {
         return result;
     }
 
-    process_binary(wasm_binary: Uint8Array, export_globals: boolean, track_changes: boolean) {
-        if (!(export_globals || track_changes)) {
-            return wasm_binary;
+    // Returns a copy of `wasm_binary` that starts in the state of `snapshot`, where `main` and
+    // `_start` do nothing. Exports keep their indices. `snapshot` must come from an instance of `wasm_binary`.
+    bake_snapshot(wasm_binary: Uint8Array, snapshot: WasmSnapshot): Uint8Array {
+        const exports = this._rust_utilities.instance.exports;
+
+        const size = 8 + 8 + 2 + (4 + 9) * snapshot.globals.length + 4 + snapshot.memory.byteLength;
+        const writer = new MessageWriterReader(new Uint8Array(size));
+        writer.write_wasm_snapshot(snapshot);
+        const snapshot_buffer = this._write_buffer(writer.get_result_array());
+        try {
+            return this._run_operation("bake_snapshot", this._write_buffer(wasm_binary), snapshot_buffer);
+        } finally {
+            (exports.buffer_free as CallableFunction)(snapshot_buffer);
         }
+    }
 
-        const length = wasm_binary.byteLength;
-        const pointer = (this._rust_utilities.instance.exports.reserve_space as CallableFunction)(length);
+    // Compares the binary a room is running with a new version of it. `memory_pages` is the running
+    // instance's memory size in pages. `wasm_binary` is the new version prepared to start from the
+    // old version's memory and globals.
+    prepare_reload(old_wasm_binary: Uint8Array, new_wasm_binary: Uint8Array, memory_pages: number): { wasm_binary: Uint8Array, report: ReloadReport } {
+        const exports = this._rust_utilities.instance.exports;
 
-        const memory = this._rust_utilities.instance.exports.memory as WebAssembly.Memory;
+        const old_buffer = this._write_buffer(old_wasm_binary);
+        try {
+            const [wasm_binary, report] = this._run_operation_with_outputs("prepare_reload", this._write_buffer(new_wasm_binary), 2, old_buffer, memory_pages);
+            return {
+                wasm_binary,
+                report: JSON.parse(decoder.decode(report)),
+            };
+        } finally {
+            (exports.buffer_free as CallableFunction)(old_buffer);
+        }
+    }
 
-        const data_location = new Uint8Array(memory.buffer, pointer, length);
-        data_location.set(new Uint8Array(wasm_binary));
-        (this._rust_utilities.instance.exports.prepare_wasm as CallableFunction)(export_globals, track_changes);
+    // A binary that was already processed with the same options is returned unchanged.
+    // Throws if it was processed with different options.
+    // `statistics` and `code_offsets` are `undefined` if `wasm_binary` was already processed.
+    // Pass `code_offsets` to `rewrite_source_map`.
+    process_binary(wasm_binary: Uint8Array, options: ProcessBinaryOptions): { wasm_binary: Uint8Array, manifest: WasmManifest, statistics?: WasmInstrumentationStatistics, code_offsets?: Uint8Array } {
+        const exports = this._rust_utilities.instance.exports;
 
-        // TODO: Write these to an output buffer instead of having two calls for them.
-        const output_ptr = (this._rust_utilities.instance.exports.get_output_ptr as CallableFunction)();
-        const output_len = (this._rust_utilities.instance.exports.get_output_len as CallableFunction)();
-        const output_wasm = new Uint8Array(memory.buffer, output_ptr, output_len);
-        return output_wasm;
+        const allowlist = options.dispatch_allowlist ? this._write_buffer(encoder.encode(options.dispatch_allowlist.join("\n"))) : 0;
+        const recorded_imports = options.recorded_imports ? this._write_buffer(encoder.encode(options.recorded_imports.join("\n"))) : 0;
+        const instrument_include = options.instrument_include ? this._write_buffer(encoder.encode(options.instrument_include.join("\n"))) : 0;
+        const instrument_exclude = options.instrument_exclude ? this._write_buffer(encoder.encode(options.instrument_exclude.join("\n"))) : 0;
+        try {
+            const [output_wasm, manifest, statistics, code_offsets] = this._run_operation_with_outputs("prepare_wasm", this._write_buffer(wasm_binary), 4,
+                options.export_globals, options.track_changes, options.track_reads ?? false, options.watchpoints ?? false, options.invocation_context ?? false,
+                options.deterministic_random ?? false, options.wasi ?? false, allowlist, recorded_imports,
+                instrument_include, instrument_exclude);
+            return {
+                wasm_binary: output_wasm,
+                manifest: JSON.parse(decoder.decode(manifest)),
+                statistics: statistics.byteLength == 0 ? undefined : JSON.parse(decoder.decode(statistics)),
+                code_offsets: statistics.byteLength == 0 ? undefined : code_offsets,
+            };
+        } finally {
+            if (allowlist) {
+                (exports.buffer_free as CallableFunction)(allowlist);
+            }
+            if (recorded_imports) {
+                (exports.buffer_free as CallableFunction)(recorded_imports);
+            }
+            for (const patterns of [instrument_include, instrument_exclude]) {
+                if (patterns) {
+                    (exports.buffer_free as CallableFunction)(patterns);
+                }
+            }
+        }
+    }
+}
+
+// Writes access sets in the layout `rust_utilities::access_sets` reads: for reads and then
+// writes, a count followed by each range's address and size, all little-endian u32s.
+function encode_access_sets(sets: Array<MemoryAccessSet>): Uint8Array {
+    const length = sets.reduce((length, set) => length + 8 + (set.reads.length + set.writes.length) * 8, 0);
+    const view = new DataView(new ArrayBuffer(length));
+    let offset = 0;
+    const write_u32 = (value: number) => {
+        view.setUint32(offset, value, true);
+        offset += 4;
+    };
+    for (const set of sets) {
+        for (const ranges of [set.reads, set.writes]) {
+            write_u32(ranges.length);
+            for (const [address, size] of ranges) {
+                write_u32(address);
+                write_u32(size);
+            }
+        }
     }
-}
\ No newline at end of file
+    return new Uint8Array(view.buffer);
+}
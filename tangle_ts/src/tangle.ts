@@ -13,7 +13,11 @@ enum MessageType {
     SetHeap,
     // Used to figure out roundtrip time.
     Ping,
-    Pong
+    Pong,
+    // Reserved for the compact encoding in `rust_utilities/src/compact_encoding.rs`.
+    CompactWasmCall,
+    CompactTimeProgressed,
+    RecordedResults,
 }
 
 type PeerData = {
@@ -34,6 +38,12 @@ export enum TangleState {
 type TangleConfiguration = {
     fixed_update_interval?: number;
     accept_new_programs?: boolean,
+    // Imports that may return values, as `module.name` patterns that may use `*` wildcards.
+    // Their results are recorded by the peer that makes a call and replayed everywhere else.
+    recorded_imports?: Array<string>,
+    // Seeds the generator that replaces imported randomness like `env.seed`.
+    // Only the seed of the peer that creates the room is used. Random if unspecified.
+    random_seed?: bigint,
     room_name?: string,
     ice_servers?: RTCIceServer[],
     room_server?: string,
@@ -53,6 +63,22 @@ export const UserId = new UserIdType();
 
 const ROUND_TRIP_TIME_ROLLING_AVERAGE_ALPHA = 0.9;
 
+// Room for the globals, events and headers sent alongside a snapshot of the guest's memory.
+const STATE_MESSAGE_OVERHEAD = 1024 * 1024;
+
+// `gzip_decode` takes its limit as a `usize`, which is 32 bits in WebAssembly.
+const MAX_DECODED_MESSAGE_SIZE = 0xFFFFFFFF;
+
+// Matches `module.name` against `module.name` patterns where `*` matches any sequence of characters,
+// the same way `wasm_guardian` does.
+function is_recorded_import(patterns: Array<string> | undefined, module_name: string, import_name: string): boolean {
+    const full_name = module_name + "." + import_name;
+    return (patterns ?? []).some((pattern) => {
+        const escaped = pattern.split("*").map((part) => part.replace(/[.+?^${}()|[\]\\]/g, "\\$&"));
+        return new RegExp("^" + escaped.join(".*") + "$", "s").test(full_name);
+    });
+}
+
 export class Tangle {
     private _room!: Room;
     private _time_machine!: TimeMachine;
@@ -86,9 +112,9 @@ export class Tangle {
         // Wrap all imports so that they can't return a value, which would cause desyncs.
         // This may need more thought in the future because it's a big limitation.
         if (importObject) {
-            Object.values(importObject).forEach((moduleImports) => {
+            Object.entries(importObject).forEach(([moduleName, moduleImports]) => {
                 Object.entries(moduleImports).forEach(([importName, importValue]) => {
-                    if (typeof importValue === 'function') {
+                    if (typeof importValue === 'function' && !is_recorded_import(tangle_configuration!.recorded_imports, moduleName, importName)) {
                         moduleImports[importName] = function (...args: any) {
                             const r = importValue(...args);
                             // This call will be reverted so it's OK if it causes a temporary desync.
@@ -104,7 +130,7 @@ export class Tangle {
             });
         }
 
-        const time_machine = await TimeMachine.setup(wasm_binary, importObject, tangle_configuration.fixed_update_interval);
+        const time_machine = await TimeMachine.setup(wasm_binary, importObject, tangle_configuration.fixed_update_interval, tangle_configuration.recorded_imports, tangle_configuration.random_seed);
 
         const tangle = new Tangle(time_machine);
         tangle._configuration = tangle_configuration;
@@ -126,6 +152,13 @@ export class Tangle {
     constructor(time_machine: TimeMachine) {
         this._time_machine = time_machine;
         this._rust_utilities = time_machine.rust_utilities;
+
+        this._time_machine.on_recorded_results = (event) => {
+            // Do not send events to the room if we're not yet fully connected.
+            if (this._tangle_state == TangleState.Connected) {
+                this._room.send_message(this._encode_recorded_results_message(event.time_stamp.time, event.recorded_results!));
+            }
+        };
     }
 
     private _change_state(state: TangleState) {
@@ -145,13 +178,20 @@ export class Tangle {
         room_name ??= document.location.href;
 
         // Append a hash of the binary so that peers won't join rooms without matching binaries.
-        const hash = this._rust_utilities.hash_data(wasm_binary);
-        room_name += hash.join("");
+        // Only the parts that affect execution are hashed, so peers with builds that differ
+        // in debug info can still play together. A prebuilt `--tangle` build hashes the same as
+        // its original build.
+        room_name += this._rust_utilities.room_id(this._time_machine.processed_binary());
+
+        // The largest message a peer needs to send is the guest's state, so anything that decodes
+        // to more than its largest memory is discarded.
+        const max_memory_size = this._time_machine.max_memory_size();
 
         const room_configuration = {
             server_url: this._configuration.room_server,
             ice_servers: this._configuration.ice_servers,
             room_name,
+            max_decoded_message_size: max_memory_size === undefined ? undefined : Math.min(max_memory_size + STATE_MESSAGE_OVERHEAD, MAX_DECODED_MESSAGE_SIZE),
             on_peer_joined: (peer_id: PeerId) => {
                 this._peer_data.set(peer_id, {
                     last_received_message: 0,
@@ -237,6 +277,11 @@ export class Tangle {
                             const m = this._decode_wasm_call_message(message_data);
                             peer.last_received_message = m.time;
 
+                            if (!this._time_machine.is_valid_call(m.function_index, m.args)) {
+                                console.error("[tangle] Rejected invalid Wasm call from peer: ", peer_id, m.function_index);
+                                break;
+                            }
+
                             const time_stamp = {
                                 time: m.time,
                                 player_id: peer_id
@@ -258,6 +303,24 @@ export class Tangle {
 
                             break;
                         }
+                        case (MessageType.RecordedResults): {
+                            const message_reader = new MessageWriterReader(message_data);
+                            const time_stamp = {
+                                time: message_reader.read_f64(),
+                                player_id: peer_id
+                            };
+                            const results = message_reader.read_tagged_numbers();
+
+                            if (this._tangle_state == TangleState.RequestingHeap) {
+                                const buffered = this._buffered_messages.find((m) => m.time_stamp.time == time_stamp.time && m.time_stamp.player_id == peer_id);
+                                if (buffered) {
+                                    buffered.recorded_results = results;
+                                }
+                            } else {
+                                this._time_machine.set_recorded_results(time_stamp, results);
+                            }
+                            break;
+                        }
                         case (MessageType.RequestState): {
                             // TODO: Check that this is a fully loaded peer.
                             const heap_message = this._time_machine.encode(MessageType.SetHeap);
@@ -275,6 +338,9 @@ export class Tangle {
                                 // Apply any messages that were received as we were waiting for this to load.
                                 for (const m of this._buffered_messages) {
                                     await this._time_machine.call_with_time_stamp(m.function_export_index, m.args, m.time_stamp);
+                                    if (m.recorded_results) {
+                                        this._time_machine.set_recorded_results(m.time_stamp, m.recorded_results);
+                                    }
                                 }
                                 this._buffered_messages = [];
 
@@ -385,6 +451,14 @@ export class Tangle {
         return this._outgoing_message_buffer.subarray(0, message_writer.offset);
     }
 
+    private _encode_recorded_results_message(time: number, results: Array<number | bigint>): Uint8Array {
+        const message_writer = new MessageWriterReader(new Uint8Array(1 + 8 + 2 + results.length * 9));
+        message_writer.write_u8(MessageType.RecordedResults);
+        message_writer.write_f64(time);
+        message_writer.write_tagged_numbers(results);
+        return message_writer.get_result_array();
+    }
+
     private _decode_wasm_call_message(data: Uint8Array) {
         const message_reader = new MessageWriterReader(data);
 
@@ -491,7 +565,7 @@ export class Tangle {
 
             const function_index = this._time_machine.get_function_export_index(function_name);
             if (function_index !== undefined) {
-                await this._time_machine.call_with_time_stamp(function_index, args_processed, time_stamp);
+                await this._time_machine.call_with_time_stamp(function_index, args_processed, time_stamp, true);
 
                 // Do not send events to the room if we're not yet fully connected.
                 if (this._tangle_state == TangleState.Connected) {
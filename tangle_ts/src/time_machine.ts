@@ -1,5 +1,5 @@
 import { MessageWriterReader } from "./message_encoding";
-import { RustUtilities } from "./rust_utilities";
+import { RustUtilities, WasmManifest } from "./rust_utilities";
 
 const WASM_PAGE_SIZE = 65536;
 
@@ -27,7 +27,11 @@ export type FunctionCall = {
     function_export_index: number,
     args: Array<number>,
     time_stamp: TimeStamp,
-    hash?: Uint8Array
+    hash?: Uint8Array,
+    // Results returned by recorded imports during this call, in the order they were made.
+    recorded_results?: Array<number | bigint>,
+    // If this peer should record import results the first time the call runs.
+    record_imports?: boolean,
 };
 
 export type WasmSnapshot = {
@@ -59,6 +63,18 @@ export class TimeMachine {
     _wasm_instance: WebAssembly.WebAssemblyInstantiatedSource;
     private _imports: WebAssembly.Imports = {};
 
+    private _manifest: WasmManifest = { exports: [], data_segments: [], recorded_imports: [] };
+    // The binary after `process_binary`, which is the same whether or not the input was already processed.
+    private _processed_binary: Uint8Array = new Uint8Array();
+
+    // Collects results while an event records its imports.
+    private _recording?: Array<number | bigint>;
+    private _replaying: Array<number | bigint> = [];
+    private _replay_position = 0;
+
+    /// Called when a call made by this peer first records import results.
+    /// The results need to be sent to other peers so they can replay them.
+    on_recorded_results?: (event: FunctionCall) => void;
     private _global_indices: Array<number> = [];
     private _exports: Array<WebAssembly.ExportValue> = [];
     private _export_keys: Array<string> = [];
@@ -77,7 +93,7 @@ export class TimeMachine {
         this.rust_utilities = rust_utilities;
     }
 
-    static async setup(wasm_binary: Uint8Array, imports: WebAssembly.Imports, fixed_update_interval?: number): Promise<TimeMachine> {
+    static async setup(wasm_binary: Uint8Array, imports: WebAssembly.Imports, fixed_update_interval?: number, recorded_imports?: Array<string>, random_seed?: bigint): Promise<TimeMachine> {
         const rust_utilities = await RustUtilities.setup();
 
         // TODO: These imports are for AssemblyScript, but they should be optional
@@ -88,19 +104,32 @@ export class TimeMachine {
             imports.env.abort ??= () => {
                 console.log("Ignoring call to abort");
             };
-            imports.env.seed ??= () => {
-                // TODO: Add more entropy
-                return 14;
-            };
         }
         let external_log: (a: number, b: number) => void = () => { console.log("Not implemented") };
         imports.env.external_log ??= (a: number, b: number) => external_log(a, b);
 
+        // Recorded imports report and replay their results through these.
+        let time_machine: TimeMachine | undefined;
+        imports.wasm_guardian ??= {};
+        for (const type of ["i32", "i64", "f32", "f64"]) {
+            imports.wasm_guardian[`record_result_${type}`] = (_index: number, value: number | bigint) => time_machine?._recording?.push(value);
+            imports.wasm_guardian[`replay_result_${type}`] = (_index: number) => time_machine!._replay_result(type == "i64");
+        }
+
 
-        wasm_binary = rust_utilities.process_binary(wasm_binary, true, false);
-        const wasm_instance = await WebAssembly.instantiate(wasm_binary, imports);
+        const processed = rust_utilities.process_binary(wasm_binary, {
+            export_globals: true,
+            track_changes: false,
+            invocation_context: true,
+            deterministic_random: true,
+            wasi: true,
+            recorded_imports,
+        });
+        const wasm_instance = await WebAssembly.instantiate(processed.wasm_binary, imports);
 
-        const time_machine = new TimeMachine(wasm_instance, rust_utilities);
+        time_machine = new TimeMachine(wasm_instance, rust_utilities);
+        time_machine._manifest = processed.manifest;
+        time_machine._processed_binary = processed.wasm_binary;
 
         console.log("[tangle] Heap size: ", (wasm_instance.instance.exports.memory as WebAssembly.Memory).buffer.byteLength);
 
@@ -112,6 +141,13 @@ export class TimeMachine {
             console.log(decoded_string);
         };
 
+        // Only the seed of the peer that creates the room matters because peers that join
+        // receive the generator's state along with the rest of the module.
+        const seed_random = wasm_instance.instance.exports.wg_seed_random as CallableFunction | undefined;
+        if (seed_random) {
+            seed_random(random_seed ?? crypto.getRandomValues(new BigInt64Array(1))[0]);
+        }
+
         // When a module is setup call its main function immediately.
         // This may only be useful for Rust.
         {
@@ -126,7 +162,7 @@ export class TimeMachine {
 
         let j = 0;
         for (const key of Object.keys(wasm_instance.instance.exports)) {
-            if (key.slice(0, 3) == "wg_") {
+            if (key.slice(0, 3) == "wg_" && wasm_instance.instance.exports[key] instanceof WebAssembly.Global) {
                 time_machine._global_indices.push(j);
             }
 
@@ -163,6 +199,16 @@ export class TimeMachine {
         return decoded_string;
     }
 
+    /// The most memory the guest can grow to, in bytes, or undefined if it doesn't declare a maximum.
+    max_memory_size(): number | undefined {
+        for (const e of this._manifest.exports) {
+            if (e.kind == "memory" && e.maximum_pages != null) {
+                return e.maximum_pages * WASM_PAGE_SIZE;
+            }
+        }
+        return undefined;
+    }
+
     get_function_export_index(function_name: string): number | undefined {
         return this._function_name_to_index.get(function_name);
     }
@@ -171,8 +217,20 @@ export class TimeMachine {
         return this._export_keys[function_index];
     }
 
+    /// Checks a call received from a peer against the export's signature.
+    /// Invalid calls must be rejected before they run or they may fail on only some peers.
+    is_valid_call(function_export_index: number, args: Array<number>): boolean {
+        const e = this._manifest.exports[function_export_index];
+        if (e?.kind != "function" || e.params.length != args.length) {
+            return false;
+        }
+        // TODO: Arguments are networked as f64s so exports that take other types can't be called yet.
+        return e.params.every((param) => param == "i32" || param == "f32" || param == "f64");
+    }
+
     /// Returns the function call of this instance.
-    async call_with_time_stamp(function_export_index: number, args: Array<number>, time_stamp: TimeStamp) {
+    /// `record_imports` should be true for calls made by this peer.
+    async call_with_time_stamp(function_export_index: number, args: Array<number>, time_stamp: TimeStamp, record_imports = false) {
         if (time_stamp_compare(time_stamp, this._snapshots[0].time_stamp) == -1) {
             // TODO: This is an error. It's no longer possible to add events in the past.
             // Report a desync here.
@@ -221,6 +279,7 @@ export class TimeMachine {
             function_export_index,
             args,
             time_stamp,
+            record_imports,
         };
         // Insert after the found insertion point.
         this._events.splice(i + 1, 0, event);
@@ -236,12 +295,85 @@ export class TimeMachine {
         const f = this._exports[function_export_index] as CallableFunction;
 
         if (f) {
+            // Pure exports can't change anything that would need reverting.
+            const e = this._manifest.exports[function_export_index];
+            if (e?.kind == "function" && e.side_effects.length == 0) {
+                (f as CallableFunction)(...args);
+                return;
+            }
             const snapshot = this._get_wasm_snapshot();
             (f as CallableFunction)(...args);
             await this._apply_snapshot(snapshot);
         }
     }
 
+    /// Sets the recorded import results of a call received from a peer.
+    /// If the call has already run it's rolled back and run again with these results.
+    set_recorded_results(time_stamp: TimeStamp, results: Array<number | bigint>) {
+        const event = this._events.find((event) => time_stamp_compare(event.time_stamp, time_stamp) == 0);
+        if (!event) {
+            console.error("[tangle error] Received recorded results for an unknown call: ", time_stamp);
+            return;
+        }
+        event.recorded_results = results;
+
+        if (time_stamp_compare(time_stamp, this._current_simulation_time) != 1) {
+            // Roll back to before every event at this time so that this one runs again.
+            const before = { time: time_stamp.time, player_id: -Infinity };
+            if (this._need_to_rollback_to_time === undefined || time_stamp_compare(before, this._need_to_rollback_to_time) == -1) {
+                this._need_to_rollback_to_time = before;
+            }
+        }
+    }
+
+    // Calls made by this peer call recorded imports the first time they run.
+    // Every other run replays the recorded results.
+    private _begin_recorded_imports(event: Event) {
+        const replaying = this._wasm_instance.instance.exports.wg_replaying as WebAssembly.Global | undefined;
+        if (!replaying) {
+            return;
+        }
+        if (event.record_imports && event.recorded_results === undefined) {
+            this._recording = [];
+            replaying.value = 0;
+        } else {
+            // Results from peers may not have arrived yet, in which case defaults are returned
+            // until they arrive and the call is run again.
+            this._replaying = event.recorded_results ?? [];
+            this._replay_position = 0;
+            replaying.value = 1;
+        }
+    }
+
+    private _end_recorded_imports(event: Event) {
+        if (this._recording) {
+            event.recorded_results = this._recording;
+            this._recording = undefined;
+            if (event.recorded_results.length > 0) {
+                this.on_recorded_results?.(event);
+            }
+        }
+    }
+
+    private _replay_result(is_i64: boolean): number | bigint {
+        const value = this._replaying[this._replay_position++] ?? 0;
+        if (is_i64) {
+            return typeof value == "bigint" ? value : BigInt(value);
+        }
+        return Number(value);
+    }
+
+    // Lets the guest read who caused the call and when through `wg_caller_id` and `wg_event_time`.
+    private _set_invocation_context(time_stamp: TimeStamp) {
+        const exports = this._wasm_instance.instance.exports;
+        if (exports.wg_caller_id) {
+            (exports.wg_caller_id as WebAssembly.Global).value = time_stamp.player_id;
+        }
+        if (exports.wg_event_time) {
+            (exports.wg_event_time as WebAssembly.Global).value = time_stamp.time;
+        }
+    }
+
     private _progress_recurring_function_calls(target_time: number) {
         if (this._fixed_update_interval !== undefined && this._fixed_update_index !== undefined) {
             // Add `fixed_update` calls that go into the future.
@@ -256,6 +388,11 @@ export class TimeMachine {
         return this._target_time;
     }
 
+    // The binary that's running, as returned by `process_binary`.
+    processed_binary(): Uint8Array {
+        return this._processed_binary;
+    }
+
     // This is used in scenarios where a peer falls too far behind in a simulation. 
     // This lets them have normal visuals until they resync.
     set_target_time(time: number) {
@@ -318,7 +455,10 @@ export class TimeMachine {
         if (function_call !== undefined && function_call.time_stamp.time <= this._target_time) {
             const f = this._exports[function_call.function_export_index] as CallableFunction;
 
+            this._set_invocation_context(function_call.time_stamp);
+            this._begin_recorded_imports(function_call);
             f(...function_call.args);
+            this._end_recorded_imports(function_call);
 
             if (debug_mode) {
                 function_call.hash = this.hash_wasm_state();
@@ -432,8 +572,8 @@ export class TimeMachine {
 
             // Copy over all globals during the resize.
             for (const [key, v] of Object.entries(old_instance.exports)) {
-                if (key.slice(0, 3) == "wg_") {
-                    (this._wasm_instance.instance.exports[key] as WebAssembly.Global).value = v;
+                if (key.slice(0, 3) == "wg_" && v instanceof WebAssembly.Global) {
+                    (this._wasm_instance.instance.exports[key] as WebAssembly.Global).value = v.value;
                 }
             }
 
@@ -458,6 +598,7 @@ export class TimeMachine {
         let size = 1 + 8 * 4 + 4 + (4 + 8 + 8 + 1) * this._events.length;
         for (const event of this._events) {
             size += event.args.length * 8;
+            size += 2 + (event.recorded_results?.length ?? 0) * 9;
         }
         size += 8 + 8 + 2 + (4 + 9) * snapshot.globals.length;
         size += 4 + snapshot.memory.buffer.byteLength;
@@ -486,6 +627,7 @@ export class TimeMachine {
             for (const arg of event.args) {
                 writer.write_f64(arg);
             }
+            writer.write_tagged_numbers(event.recorded_results ?? []);
         }
 
         // Encode the snapshot
@@ -518,10 +660,12 @@ export class TimeMachine {
             for (let j = 0; j < args_length; ++j) {
                 args[j] = reader.read_f64();
             }
+            const recorded_results = reader.read_tagged_numbers();
             this._events[i] = {
                 function_export_index,
                 time_stamp,
                 args,
+                recorded_results,
             };
 
             if (!(time_stamp_compare(last_time_stamp, time_stamp) == -1)) {
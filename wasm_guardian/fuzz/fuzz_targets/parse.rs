@@ -0,0 +1,14 @@
+//! Arbitrary bytes through every function that reads a binary, which must fail cleanly
+//! instead of panicking.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    wasm_guardian::semantic_hash(data);
+    wasm_guardian::Provenance::read(data);
+    let _ = wasm_guardian::check_determinism(data);
+    // Only valid modules get past walrus, so this mostly exercises the custom section readers.
+    let _ = wasm_guardian::transform_wasm(data, &Default::default());
+});
@@ -0,0 +1,59 @@
+//! Transforms generated modules, then checks that the output validates and that running it
+//! matches the original with every write reported.
+
+#![no_main]
+
+use libfuzzer_sys::arbitrary::{Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+use wasm_guardian::TransformOptions;
+
+#[path = "../../tests/differential/harness.rs"]
+mod harness;
+
+/// Enough to run loops for a while without making each input slow.
+const FUEL: u64 = 100_000;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = run(&mut Unstructured::new(data));
+});
+
+fn run(u: &mut Unstructured) -> Result<()> {
+    let mut config: wasm_smith::Config = u.arbitrary()?;
+    // Limited to what walrus 0.19 parses, wasmi 0.31 runs and the transform instruments.
+    // Bulk memory is off because `data.drop` and the table instructions aren't handled yet.
+    config.bulk_memory_enabled = false;
+    config.reference_types_enabled = false;
+    config.simd_enabled = false;
+    config.relaxed_simd_enabled = false;
+    config.threads_enabled = false;
+    config.exceptions_enabled = false;
+    config.gc_enabled = false;
+    config.tail_call_enabled = false;
+    config.memory64_enabled = false;
+    config.custom_page_sizes_enabled = false;
+    config.max_memories = 1;
+    config.max_imports = 0;
+    config.export_everything = true;
+    let original = wasm_smith::Module::new(config, u)?.to_bytes();
+
+    let options = TransformOptions {
+        export_globals: true,
+        track_changes: true,
+        track_reads: u.arbitrary()?,
+        watchpoints: u.arbitrary()?,
+        invocation_context: u.arbitrary()?,
+        deterministic_random: u.arbitrary()?,
+        ..Default::default()
+    };
+    let transformed = wasm_guardian::transform_wasm(&original, &options)
+        .expect("generated modules are valid")
+        .wasm;
+    wasmparser::validate(&transformed).expect("transformed module is invalid");
+
+    // Transforming again must be a no-op.
+    let again = wasm_guardian::transform_wasm(&transformed, &options).unwrap();
+    assert_eq!(again.wasm, transformed);
+
+    harness::check_transform("generated", &original, &transformed, Some(FUEL));
+    Ok(())
+}
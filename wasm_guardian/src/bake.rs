@@ -0,0 +1,212 @@
+//! Writes a module's memory and global values back into the module, so that instantiating
+//! the result starts from that state instead of from scratch, similar to Wizer.
+
+use std::collections::HashSet;
+
+use walrus::ir::Value;
+use walrus::{
+    ActiveData, ActiveDataLocation, DataKind, ExportItem, FunctionBuilder, GlobalId, GlobalKind,
+    InitExpr, ValType,
+};
+
+use crate::globals::global_indices;
+
+pub const WASM_PAGE_SIZE: usize = 65536;
+
+/// Exports with these names do nothing after baking because they initialize state that's
+/// already baked in.
+pub const INITIALIZER_EXPORTS: &[&str] = &["main", "_start"];
+
+/// A global's value as hosts snapshot it: JavaScript represents i64 globals as `bigint` and
+/// every other number type as `number`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotValue {
+    F64(f64),
+    I64(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// The contents of memory 0.
+    pub memory: Vec<u8>,
+    /// Values keyed by the global's export index, the same index the host uses.
+    pub globals: Vec<(u32, SnapshotValue)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BakeError {
+    InvalidModule,
+    /// The snapshot has memory but the module doesn't.
+    NoMemory,
+    /// The snapshot's memory is larger than the memory's maximum size.
+    MemoryTooLarge,
+    /// The export index isn't a global defined by the module.
+    NotALocalGlobal(u32),
+    /// The value can't be represented as the global's type.
+    WrongValueType(u32),
+    /// The module has a mutable global, at this index, that isn't exported, so the snapshot
+    /// can't have its value. Transform the module with `export_globals` before snapshotting.
+    UnexportedGlobal(u32),
+}
+
+/// Returns a module that starts in the state of `snapshot`.
+///
+/// Active data segments of memory 0 are replaced by segments holding the non-zero bytes of
+/// each page. Globals in the snapshot get the snapshot's values as their initializers.
+/// The start function is removed and [INITIALIZER_EXPORTS] are replaced by functions that do
+/// nothing, so every export keeps its index. Tables are not part of the snapshot and are left
+/// unchanged.
+///
+/// Fails with [BakeError::UnexportedGlobal] if the module has a mutable global the host
+/// couldn't have snapshotted, since baking would silently reset it to its initial value.
+pub fn bake_snapshot(bytes: &[u8], snapshot: &Snapshot) -> Result<Vec<u8>, BakeError> {
+    let mut module = walrus::Module::from_buffer(bytes).map_err(|_| BakeError::InvalidModule)?;
+
+    let indices = global_indices(&module);
+    let exported_globals: HashSet<GlobalId> = module
+        .exports
+        .iter()
+        .filter_map(|e| match e.item {
+            ExportItem::Global(id) => Some(id),
+            _ => None,
+        })
+        .collect();
+    if let Some(global) = module.globals.iter().find(|g| {
+        g.mutable && matches!(g.kind, GlobalKind::Local(_)) && !exported_globals.contains(&g.id())
+    }) {
+        return Err(BakeError::UnexportedGlobal(indices[&global.id()]));
+    }
+
+    // Look up globals by export index before any exports are removed.
+    let exported: Vec<ExportItem> = module.exports.iter().map(|e| e.item).collect();
+    for (export_index, value) in &snapshot.globals {
+        let Some(ExportItem::Global(id)) = exported.get(*export_index as usize) else {
+            return Err(BakeError::NotALocalGlobal(*export_index));
+        };
+        let global = module.globals.get_mut(*id);
+        if !matches!(global.kind, GlobalKind::Local(_)) {
+            return Err(BakeError::NotALocalGlobal(*export_index));
+        }
+        let value =
+            convert_value(*value, global.ty).ok_or(BakeError::WrongValueType(*export_index))?;
+        global.kind = GlobalKind::Local(InitExpr::Value(value));
+    }
+
+    if !snapshot.memory.is_empty() {
+        let memory = module
+            .memories
+            .iter_mut()
+            .next()
+            .ok_or(BakeError::NoMemory)?;
+        let pages = snapshot.memory.len().div_ceil(WASM_PAGE_SIZE) as u32;
+        if memory.maximum.is_some_and(|maximum| pages > maximum) {
+            return Err(BakeError::MemoryTooLarge);
+        }
+        memory.initial = memory.initial.max(pages);
+        let memory_id = memory.id();
+
+        // The snapshot already contains everything the active segments would have written.
+        remove_active_data(&mut module, memory_id);
+
+        for (page_index, page) in snapshot.memory.chunks(WASM_PAGE_SIZE).enumerate() {
+            let (Some(first), Some(last)) = (
+                page.iter().position(|b| *b != 0),
+                page.iter().rposition(|b| *b != 0),
+            ) else {
+                continue;
+            };
+            let offset = (page_index * WASM_PAGE_SIZE + first) as u32;
+            let data = module.data.add(
+                DataKind::Active(ActiveData {
+                    memory: memory_id,
+                    location: ActiveDataLocation::Absolute(offset),
+                }),
+                page[first..=last].to_vec(),
+            );
+            module
+                .memories
+                .get_mut(memory_id)
+                .data_segments
+                .insert(data);
+        }
+    }
+
+    remove_initializers(&mut module);
+
+    Ok(module.emit_wasm())
+}
+
+pub(crate) fn remove_active_data(module: &mut walrus::Module, memory: walrus::MemoryId) {
+    let active: Vec<_> = module
+        .data
+        .iter()
+        .filter(|data| matches!(&data.kind, DataKind::Active(a) if a.memory == memory))
+        .map(|data| data.id())
+        .collect();
+    for id in active {
+        module.data.delete(id);
+        module.memories.get_mut(memory).data_segments.remove(&id);
+    }
+}
+
+/// Removes the start function and points [INITIALIZER_EXPORTS] at functions of the same type
+/// that do nothing and return zeros.
+///
+/// The exports are kept rather than deleted because hosts refer to exports by index, and
+/// deleting one would shift every export after it.
+pub(crate) fn remove_initializers(module: &mut walrus::Module) {
+    module.start = None;
+    let initializers: Vec<_> = module
+        .exports
+        .iter()
+        .filter(|e| INITIALIZER_EXPORTS.contains(&e.name.as_str()))
+        .filter_map(|e| match e.item {
+            ExportItem::Function(function) => Some((e.id(), function)),
+            _ => None,
+        })
+        .collect();
+    for (id, function) in initializers {
+        let ty = module.types.get(module.funcs.get(function).ty());
+        let (params, results) = (ty.params().to_vec(), ty.results().to_vec());
+        let args = params.iter().map(|ty| module.locals.add(*ty)).collect();
+        let mut builder = FunctionBuilder::new(&mut module.types, &params, &results);
+        let mut body = builder.func_body();
+        for result in &results {
+            body.const_(match result {
+                ValType::I32 => Value::I32(0),
+                ValType::I64 => Value::I64(0),
+                ValType::F32 => Value::F32(0.0),
+                ValType::F64 => Value::F64(0.0),
+                ValType::V128 => Value::V128(0),
+                ValType::Externref | ValType::Funcref => {
+                    body.ref_null(*result);
+                    continue;
+                }
+            });
+        }
+        let stub = builder.finish(args, &mut module.funcs);
+        module.exports.get_mut(id).item = ExportItem::Function(stub);
+    }
+}
+
+fn convert_value(value: SnapshotValue, ty: ValType) -> Option<Value> {
+    Some(match (ty, value) {
+        (ValType::I32, SnapshotValue::F64(v)) => {
+            // Hosts may read i32 globals as signed or unsigned.
+            if v.fract() != 0.0 || !(i32::MIN as f64..=u32::MAX as f64).contains(&v) {
+                return None;
+            }
+            Value::I32(v as i64 as i32)
+        }
+        (ValType::I64, SnapshotValue::I64(v)) => Value::I64(v),
+        (ValType::F32, SnapshotValue::F64(v)) => {
+            // Hosts read f32 globals as f64s, so any other value didn't come from the global.
+            if !v.is_nan() && v as f32 as f64 != v {
+                return None;
+            }
+            Value::F32(v as f32)
+        }
+        (ValType::F64, SnapshotValue::F64(v)) => Value::F64(v),
+        _ => return None,
+    })
+}
@@ -0,0 +1,382 @@
+//! Runs the transform and related tools natively, so build pipelines can prepare guests ahead
+//! of time instead of every client transforming them in the browser.
+
+use std::collections::HashMap;
+use std::process::ExitCode;
+
+use wasm_guardian::{
+    DispatchOptions, InstrumentationFilter, Provenance, TransformError, TransformOptions,
+};
+
+const USAGE: &str = "\
+Usage:
+  wasm-guardian transform <input.wasm> -o <output.wasm> [options]
+      [--manifest <manifest.json>] [--source-map <input.wasm.map>]
+      [--statistics <statistics.json>]
+  wasm-guardian inspect <input.wasm>
+  wasm-guardian validate <input.wasm> [options]
+  wasm-guardian hash <input.wasm>
+
+Options:
+  --export-globals          Export every mutable global so the host can snapshot it
+  --track-changes           Report stores, memory grows and global sets to the host
+  --track-reads             Report loads and memory.copy sources to the host
+  --watchpoints             Export wg_watch and report writes to watched ranges to the host
+  --invocation-context      Export wg_caller_id and wg_event_time
+  --deterministic-random    Replace imported randomness with a seeded generator
+  --wasi                    Implement WASI preview1 imports inside the module
+  --record-import <pattern> Record and replay an import's results, as module.name (repeatable)
+  --dispatch <pattern>      Generate wg_dispatch, allowing matching exports (repeatable)
+  --instrument <pattern>    Only instrument matching functions and what they call (repeatable)
+  --no-instrument <pattern> Don't instrument matching functions unless tracked code calls them
+                            (repeatable). Patterns match export or function names, or #index
+  --tangle                  The options Tangle uses: --export-globals --invocation-context
+                            --deterministic-random --wasi. Add --record-import for each of
+                            Tangle's recorded_imports
+
+A source map is written next to the output with a .map extension.
+`transform` prints the code size change and the most instrumented functions.
+`validate` transforms the module with the options first, unless it's already transformed.";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("transform") => transform(&args[1..]),
+        Some("inspect") => inspect(&args[1..]),
+        Some("validate") => validate(&args[1..]),
+        Some("hash") => hash(&args[1..]),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(code) => code,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Arguments that aren't transform options, by flag. Positional arguments use the key `""`.
+type OtherArgs = HashMap<&'static str, Vec<String>>;
+
+fn parse_args(
+    args: &[String],
+    value_flags: &[&'static str],
+) -> Result<(TransformOptions, OtherArgs), String> {
+    let mut options = TransformOptions::default();
+    let mut other = OtherArgs::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value\n\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--export-globals" => options.export_globals = true,
+            "--track-changes" => options.track_changes = true,
+            "--track-reads" => options.track_reads = true,
+            "--watchpoints" => options.watchpoints = true,
+            "--invocation-context" => options.invocation_context = true,
+            "--deterministic-random" => options.deterministic_random = true,
+            "--wasi" => options.wasi = true,
+            // The same as `TimeMachine.setup`, so Tangle accepts the output as already processed.
+            "--tangle" => {
+                options.export_globals = true;
+                options.invocation_context = true;
+                options.deterministic_random = true;
+                options.wasi = true;
+            }
+            "--record-import" => options.recorded_imports.push(value()?),
+            "--dispatch" => options
+                .dispatch
+                .get_or_insert_with(DispatchOptions::default)
+                .allowlist
+                .push(value()?),
+            "--instrument" => options
+                .instrumentation_filter
+                .get_or_insert_with(InstrumentationFilter::default)
+                .include
+                .push(value()?),
+            "--no-instrument" => options
+                .instrumentation_filter
+                .get_or_insert_with(InstrumentationFilter::default)
+                .exclude
+                .push(value()?),
+            flag => match value_flags.iter().find(|f| **f == flag) {
+                Some(flag) => other.entry(flag).or_default().push(value()?),
+                None if flag.starts_with('-') => {
+                    return Err(format!("Unknown option {}\n\n{}", flag, USAGE))
+                }
+                None => other.entry("").or_default().push(flag.to_string()),
+            },
+        }
+    }
+    Ok((options, other))
+}
+
+/// The single value of `flag`, or the single positional argument if `flag` is `""`.
+fn single<'a>(other: &'a OtherArgs, flag: &str) -> Result<Option<&'a str>, String> {
+    match other.get(flag).map(Vec::as_slice) {
+        None => Ok(None),
+        Some([value]) => Ok(Some(value)),
+        Some(_) if flag.is_empty() => Err(format!("Expected one input file\n\n{}", USAGE)),
+        Some(_) => Err(format!("{} can only be given once", flag)),
+    }
+}
+
+fn read_input(other: &OtherArgs) -> Result<(String, Vec<u8>), String> {
+    let path = single(other, "")?.ok_or_else(|| format!("Missing input file\n\n{}", USAGE))?;
+    let bytes = std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    Ok((path.to_string(), bytes))
+}
+
+fn write_output(path: &str, contents: &[u8]) -> Result<(), String> {
+    std::fs::write(path, contents).map_err(|e| format!("Could not write {}: {}", path, e))
+}
+
+fn transform_error(path: &str, error: TransformError) -> String {
+    match error {
+        TransformError::InvalidModule => format!("{} isn't a valid Wasm module", path),
+        TransformError::AlreadyTransformed(provenance) => format!(
+            "{} was already transformed with different options: {}",
+            path,
+            provenance.to_json()
+        ),
+        TransformError::DwarfSection(name) => format!(
+            "{} has DWARF debug info in {}, which can't be kept correct through the transform. \
+             Strip it, for example with wasm-opt --strip-dwarf, and use a source map instead",
+            path, name
+        ),
+        TransformError::UnknownProvenance => format!(
+            "{} was transformed by a version of wasm-guardian with a different provenance format",
+            path
+        ),
+        TransformError::UnsupportedImport {
+            module,
+            name,
+            reason,
+        } => format!("{} imports {}.{}, but {}", path, module, name, reason),
+    }
+}
+
+fn transform(args: &[String]) -> Result<ExitCode, String> {
+    let (options, other) = parse_args(args, &["-o", "--manifest", "--source-map", "--statistics"])?;
+    let (input_path, input) = read_input(&other)?;
+    let output_path = single(&other, "-o")?.ok_or_else(|| format!("Missing -o\n\n{}", USAGE))?;
+
+    let output = wasm_guardian::transform_wasm(&input, &options)
+        .map_err(|e| transform_error(&input_path, e))?;
+    write_output(output_path, &output.wasm)?;
+
+    if let Some(manifest_path) = single(&other, "--manifest")? {
+        write_output(manifest_path, output.manifest.to_json().as_bytes())?;
+    }
+    if let Some(source_map_path) = single(&other, "--source-map")? {
+        let source_map = std::fs::read_to_string(source_map_path)
+            .map_err(|e| format!("Could not read {}: {}", source_map_path, e))?;
+        let source_map = match &output.code_offsets {
+            Some(code_offsets) => wasm_guardian::rewrite_source_map(&source_map, code_offsets)
+                .ok_or_else(|| format!("{} isn't a valid source map", source_map_path))?,
+            // The module was already transformed, so the source map already matches it.
+            None => source_map,
+        };
+        write_output(&format!("{}.map", output_path), source_map.as_bytes())?;
+    }
+    match &output.statistics {
+        Some(statistics) => {
+            if let Some(statistics_path) = single(&other, "--statistics")? {
+                write_output(statistics_path, statistics.to_json().as_bytes())?;
+            }
+            print_statistics(statistics);
+        }
+        None => println!("{} is already transformed, written unchanged", input_path),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+const TOP_FUNCTIONS: usize = 10;
+
+fn print_statistics(statistics: &wasm_guardian::InstrumentationStatistics) {
+    println!(
+        "code size: {} -> {} bytes",
+        statistics.code_size_before, statistics.code_size_after
+    );
+    for (name, count) in statistics.totals() {
+        println!("  {:<12} {}", name, count);
+    }
+    if statistics.functions.is_empty() {
+        return;
+    }
+    println!("most instrumented functions:");
+    for function in statistics.functions.iter().take(TOP_FUNCTIONS) {
+        let index = function
+            .index
+            .map_or_else(|| "generated".to_string(), |index| index.to_string());
+        println!(
+            "  {:>6} {:>9} {}",
+            function.total(),
+            index,
+            function.name.as_deref().unwrap_or("")
+        );
+    }
+}
+
+fn inspect(args: &[String]) -> Result<ExitCode, String> {
+    let (_, other) = parse_args(args, &[])?;
+    let (input_path, input) = read_input(&other)?;
+    let module = walrus::Module::from_buffer(&input)
+        .map_err(|_| transform_error(&input_path, TransformError::InvalidModule))?;
+
+    println!("exports:");
+    for (i, export) in module.exports.iter().enumerate() {
+        let description = match export.item {
+            walrus::ExportItem::Function(function) => {
+                let ty = module.types.get(module.funcs.get(function).ty());
+                format!(
+                    "function ({}) -> ({})",
+                    type_list(ty.params()),
+                    type_list(ty.results())
+                )
+            }
+            walrus::ExportItem::Global(global) => {
+                format!("global {}", module.globals.get(global).ty)
+            }
+            walrus::ExportItem::Memory(_) => "memory".to_string(),
+            walrus::ExportItem::Table(_) => "table".to_string(),
+        };
+        println!("  {} {} {}", i, export.name, description);
+    }
+
+    println!("globals:");
+    for (i, global) in module.globals.iter().enumerate() {
+        let kind = match &global.kind {
+            walrus::GlobalKind::Import(import) => {
+                let import = module.imports.get(*import);
+                format!("imported from {}.{}", import.module, import.name)
+            }
+            walrus::GlobalKind::Local(_) => "local".to_string(),
+        };
+        let mutability = if global.mutable { "mut " } else { "" };
+        println!("  {} {}{} {}", i, mutability, global.ty, kind);
+    }
+
+    println!("memories:");
+    for (i, memory) in module.memories.iter().enumerate() {
+        let maximum = memory
+            .maximum
+            .map_or("none".to_string(), |maximum| maximum.to_string());
+        let imported = if memory.import.is_some() {
+            " imported"
+        } else {
+            ""
+        };
+        let shared = if memory.shared { " shared" } else { "" };
+        println!(
+            "  {} initial {} maximum {}{}{}",
+            i, memory.initial, maximum, shared, imported
+        );
+    }
+
+    println!("imports:");
+    for import in module.imports.iter() {
+        let kind = match import.kind {
+            walrus::ImportKind::Function(_) => "function",
+            walrus::ImportKind::Global(_) => "global",
+            walrus::ImportKind::Memory(_) => "memory",
+            walrus::ImportKind::Table(_) => "table",
+        };
+        println!("  {}.{} {}", import.module, import.name, kind);
+    }
+
+    println!("instrumentation:");
+    match Provenance::read(&input) {
+        Some(provenance) => {
+            println!("  provenance {}", provenance.to_json());
+            for (hook, calls) in hook_calls(&module) {
+                println!("  {} calls {}", hook, calls);
+            }
+        }
+        None => println!("  not transformed"),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// How many times each `wasm_guardian.on_*` hook is called from the module's code.
+fn hook_calls(module: &walrus::Module) -> Vec<(String, usize)> {
+    struct CountCalls<'a> {
+        counts: &'a mut HashMap<walrus::FunctionId, usize>,
+    }
+    impl<'instr> walrus::ir::Visitor<'instr> for CountCalls<'_> {
+        fn visit_call(&mut self, instr: &walrus::ir::Call) {
+            if let Some(count) = self.counts.get_mut(&instr.func) {
+                *count += 1;
+            }
+        }
+    }
+
+    let hooks: Vec<(String, walrus::FunctionId)> = module
+        .imports
+        .iter()
+        .filter_map(|import| match import.kind {
+            walrus::ImportKind::Function(function)
+                if import.module == "wasm_guardian" && import.name.starts_with("on_") =>
+            {
+                Some((import.name.clone(), function))
+            }
+            _ => None,
+        })
+        .collect();
+    let mut counts: HashMap<walrus::FunctionId, usize> =
+        hooks.iter().map(|(_, function)| (*function, 0)).collect();
+    for (_, function) in module.funcs.iter_local() {
+        let mut visitor = CountCalls {
+            counts: &mut counts,
+        };
+        walrus::ir::dfs_in_order(&mut visitor, function, function.entry_block());
+    }
+    hooks
+        .into_iter()
+        .map(|(name, function)| (name, counts[&function]))
+        .collect()
+}
+
+fn type_list(types: &[walrus::ValType]) -> String {
+    types
+        .iter()
+        .map(|ty| ty.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn validate(args: &[String]) -> Result<ExitCode, String> {
+    let (options, other) = parse_args(args, &[])?;
+    let (input_path, input) = read_input(&other)?;
+
+    let transformed = if Provenance::read(&input).is_some() {
+        input
+    } else {
+        wasm_guardian::transform_wasm(&input, &options)
+            .map_err(|e| transform_error(&input_path, e))?
+            .wasm
+    };
+    let issues = wasm_guardian::check_determinism(&transformed)
+        .map_err(|e| transform_error(&input_path, e))?;
+    for issue in &issues {
+        println!("{}", issue);
+    }
+    if issues.is_empty() {
+        println!("{} has no known sources of nondeterminism", input_path);
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+fn hash(args: &[String]) -> Result<ExitCode, String> {
+    let (_, other) = parse_args(args, &[])?;
+    let (_, input) = read_input(&other)?;
+    let hash = wasm_guardian::semantic_hash(&input);
+    println!("{:032x} {}", hash, wasm_guardian::room_id(hash));
+    Ok(ExitCode::SUCCESS)
+}
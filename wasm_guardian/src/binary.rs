@@ -0,0 +1,146 @@
+//! Minimal reading and writing of the raw WebAssembly binary format, for information walrus
+//! doesn't keep.
+
+use std::collections::HashMap;
+
+pub const CUSTOM_SECTION_ID: u8 = 0;
+
+/// The 8 byte magic number and version that start every WebAssembly module.
+pub const HEADER: [u8; 8] = [0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00];
+
+pub struct Reader<'a> {
+    pub data: &'a [u8],
+    pub offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offset >= self.data.len()
+    }
+
+    pub fn read_u8(&mut self) -> Option<u8> {
+        let v = *self.data.get(self.offset)?;
+        self.offset += 1;
+        Some(v)
+    }
+
+    pub fn read_var_u32(&mut self) -> Option<u32> {
+        let mut result = 0u32;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift == 28 && byte > 0x0F {
+                return None;
+            }
+            result |= ((byte & 0x7F) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+            shift += 7;
+        }
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let end = self.offset.checked_add(length)?;
+        let result = self.data.get(self.offset..end)?;
+        self.offset = end;
+        Some(result)
+    }
+
+    pub fn read_name(&mut self) -> Option<&'a str> {
+        let length = self.read_var_u32()?;
+        std::str::from_utf8(self.read_bytes(length as usize)?).ok()
+    }
+}
+
+pub fn write_var_u32(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn write_name(out: &mut Vec<u8>, name: &str) {
+    write_var_u32(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+pub struct Section<'a> {
+    pub id: u8,
+    /// The section's contents, not including its id and size.
+    pub data: &'a [u8],
+}
+
+impl<'a> Section<'a> {
+    /// For custom sections returns the name and the contents following it.
+    pub fn custom(&self) -> Option<(&'a str, &'a [u8])> {
+        if self.id != CUSTOM_SECTION_ID {
+            return None;
+        }
+        let mut reader = Reader::new(self.data);
+        let name = reader.read_name()?;
+        Some((name, &self.data[reader.offset..]))
+    }
+}
+
+/// Iterates the sections of a module. Iteration stops early if the module is malformed.
+pub fn sections(bytes: &[u8]) -> impl Iterator<Item = Section<'_>> {
+    let mut reader = Reader::new(bytes);
+    let valid_header = reader.read_bytes(HEADER.len()) == Some(&HEADER[..]);
+    std::iter::from_fn(move || {
+        if !valid_header || reader.is_empty() {
+            return None;
+        }
+        let id = reader.read_u8()?;
+        let size = reader.read_var_u32()?;
+        let data = reader.read_bytes(size as usize)?;
+        Some(Section { id, data })
+    })
+}
+
+/// Returns the global names from the name section's global subsection, keyed by global index.
+///
+/// walrus doesn't parse this subsection so it's lost when a module is re-emitted.
+pub fn global_names(bytes: &[u8]) -> HashMap<u32, String> {
+    const GLOBAL_NAMES_SUBSECTION: u8 = 7;
+
+    let mut names = HashMap::new();
+    for section in sections(bytes) {
+        let Some(("name", data)) = section.custom() else {
+            continue;
+        };
+        let mut reader = Reader::new(data);
+        while !reader.is_empty() {
+            let (Some(subsection_id), Some(size)) = (reader.read_u8(), reader.read_var_u32())
+            else {
+                break;
+            };
+            let Some(subsection) = reader.read_bytes(size as usize) else {
+                break;
+            };
+            if subsection_id != GLOBAL_NAMES_SUBSECTION {
+                continue;
+            }
+            let mut reader = Reader::new(subsection);
+            let count = reader.read_var_u32().unwrap_or(0);
+            for _ in 0..count {
+                match (reader.read_var_u32(), reader.read_name()) {
+                    (Some(index), Some(name)) => {
+                        names.insert(index, name.to_string());
+                    }
+                    _ => break,
+                }
+            }
+        }
+    }
+    names
+}
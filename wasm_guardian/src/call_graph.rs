@@ -0,0 +1,193 @@
+//! Which functions each function may call, for working out what code an export can run and
+//! what it can change.
+
+use std::collections::{HashMap, HashSet};
+
+use walrus::ir::{Instr, LoadSimdKind};
+use walrus::FunctionId;
+
+/// What calling a function can do besides returning a result, found statically from every
+/// function it can reach. A function with none of these is pure.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SideEffects {
+    /// Stores to, grows, or otherwise writes memory.
+    pub writes_memory: bool,
+    /// Sets a global, or changes a table.
+    pub writes_globals: bool,
+    /// Calls an imported function. `wasm_guardian` hooks, like `on_store`, aren't counted.
+    pub calls_imports: bool,
+}
+
+impl SideEffects {
+    pub fn is_pure(&self) -> bool {
+        *self == SideEffects::default()
+    }
+
+    fn add(&mut self, other: SideEffects) {
+        self.writes_memory |= other.writes_memory;
+        self.writes_globals |= other.writes_globals;
+        self.calls_imports |= other.calls_imports;
+    }
+}
+
+pub(crate) struct CallGraph {
+    /// Functions each local function calls directly or takes a reference to with `ref.func`.
+    callees: HashMap<FunctionId, HashSet<FunctionId>>,
+    /// Local functions that use `call_indirect`.
+    calls_indirect: HashSet<FunctionId>,
+    /// Functions that may be called indirectly: every function in an element segment or
+    /// referenced with `ref.func`.
+    indirect_targets: HashSet<FunctionId>,
+    /// Side effects of each function's own instructions, not counting what it calls.
+    direct_effects: HashMap<FunctionId, SideEffects>,
+}
+
+impl CallGraph {
+    pub(crate) fn new(module: &walrus::Module) -> Self {
+        let mut graph = CallGraph {
+            callees: HashMap::new(),
+            calls_indirect: HashSet::new(),
+            indirect_targets: module
+                .elements
+                .iter()
+                .flat_map(|element| element.members.iter().flatten().copied())
+                .collect(),
+            direct_effects: HashMap::new(),
+        };
+        for function in module.funcs.iter() {
+            if let walrus::FunctionKind::Import(imported) = &function.kind {
+                let calls_imports = module.imports.get(imported.import).module != "wasm_guardian";
+                let effects = SideEffects {
+                    calls_imports,
+                    ..Default::default()
+                };
+                graph.direct_effects.insert(function.id(), effects);
+            }
+        }
+        for (id, function) in module.funcs.iter_local() {
+            let mut visitor = Calls::default();
+            walrus::ir::dfs_in_order(&mut visitor, function, function.entry_block());
+            if visitor.calls_indirect {
+                graph.calls_indirect.insert(id);
+            }
+            graph.indirect_targets.extend(&visitor.references);
+            visitor.callees.extend(visitor.references);
+            graph.callees.insert(id, visitor.callees);
+            graph.direct_effects.insert(id, visitor.effects);
+        }
+        graph
+    }
+
+    /// Stops counting calls to an imported function as calling imports.
+    pub(crate) fn ignore_import(&mut self, function: FunctionId) {
+        self.direct_effects.remove(&function);
+    }
+
+    /// The side effects of calling `function`.
+    pub(crate) fn side_effects(&self, function: FunctionId) -> SideEffects {
+        let mut effects = SideEffects::default();
+        for reachable in self.reachable([function]) {
+            if let Some(direct) = self.direct_effects.get(&reachable) {
+                effects.add(*direct);
+            }
+        }
+        effects
+    }
+
+    /// Every function reachable from `roots`, including the roots.
+    pub(crate) fn reachable(
+        &self,
+        roots: impl IntoIterator<Item = FunctionId>,
+    ) -> HashSet<FunctionId> {
+        let mut reachable = HashSet::new();
+        let mut stack: Vec<FunctionId> = roots.into_iter().collect();
+        while let Some(function) = stack.pop() {
+            if !reachable.insert(function) {
+                continue;
+            }
+            if let Some(callees) = self.callees.get(&function) {
+                stack.extend(callees.iter().filter(|f| !reachable.contains(f)));
+            }
+            if self.calls_indirect.contains(&function) {
+                stack.extend(
+                    self.indirect_targets
+                        .iter()
+                        .filter(|f| !reachable.contains(f)),
+                );
+            }
+        }
+        reachable
+    }
+}
+
+/// Functions that can be called from outside the module: exports, the start function and,
+/// if a table is exported or imported, every function that may be put in a table.
+pub(crate) fn entry_points(module: &walrus::Module, graph: &CallGraph) -> HashSet<FunctionId> {
+    let mut entry_points: HashSet<FunctionId> = module
+        .exports
+        .iter()
+        .filter_map(|export| match export.item {
+            walrus::ExportItem::Function(function) => Some(function),
+            _ => None,
+        })
+        .chain(module.start)
+        .collect();
+    let shared_table = module.tables.iter().any(|table| table.import.is_some())
+        || module
+            .exports
+            .iter()
+            .any(|export| matches!(export.item, walrus::ExportItem::Table(_)));
+    if shared_table {
+        entry_points.extend(&graph.indirect_targets);
+    }
+    entry_points
+}
+
+#[derive(Default)]
+struct Calls {
+    callees: HashSet<FunctionId>,
+    references: HashSet<FunctionId>,
+    calls_indirect: bool,
+    effects: SideEffects,
+}
+
+impl<'instr> walrus::ir::Visitor<'instr> for Calls {
+    fn visit_instr(&mut self, instr: &'instr Instr, _: &'instr walrus::InstrLocId) {
+        match instr {
+            Instr::Call(call) => {
+                self.callees.insert(call.func);
+            }
+            Instr::CallIndirect(_) => self.calls_indirect = true,
+            Instr::RefFunc(ref_func) => {
+                self.references.insert(ref_func.func);
+            }
+            Instr::Store(_)
+            | Instr::AtomicRmw(_)
+            | Instr::Cmpxchg(_)
+            | Instr::MemoryGrow(_)
+            | Instr::MemoryInit(_)
+            | Instr::MemoryCopy(_)
+            | Instr::MemoryFill(_)
+            | Instr::DataDrop(_) => self.effects.writes_memory = true,
+            Instr::LoadSimd(load) => {
+                if matches!(
+                    load.kind,
+                    LoadSimdKind::V128Store8Lane(_)
+                        | LoadSimdKind::V128Store16Lane(_)
+                        | LoadSimdKind::V128Store32Lane(_)
+                        | LoadSimdKind::V128Store64Lane(_)
+                ) {
+                    self.effects.writes_memory = true;
+                }
+            }
+            Instr::GlobalSet(_)
+            | Instr::TableSet(_)
+            | Instr::TableGrow(_)
+            | Instr::TableFill(_)
+            | Instr::TableInit(_)
+            | Instr::TableCopy(_)
+            | Instr::ElemDrop(_) => self.effects.writes_globals = true,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,537 @@
+//! Merges the `on_store` notifications of stores to fields of the same struct, and the
+//! `on_load` notifications of loads from them.
+//!
+//! Compiled code often accesses several fields through one base pointer, like
+//! `base+0`, `base+4`, `base+8`, with nothing in between that could change the pointer.
+//! Instead of a call per access, the first one reports the range covering all of them.
+//!
+//! Only straight-line code is merged: a run of accesses ends at calls, control flow and
+//! anything else whose effect on the stack isn't modelled here.
+//!
+//! Loops are merged across iterations when they step a pointer local by a constant and
+//! continue with `br_if` while it's below a bound that doesn't change in the loop, like
+//! `p += 8; br_if $loop (p < end)`. The accesses through that pointer are reported by one
+//! range before the loop, computed from the pointer's first value and the last value below
+//! the bound. Only loops without nested control flow are merged, so nothing else can continue
+//! them.
+
+use walrus::ir::{BinaryOp, Instr, InstrLocId, InstrSeqId, LoadSimdKind, UnaryOp, Value};
+use walrus::{FunctionId, LocalFunction, LocalId, MemoryId};
+
+/// Which instructions to plan notifications for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Access {
+    /// `store` instructions.
+    Stores,
+    /// `load` instructions and SIMD loads.
+    Loads,
+}
+
+/// Runs of accesses spanning more than this many bytes are split, so unrelated stores through
+/// the same pointer don't report large ranges they never write.
+const MAX_RANGE: u32 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Notification {
+    /// Report the access on its own with its address.
+    Separate,
+    /// Before the access, report `size` bytes starting at `offset` past the value of `base`.
+    /// This covers this access and the following [Notification::Covered] accesses.
+    Range {
+        base: LocalId,
+        offset: u32,
+        size: u32,
+    },
+    /// An earlier [Notification::Range], or the notification before the loop it's in,
+    /// already reported this access.
+    Covered,
+}
+
+/// What a loop compares its pointer with to decide whether to continue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Bound {
+    Local(LocalId),
+    Const(i32),
+}
+
+/// The accesses in a loop through a pointer local that grows by `step` each iteration.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LoopRange {
+    pub memory: MemoryId,
+    pub base: LocalId,
+    pub step: u32,
+    /// The loop continues while `base` is below this after stepping.
+    pub bound: Bound,
+    /// The bytes accessed in an iteration, relative to `base` at its start.
+    pub start: u32,
+    pub end: u32,
+}
+
+/// The notifications for a loop's accesses. Those through [LoopRange::base] are
+/// [Notification::Covered] by a notification of the range before the loop.
+pub(crate) struct LoopPlan {
+    pub range: LoopRange,
+    pub notifications: Vec<Notification>,
+}
+
+/// For an instruction `access` is planning for, the memory it accesses, its offset and width,
+/// and how many values are above the address on the stack.
+pub(crate) fn accessed(instr: &Instr, access: Access) -> Option<(MemoryId, u32, u32, usize)> {
+    match (instr, access) {
+        (Instr::Store(store), Access::Stores) => {
+            Some((store.memory, store.arg.offset, store.kind.width(), 1))
+        }
+        (Instr::Load(load), Access::Loads) => {
+            Some((load.memory, load.arg.offset, load.kind.width(), 0))
+        }
+        (Instr::LoadSimd(load), Access::Loads) => {
+            let (width, depth) = simd_load_width(load.kind)?;
+            Some((load.memory, load.arg.offset, width, depth))
+        }
+        _ => None,
+    }
+}
+
+/// The number of bytes a SIMD load reads and how many values are above its address, or `None`
+/// for lane stores.
+pub(crate) fn simd_load_width(kind: LoadSimdKind) -> Option<(u32, usize)> {
+    Some(match kind {
+        LoadSimdKind::Splat8 => (1, 0),
+        LoadSimdKind::Splat16 => (2, 0),
+        LoadSimdKind::Splat32 | LoadSimdKind::V128Load32Zero => (4, 0),
+        LoadSimdKind::Splat64
+        | LoadSimdKind::V128Load8x8S
+        | LoadSimdKind::V128Load8x8U
+        | LoadSimdKind::V128Load16x4S
+        | LoadSimdKind::V128Load16x4U
+        | LoadSimdKind::V128Load32x2S
+        | LoadSimdKind::V128Load32x2U
+        | LoadSimdKind::V128Load64Zero => (8, 0),
+        LoadSimdKind::V128Load8Lane(_) => (1, 1),
+        LoadSimdKind::V128Load16Lane(_) => (2, 1),
+        LoadSimdKind::V128Load32Lane(_) => (4, 1),
+        LoadSimdKind::V128Load64Lane(_) => (8, 1),
+        LoadSimdKind::V128Store8Lane(_)
+        | LoadSimdKind::V128Store16Lane(_)
+        | LoadSimdKind::V128Store32Lane(_)
+        | LoadSimdKind::V128Store64Lane(_) => return None,
+    })
+}
+
+/// Appends a call to `hook` with the address `offset` past the value of `base` and `size`.
+/// It leaves the stack as it was.
+pub(crate) fn push_range_notification(
+    instructions: &mut Vec<(Instr, InstrLocId)>,
+    hook: FunctionId,
+    (base, offset, size): (LocalId, u32, u32),
+    loc: InstrLocId,
+) {
+    instructions.push((Instr::LocalGet(walrus::ir::LocalGet { local: base }), loc));
+    if offset != 0 {
+        instructions.extend_from_slice(&[
+            (
+                Instr::Const(walrus::ir::Const {
+                    value: walrus::ir::Value::I32(offset as _),
+                }),
+                loc,
+            ),
+            (
+                Instr::Binop(walrus::ir::Binop {
+                    op: walrus::ir::BinaryOp::I32Add,
+                }),
+                loc,
+            ),
+        ]);
+    }
+    instructions.extend_from_slice(&[
+        (
+            Instr::Const(walrus::ir::Const {
+                value: walrus::ir::Value::I32(size as _),
+            }),
+            loc,
+        ),
+        (Instr::Call(walrus::ir::Call { func: hook }), loc),
+    ]);
+}
+
+struct Run {
+    base: LocalId,
+    memory: MemoryId,
+    /// Where the first access's notification is in the plan.
+    leader: usize,
+    start: u32,
+    end: u32,
+}
+
+impl Run {
+    fn finish(self, plan: &mut [Notification]) {
+        plan[self.leader] = Notification::Range {
+            base: self.base,
+            offset: self.start,
+            size: self.end - self.start,
+        };
+    }
+}
+
+/// Plans the notification for each access in a block, in the order they appear.
+///
+/// Addresses are tracked with a model of the stack that records which values came straight
+/// from a `local.get`. An access's address is only known if it did and the local hasn't been
+/// set since.
+pub(crate) fn plan_block(instrs: &[(Instr, InstrLocId)], access: Access) -> Vec<Notification> {
+    plan(instrs, access, None)
+}
+
+/// Plans the notifications for the body of the loop `id` if it has accesses through a pointer
+/// that can be reported before the loop runs. See the module documentation.
+pub(crate) fn plan_loop(
+    instrs: &[(Instr, InstrLocId)],
+    id: InstrSeqId,
+    access: Access,
+) -> Option<LoopPlan> {
+    let (base, step, bound) = induction_variable(instrs, id)?;
+    let mut induction = Induction {
+        base,
+        step,
+        stepped: false,
+        range: None,
+    };
+    let notifications = plan(instrs, access, Some(&mut induction));
+    let (memory, start, end) = induction.range?;
+    Some(LoopPlan {
+        range: LoopRange {
+            memory,
+            base,
+            step,
+            bound,
+            start,
+            end,
+        },
+        notifications,
+    })
+}
+
+/// A loop's pointer while its body is planned.
+struct Induction {
+    base: LocalId,
+    step: u32,
+    /// Whether the pointer has been stepped in this iteration.
+    stepped: bool,
+    /// The memory accessed through the pointer so far, and the bytes accessed relative to its
+    /// value at the start of the iteration.
+    range: Option<(MemoryId, u32, u32)>,
+}
+
+/// Finds the pointer, its step and its bound if the loop `id` ends with
+/// `br_if id (i32.lt_u base bound)`, steps `base` once with `base + step`, doesn't change
+/// `bound`, and has no nested control flow.
+fn induction_variable(
+    instrs: &[(Instr, InstrLocId)],
+    id: InstrSeqId,
+) -> Option<(LocalId, u32, Bound)> {
+    let [body @ .., (compared, _), (bound, _), (Instr::Binop(compare), _), (Instr::BrIf(br_if), _)] =
+        instrs
+    else {
+        return None;
+    };
+    if !matches!(compare.op, BinaryOp::I32LtU) || br_if.block != id {
+        return None;
+    }
+    let base = match compared {
+        Instr::LocalGet(walrus::ir::LocalGet { local })
+        | Instr::LocalTee(walrus::ir::LocalTee { local }) => *local,
+        _ => return None,
+    };
+    let bound = match bound {
+        Instr::LocalGet(get) if get.local != base => Bound::Local(get.local),
+        Instr::Const(walrus::ir::Const {
+            value: Value::I32(value),
+        }) => Bound::Const(*value),
+        _ => return None,
+    };
+
+    let mut step = None;
+    // Includes the compared value, which may be the `local.tee` that steps `base`.
+    let checked = &instrs[..body.len() + 1];
+    for (i, (instr, _)) in checked.iter().enumerate() {
+        match instr {
+            Instr::LocalSet(walrus::ir::LocalSet { local })
+            | Instr::LocalTee(walrus::ir::LocalTee { local }) => {
+                if *local == base {
+                    if step.is_some() {
+                        return None;
+                    }
+                    step = Some(step_by(&checked[..i], base)?);
+                } else if bound == Bound::Local(*local) {
+                    return None;
+                }
+            }
+            Instr::Block(_)
+            | Instr::Loop(_)
+            | Instr::IfElse(_)
+            | Instr::Br(_)
+            | Instr::BrIf(_)
+            | Instr::BrTable(_) => return None,
+            _ => {}
+        }
+    }
+    Some((base, step?, bound))
+}
+
+/// The step if `instrs` ends by computing `base + step` with a positive constant step.
+fn step_by(instrs: &[(Instr, InstrLocId)], base: LocalId) -> Option<u32> {
+    let [.., (a, _), (b, _), (Instr::Binop(add), _)] = instrs else {
+        return None;
+    };
+    if !matches!(add.op, BinaryOp::I32Add) {
+        return None;
+    }
+    let step = match (a, b) {
+        (Instr::LocalGet(get), Instr::Const(constant))
+        | (Instr::Const(constant), Instr::LocalGet(get))
+            if get.local == base =>
+        {
+            constant.value
+        }
+        _ => return None,
+    };
+    match step {
+        Value::I32(step) if step > 0 => Some(step as u32),
+        _ => None,
+    }
+}
+
+/// Adds a block that reports the bytes a loop accesses in `range` with `hook`, to run right
+/// before the loop. `temp` is an i64 local.
+pub(crate) fn add_loop_notification(
+    function: &mut LocalFunction,
+    hook: FunctionId,
+    range: &LoopRange,
+    temp: LocalId,
+) -> InstrSeqId {
+    let mut block = function.builder_mut().dangling_instr_seq(None);
+    // The pointer's largest value in the loop: its last value below the bound, or its first
+    // value if that isn't below the bound, because the body runs once before the check.
+    push_bound(&mut block, range.bound)
+        .i32_const(1)
+        .binop(BinaryOp::I32Sub);
+    push_bound(&mut block, range.bound)
+        .i32_const(1)
+        .binop(BinaryOp::I32Sub)
+        .local_get(range.base)
+        .binop(BinaryOp::I32Sub)
+        .i32_const(range.step as i32)
+        .binop(BinaryOp::I32RemU)
+        .binop(BinaryOp::I32Sub)
+        .local_get(range.base)
+        .local_get(range.base);
+    push_bound(&mut block, range.bound)
+        .binop(BinaryOp::I32LtU)
+        .select(None)
+        .unop(UnaryOp::I64ExtendUI32)
+        .local_tee(temp)
+        .i64_const(range.step.max(range.end) as i64)
+        .binop(BinaryOp::I64Add)
+        .i64_const(1 << 32)
+        .binop(BinaryOp::I64GeU)
+        .if_else(
+            None,
+            |all| {
+                // Stepping could wrap around, or the range reaches past 32-bit memory, so
+                // report the whole memory as it is before the loop.
+                all.memory_size(range.memory)
+                    .unop(UnaryOp::I64ExtendUI32)
+                    .i64_const(16)
+                    .binop(BinaryOp::I64Shl)
+                    .local_tee(temp)
+                    .i64_const(1 << 32)
+                    .binop(BinaryOp::I64LtU)
+                    .if_else(
+                        None,
+                        |fits| {
+                            fits.i32_const(0)
+                                .local_get(temp)
+                                .unop(UnaryOp::I32WrapI64)
+                                .call(hook);
+                        },
+                        |full| {
+                            // A 4 GiB memory's size doesn't fit in an i32, so it's reported in
+                            // two halves.
+                            full.i32_const(0)
+                                .i32_const(i32::MIN)
+                                .call(hook)
+                                .i32_const(i32::MIN)
+                                .i32_const(i32::MIN)
+                                .call(hook);
+                        },
+                    );
+            },
+            |range_only| {
+                // From the first value's start to the largest value's end, which is
+                // larger and below 2^32.
+                range_only
+                    .local_get(range.base)
+                    .i32_const(range.start as i32)
+                    .binop(BinaryOp::I32Add)
+                    .local_get(temp)
+                    .i64_const(range.end as i64)
+                    .binop(BinaryOp::I64Add)
+                    .unop(UnaryOp::I32WrapI64)
+                    .local_get(range.base)
+                    .i32_const(range.start as i32)
+                    .binop(BinaryOp::I32Add)
+                    .binop(BinaryOp::I32Sub)
+                    .call(hook);
+            },
+        );
+    block.id()
+}
+
+fn push_bound<'a, 'b>(
+    block: &'a mut walrus::InstrSeqBuilder<'b>,
+    bound: Bound,
+) -> &'a mut walrus::InstrSeqBuilder<'b> {
+    match bound {
+        Bound::Local(local) => block.local_get(local),
+        Bound::Const(value) => block.i32_const(value),
+    }
+}
+
+/// Plans a block's notifications. With `induction`, accesses through the loop's pointer are
+/// covered and their range is recorded instead.
+fn plan(
+    instrs: &[(Instr, InstrLocId)],
+    access: Access,
+    mut induction: Option<&mut Induction>,
+) -> Vec<Notification> {
+    let mut plan = Vec::new();
+    let mut run: Option<Run> = None;
+    // Values pushed in the block that haven't been popped yet, as the local each came from.
+    // Values popped from an empty stack are unknown.
+    let mut stack: Vec<Option<LocalId>> = Vec::new();
+
+    for (instr, _) in instrs {
+        if let Some((memory, start, width, depth)) = accessed(instr, access) {
+            let address = stack.len().checked_sub(depth + 1).and_then(|i| stack[i]);
+            pop_push(&mut stack, instr);
+
+            let (Some(base), Some(end)) = (address, start.checked_add(width)) else {
+                plan.push(Notification::Separate);
+                continue;
+            };
+            if let Some(induction) = induction.as_deref_mut().filter(|i| i.base == base) {
+                let offset = if induction.stepped { induction.step } else { 0 };
+                let range = match (start.checked_add(offset), end.checked_add(offset)) {
+                    (Some(start), Some(end)) => match induction.range {
+                        Some((m, first, last)) if m == memory => {
+                            Some((m, first.min(start), last.max(end)))
+                        }
+                        Some(_) => None,
+                        None => Some((memory, start, end)),
+                    },
+                    _ => None,
+                };
+                if range.is_some() {
+                    induction.range = range;
+                    plan.push(Notification::Covered);
+                } else {
+                    plan.push(Notification::Separate);
+                }
+                continue;
+            }
+            if let Some(run) = &mut run {
+                let merged_start = run.start.min(start);
+                let merged_end = run.end.max(end);
+                if run.base == base
+                    && run.memory == memory
+                    && merged_end - merged_start <= MAX_RANGE
+                {
+                    run.start = merged_start;
+                    run.end = merged_end;
+                    plan.push(Notification::Covered);
+                    continue;
+                }
+            }
+            if let Some(run) = run.take() {
+                run.finish(&mut plan);
+            }
+            run = Some(Run {
+                base,
+                memory,
+                leader: plan.len(),
+                start,
+                end,
+            });
+            // Replaced when the run finishes.
+            plan.push(Notification::Separate);
+            continue;
+        }
+        match instr {
+            Instr::LocalGet(get) => stack.push(Some(get.local)),
+            Instr::LocalSet(walrus::ir::LocalSet { local })
+            | Instr::LocalTee(walrus::ir::LocalTee { local }) => {
+                stack.pop();
+                // Values read from the local before it changed no longer match it.
+                for value in stack.iter_mut().filter(|value| **value == Some(*local)) {
+                    *value = None;
+                }
+                if run.as_ref().is_some_and(|run| run.base == *local) {
+                    run.take().unwrap().finish(&mut plan);
+                }
+                if let Some(induction) = induction.as_deref_mut().filter(|i| i.base == *local) {
+                    induction.stepped = true;
+                }
+                if let Instr::LocalTee(_) = instr {
+                    stack.push(Some(*local));
+                }
+            }
+            Instr::Const(_)
+            | Instr::GlobalGet(_)
+            | Instr::MemorySize(_)
+            | Instr::RefNull(_)
+            | Instr::RefFunc(_) => stack.push(None),
+            Instr::GlobalSet(_) | Instr::Drop(_) => {
+                stack.pop();
+            }
+            Instr::Unop(_)
+            | Instr::Load(_)
+            | Instr::RefIsNull(_)
+            | Instr::Binop(_)
+            | Instr::Select(_)
+            | Instr::Store(_)
+            | Instr::LoadSimd(_) => pop_push(&mut stack, instr),
+            _ => {
+                // Calls and control flow could change memory or skip the rest of the run, and
+                // their effect on the stack isn't modelled.
+                if let Some(run) = run.take() {
+                    run.finish(&mut plan);
+                }
+                stack.clear();
+            }
+        }
+    }
+    if let Some(run) = run.take() {
+        run.finish(&mut plan);
+    }
+    plan
+}
+
+/// Updates the stack model for an instruction that doesn't touch locals and pushes at most
+/// one value, which isn't from a local.
+fn pop_push(stack: &mut Vec<Option<LocalId>>, instr: &Instr) {
+    let (pops, pushes) = match instr {
+        Instr::Unop(_) | Instr::Load(_) | Instr::RefIsNull(_) => (1, 1),
+        Instr::Binop(_) => (2, 1),
+        Instr::Select(_) => (3, 1),
+        Instr::Store(_) => (2, 0),
+        Instr::LoadSimd(load) => match simd_load_width(load.kind) {
+            Some((_, depth)) => (depth + 1, 1),
+            None => (2, 0),
+        },
+        _ => unreachable!("{:?} isn't modelled", instr),
+    };
+    stack.truncate(stack.len().saturating_sub(pops));
+    if pushes == 1 {
+        stack.push(None);
+    }
+}
@@ -0,0 +1,60 @@
+//! Lets guests ask which peer caused the current call and when, without the host
+//! passing that information as arguments.
+//!
+//! The host sets the `wg_caller_id` and `wg_event_time` globals from the event's time stamp
+//! before it runs each call, including calls that are replayed during a rollback, so the values
+//! a guest reads are the same on every peer.
+//!
+//! Guests read them by importing `wasm_guardian.caller_id` and `wasm_guardian.event_time`,
+//! both of type `() -> f64`. The transform replaces those imports with reads of the globals.
+
+use walrus::{GlobalId, InitExpr, ValType};
+
+use crate::imports::{find_imported_func, replace_imported_func};
+use crate::TransformError;
+
+pub const CALLER_ID_GLOBAL: &str = "wg_caller_id";
+pub const EVENT_TIME_GLOBAL: &str = "wg_event_time";
+
+/// The globals the host sets before each call that other generated code reads.
+#[derive(Clone, Copy)]
+pub(crate) struct InvocationContext {
+    /// Milliseconds.
+    pub event_time: GlobalId,
+}
+
+pub(crate) fn add_invocation_context(
+    module: &mut walrus::Module,
+) -> Result<InvocationContext, TransformError> {
+    let mut globals = Vec::new();
+    for (global_name, import_name) in [
+        (CALLER_ID_GLOBAL, "caller_id"),
+        (EVENT_TIME_GLOBAL, "event_time"),
+    ] {
+        let global = module.globals.add_local(
+            ValType::F64,
+            true,
+            InitExpr::Value(walrus::ir::Value::F64(0.0)),
+        );
+        module.exports.add(global_name, global);
+        globals.push(global);
+
+        let Some(function) = find_imported_func(module, "wasm_guardian", import_name) else {
+            continue;
+        };
+        let ty = module.types.get(module.funcs.get(function).ty());
+        if !ty.params().is_empty() || ty.results() != [ValType::F64] {
+            return Err(TransformError::UnsupportedImport {
+                module: "wasm_guardian".to_string(),
+                name: import_name.to_string(),
+                reason: "it must have the type () -> f64".to_string(),
+            });
+        }
+        replace_imported_func(module, function, |body, _| {
+            body.global_get(global);
+        });
+    }
+    Ok(InvocationContext {
+        event_time: globals[1],
+    })
+}
@@ -0,0 +1,231 @@
+//! Keeps source locations usable after the transform moves code around.
+//!
+//! walrus tags each parsed instruction with its offset in the original binary and reports
+//! where it ended up when the module is emitted. [CodeOffsets] holds that mapping, and
+//! [rewrite_source_map] uses it to update a source map for the transformed module.
+//!
+//! walrus can't rewrite DWARF, so modules with DWARF sections are rejected rather than
+//! transformed into modules whose debug info points at the wrong code.
+
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+
+use crate::binary::{sections, write_var_u32, Reader};
+
+/// The name of the module's first DWARF section, if it has one.
+pub(crate) fn dwarf_section(bytes: &[u8]) -> Option<&str> {
+    sections(bytes)
+        .filter_map(|section| section.custom())
+        .map(|(name, _)| name)
+        .find(|name| name.starts_with(".debug"))
+}
+
+/// Maps byte offsets of instructions in the original module to their offsets in the
+/// transformed module. Offsets are from the start of the binary, as in wasm source maps.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CodeOffsets {
+    /// `(original, transformed)`, sorted and with one entry per original offset.
+    offsets: Vec<(u32, u32)>,
+}
+
+impl CodeOffsets {
+    fn from_code_transform(transform: &walrus::CodeTransform) -> Self {
+        let mut offsets: Vec<(u32, u32)> = transform
+            .iter()
+            .map(|(loc, offset)| (loc.data(), *offset as u32))
+            .collect();
+        // Injected code shares the location of the instruction it wraps, so an original
+        // offset can appear several times. The first is the start of the injected code.
+        offsets.sort_unstable();
+        offsets.dedup_by_key(|(original, _)| *original);
+        Self { offsets }
+    }
+
+    /// Encodes the offsets as pairs of LEB128 numbers, so hosts can keep them to rewrite
+    /// source maps later.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (original, transformed) in &self.offsets {
+            write_var_u32(&mut bytes, *original);
+            write_var_u32(&mut bytes, *transformed);
+        }
+        bytes
+    }
+
+    /// Decodes offsets written by [CodeOffsets::to_bytes]. Returns `None` if they're malformed
+    /// or the original offsets aren't increasing.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(bytes);
+        let mut offsets: Vec<(u32, u32)> = Vec::new();
+        while reader.offset < bytes.len() {
+            let original = reader.read_var_u32()?;
+            if offsets.last().is_some_and(|(last, _)| *last >= original) {
+                return None;
+            }
+            offsets.push((original, reader.read_var_u32()?));
+        }
+        Some(Self { offsets })
+    }
+
+    /// The transformed offset of the instruction at `original`, or of the closest instruction
+    /// before it if there's no instruction at `original`.
+    pub fn map(&self, original: u32) -> Option<u32> {
+        let i = self.offsets.partition_point(|(o, _)| *o <= original);
+        Some(self.offsets.get(i.checked_sub(1)?)?.1)
+    }
+}
+
+/// A custom section that passes its contents through unchanged and keeps the code transform
+/// walrus applies to custom sections, which walrus doesn't otherwise expose.
+#[derive(Debug)]
+pub(crate) struct RecordCodeOffsets {
+    section: walrus::RawCustomSection,
+    offsets: Arc<Mutex<CodeOffsets>>,
+}
+
+impl RecordCodeOffsets {
+    /// Returns the section to add to the module and a handle that holds the offsets once the
+    /// module is emitted. The module must be parsed with `preserve_code_transform`.
+    pub(crate) fn new(section: walrus::RawCustomSection) -> (Self, Arc<Mutex<CodeOffsets>>) {
+        let offsets = Arc::new(Mutex::new(CodeOffsets::default()));
+        let section = Self {
+            section,
+            offsets: offsets.clone(),
+        };
+        (section, offsets)
+    }
+}
+
+impl walrus::CustomSection for RecordCodeOffsets {
+    fn name(&self) -> &str {
+        &self.section.name
+    }
+
+    fn data(&self, _: &walrus::IdsToIndices) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.section.data)
+    }
+
+    fn apply_code_transform(&mut self, transform: &walrus::CodeTransform) {
+        *self.offsets.lock().unwrap() = CodeOffsets::from_code_transform(transform);
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Returns `source_map`, a source map for the original module, with its generated columns
+/// mapped to the transformed module. Mappings for code that no longer exists are removed.
+///
+/// Returns `None` if `source_map` doesn't have a valid `"mappings"` field.
+pub fn rewrite_source_map(source_map: &str, offsets: &CodeOffsets) -> Option<String> {
+    let key = source_map.find("\"mappings\"")?;
+    let start = key + source_map[key..].find(':')? + 1;
+    let start = start + source_map[start..].find('"')? + 1;
+    let end = start + source_map[start..].find('"')?;
+
+    let mut lines = decode_mappings(&source_map[start..end])?;
+    for segments in &mut lines {
+        segments.retain_mut(|segment| match offsets.map(segment[0] as u32) {
+            Some(column) => {
+                segment[0] = column as i64;
+                true
+            }
+            None => false,
+        });
+        segments.sort_by_key(|segment| segment[0]);
+    }
+
+    let mut rewritten = String::with_capacity(source_map.len());
+    rewritten.push_str(&source_map[..start]);
+    rewritten.push_str(&encode_mappings(&lines));
+    rewritten.push_str(&source_map[end..]);
+    Some(rewritten)
+}
+
+/// Decodes each line's segments into absolute values.
+fn decode_mappings(mappings: &str) -> Option<Vec<Vec<Vec<i64>>>> {
+    // The generated column restarts on each line. The other fields carry over.
+    let mut previous = [0i64; 5];
+    mappings
+        .split(';')
+        .map(|line| {
+            previous[0] = 0;
+            line.split(',')
+                .filter(|segment| !segment.is_empty())
+                .map(|segment| {
+                    let mut values = decode_vlq(segment)?;
+                    if !matches!(values.len(), 1 | 4 | 5) {
+                        return None;
+                    }
+                    for (value, previous) in values.iter_mut().zip(&mut previous) {
+                        *value += *previous;
+                        *previous = *value;
+                    }
+                    Some(values)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn encode_mappings(lines: &[Vec<Vec<i64>>]) -> String {
+    let mut previous = [0i64; 5];
+    let mut mappings = String::new();
+    for (i, segments) in lines.iter().enumerate() {
+        if i > 0 {
+            mappings.push(';');
+        }
+        previous[0] = 0;
+        for (j, segment) in segments.iter().enumerate() {
+            if j > 0 {
+                mappings.push(',');
+            }
+            for (value, previous) in segment.iter().zip(&mut previous) {
+                encode_vlq(&mut mappings, *value - *previous);
+                *previous = *value;
+            }
+        }
+    }
+    mappings
+}
+
+fn decode_vlq(segment: &str) -> Option<Vec<i64>> {
+    let mut values = Vec::new();
+    let mut value = 0i64;
+    let mut shift = 0;
+    for c in segment.bytes() {
+        let digit = BASE64.iter().position(|b| *b == c)? as i64;
+        value |= (digit & 31) << shift;
+        if digit & 32 == 0 {
+            let negative = value & 1 != 0;
+            value >>= 1;
+            values.push(if negative { -value } else { value });
+            value = 0;
+            shift = 0;
+        } else {
+            shift += 5;
+            if shift > 60 {
+                return None;
+            }
+        }
+    }
+    (shift == 0).then_some(values)
+}
+
+fn encode_vlq(out: &mut String, value: i64) {
+    let mut vlq = if value < 0 {
+        ((-value) << 1) | 1
+    } else {
+        value << 1
+    };
+    loop {
+        let mut digit = vlq & 31;
+        vlq >>= 5;
+        if vlq > 0 {
+            digit |= 32;
+        }
+        out.push(BASE64[digit as usize] as char);
+        if vlq == 0 {
+            return;
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Finds things in a module that could make peers running the same calls end up in different
+//! states, so they can be caught when building a guest instead of as a desync in a room.
+
+use std::fmt;
+
+use crate::dispatch::wildcard_match;
+use crate::provenance::Provenance;
+use crate::TransformError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeterminismIssue {
+    /// The import returns a value that the host could return differently on each peer.
+    /// Record it with `TransformOptions::recorded_imports` or replace it.
+    ImportResult { module: String, name: String },
+    /// The host can change the global's value outside of calls.
+    MutableImportedGlobal { module: String, name: String },
+    /// The memory can be written by other threads.
+    SharedMemory,
+}
+
+impl fmt::Display for DeterminismIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeterminismIssue::ImportResult { module, name } => write!(
+                f,
+                "{}.{} returns a value that may differ between peers",
+                module, name
+            ),
+            DeterminismIssue::MutableImportedGlobal { module, name } => {
+                write!(f, "{}.{} is a mutable imported global", module, name)
+            }
+            DeterminismIssue::SharedMemory => write!(f, "memory is shared between threads"),
+        }
+    }
+}
+
+/// Checks a module as it will run in a room, so it should usually be a transformed module.
+///
+/// Imports from `wasm_guardian` are provided by the host the same way on every peer, and
+/// imports recorded by the transform that produced the module are replayed.
+pub fn check_determinism(bytes: &[u8]) -> Result<Vec<DeterminismIssue>, TransformError> {
+    let module = walrus::Module::from_buffer(bytes).map_err(|_| TransformError::InvalidModule)?;
+    let recorded = Provenance::read(bytes)
+        .map(|provenance| provenance.options.recorded_imports)
+        .unwrap_or_default();
+
+    let mut issues = Vec::new();
+    for import in module.imports.iter() {
+        if import.module == "wasm_guardian" {
+            continue;
+        }
+        match import.kind {
+            walrus::ImportKind::Function(function) => {
+                let full_name = format!("{}.{}", import.module, import.name);
+                let ty = module.types.get(module.funcs.get(function).ty());
+                if !ty.results().is_empty()
+                    && !recorded
+                        .iter()
+                        .any(|pattern| wildcard_match(pattern, &full_name))
+                {
+                    issues.push(DeterminismIssue::ImportResult {
+                        module: import.module.clone(),
+                        name: import.name.clone(),
+                    });
+                }
+            }
+            walrus::ImportKind::Global(global) if module.globals.get(global).mutable => {
+                issues.push(DeterminismIssue::MutableImportedGlobal {
+                    module: import.module.clone(),
+                    name: import.name.clone(),
+                });
+            }
+            _ => {}
+        }
+    }
+    if module.memories.iter().any(|memory| memory.shared) {
+        issues.push(DeterminismIssue::SharedMemory);
+    }
+    Ok(issues)
+}
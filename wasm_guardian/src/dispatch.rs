@@ -0,0 +1,193 @@
+//! Generates `wg_dispatch`, a single export that peers' calls are routed through so that only
+//! allowed exports can be called and invalid calls are rejected the same way on every peer.
+
+use walrus::ir::{BinaryOp, UnaryOp, Value};
+use walrus::{FunctionBuilder, InitExpr, ValType};
+
+pub const DISPATCH_EXPORT_NAME: &str = "wg_dispatch";
+
+/// `wg_dispatch` called the export.
+pub const DISPATCH_OK: i32 = 0;
+/// The index is out of range, isn't a function, or isn't on the allowlist.
+pub const DISPATCH_NOT_ALLOWED: i32 = 1;
+/// `arg_count` doesn't match the export's parameter count.
+pub const DISPATCH_BAD_ARGS: i32 = 2;
+
+/// Arguments are passed in exported i64 globals named this followed by the argument's position,
+/// as their bits: f64s reinterpreted, and i32s and f32s in the low 32 bits.
+///
+/// They belong to the generated code rather than the guest, so the host never writes to the
+/// guest's memory, which is tracked and snapshotted, to make a call.
+pub const DISPATCH_ARG_GLOBAL_PREFIX: &str = "wg_dispatch_arg_";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DispatchOptions {
+    /// Export names that may be called through `wg_dispatch`. `*` matches any sequence of characters.
+    ///
+    /// Exports generated by `wasm_guardian` can never be called, and neither can exports
+    /// that take parameters other than i32, i64, f32 or f64.
+    pub allowlist: Vec<String>,
+}
+
+impl DispatchOptions {
+    pub fn is_allowed(&self, export_name: &str) -> bool {
+        !export_name.starts_with("wg_")
+            && self
+                .allowlist
+                .iter()
+                .any(|pattern| wildcard_match(pattern, export_name))
+    }
+}
+
+/// Adds `wg_dispatch(index: i32, arg_count: i32) -> i32` to the module, and a
+/// [DISPATCH_ARG_GLOBAL_PREFIX] global for each parameter of the allowed export with the most.
+///
+/// `index` is the export's position in the export section, the same index the host uses.
+/// Arguments are read from the globals and results are dropped.
+/// Returns one of the `DISPATCH_` status codes.
+pub(crate) fn add_dispatch_export(module: &mut walrus::Module, options: &DispatchOptions) {
+    // The callable export for each export index, or `None` if calls should be rejected.
+    let targets: Vec<Option<(walrus::FunctionId, Vec<ValType>, usize)>> = module
+        .exports
+        .iter()
+        .map(|export| {
+            let walrus::ExportItem::Function(function) = export.item else {
+                return None;
+            };
+            if !options.is_allowed(&export.name) {
+                return None;
+            }
+            let ty = module.types.get(module.funcs.get(function).ty());
+            let params = ty.params().to_vec();
+            let loadable = params
+                .iter()
+                .all(|p| matches!(p, ValType::I32 | ValType::I64 | ValType::F32 | ValType::F64));
+            if !loadable {
+                return None;
+            }
+            Some((function, params, ty.results().len()))
+        })
+        .collect();
+
+    let arg_globals: Vec<_> = (0..targets
+        .iter()
+        .flatten()
+        .map(|(_, params, _)| params.len())
+        .max()
+        .unwrap_or(0))
+        .map(|i| {
+            let global =
+                module
+                    .globals
+                    .add_local(ValType::I64, true, InitExpr::Value(Value::I64(0)));
+            module
+                .exports
+                .add(&format!("{}{}", DISPATCH_ARG_GLOBAL_PREFIX, i), global);
+            global
+        })
+        .collect();
+
+    let index = module.locals.add(ValType::I32);
+    let arg_count = module.locals.add(ValType::I32);
+
+    let mut builder = FunctionBuilder::new(
+        &mut module.types,
+        &[ValType::I32, ValType::I32],
+        &[ValType::I32],
+    );
+
+    // A block per export is nested inside the `reject` block. `br_table` exits the block for the
+    // requested export and lands on the code that follows it, which calls that export.
+    let reject = builder.dangling_instr_seq(None).id();
+    let cases: Vec<_> = targets
+        .iter()
+        .map(|_| builder.dangling_instr_seq(None).id())
+        .collect();
+
+    let innermost = *cases.first().unwrap_or(&reject);
+    let table: Vec<_> = targets
+        .iter()
+        .zip(&cases)
+        .map(|(target, case)| if target.is_some() { *case } else { reject })
+        .collect();
+    builder
+        .instr_seq(innermost)
+        .local_get(index)
+        .br_table(table.into_boxed_slice(), reject);
+
+    for (i, target) in targets.iter().enumerate() {
+        // The code for case `i` follows its block, inside the next outer block.
+        let outer = *cases.get(i + 1).unwrap_or(&reject);
+        let mut seq = builder.instr_seq(outer);
+        seq.instr(walrus::ir::Block { seq: cases[i] });
+
+        let Some((function, params, result_count)) = target else {
+            continue;
+        };
+
+        seq.local_get(arg_count)
+            .i32_const(params.len() as i32)
+            .binop(BinaryOp::I32Ne)
+            .if_else(
+                None,
+                |then| {
+                    then.i32_const(DISPATCH_BAD_ARGS).return_();
+                },
+                |_| {},
+            );
+
+        for (param, global) in params.iter().zip(&arg_globals) {
+            seq.global_get(*global);
+            match param {
+                ValType::I32 => {
+                    seq.unop(UnaryOp::I32WrapI64);
+                }
+                ValType::I64 => {}
+                ValType::F32 => {
+                    seq.unop(UnaryOp::I32WrapI64)
+                        .unop(UnaryOp::F32ReinterpretI32);
+                }
+                _ => {
+                    seq.unop(UnaryOp::F64ReinterpretI64);
+                }
+            }
+        }
+        seq.call(*function);
+
+        for _ in 0..*result_count {
+            seq.drop();
+        }
+        seq.i32_const(DISPATCH_OK).return_();
+    }
+
+    builder
+        .func_body()
+        .instr(walrus::ir::Block { seq: reject })
+        .i32_const(DISPATCH_NOT_ALLOWED);
+
+    builder.name(DISPATCH_EXPORT_NAME.to_string());
+    let dispatch = builder.finish(vec![index, arg_count], &mut module.funcs);
+    module.exports.add(DISPATCH_EXPORT_NAME, dispatch);
+}
+
+/// Matches `text` against `pattern`, where `*` in the pattern matches any sequence of characters.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // There were no wildcards.
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(position) => rest = &rest[position + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
@@ -0,0 +1,69 @@
+//! Chooses which functions `track_changes` instruments, so exports that are only ever called
+//! and reverted, like `draw`, don't pay for reporting their writes.
+
+use std::collections::{HashMap, HashSet};
+
+use walrus::FunctionId;
+
+use crate::call_graph::{entry_points, CallGraph};
+use crate::wildcard_match;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InstrumentationFilter {
+    /// Functions to instrument. If empty every function that isn't excluded is.
+    pub include: Vec<String>,
+    /// Functions not to instrument.
+    pub exclude: Vec<String>,
+}
+
+impl InstrumentationFilter {
+    /// Whether a function is selected by the patterns.
+    ///
+    /// A pattern matches a function if it matches one of its export names or its name-section
+    /// name, where `*` matches any sequence of characters, or if it's `#` followed by the
+    /// function's index in the original module.
+    pub fn is_selected(&self, index: Option<u32>, names: &[&str]) -> bool {
+        let matches = |pattern: &String| match pattern.strip_prefix('#') {
+            Some(pattern_index) => index.is_some_and(|index| pattern_index == index.to_string()),
+            None => names.iter().any(|name| wildcard_match(pattern, name)),
+        };
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+
+    /// The local functions to instrument.
+    ///
+    /// Entry points that are selected are instrumented along with every function they can
+    /// reach, so a function that an excluded export calls is still instrumented if a tracked
+    /// export calls it too. Functions that aren't entry points are only roots when `include`
+    /// names them. Functions no tracked code can reach are left alone.
+    pub(crate) fn instrumented_functions(
+        &self,
+        module: &walrus::Module,
+        original_indices: &HashMap<FunctionId, u32>,
+    ) -> HashSet<FunctionId> {
+        let graph = CallGraph::new(module);
+        let entry_points = entry_points(module, &graph);
+
+        let mut names: HashMap<FunctionId, Vec<&str>> = HashMap::new();
+        for export in module.exports.iter() {
+            if let walrus::ExportItem::Function(function) = export.item {
+                names.entry(function).or_default().push(&export.name);
+            }
+        }
+        for function in module.funcs.iter() {
+            if let Some(name) = &function.name {
+                names.entry(function.id()).or_default().push(name);
+            }
+        }
+
+        let roots = module.funcs.iter().map(|f| f.id()).filter(|id| {
+            (entry_points.contains(id) || !self.include.is_empty())
+                && self.is_selected(
+                    original_indices.get(id).copied(),
+                    names.get(id).map_or(&[], Vec::as_slice),
+                )
+        });
+        graph.reachable(roots)
+    }
+}
@@ -0,0 +1,93 @@
+//! Names for exported globals that stay the same between builds of a module.
+
+use std::collections::HashMap;
+
+/// Prefix of the names `export_globals` exports a module's own mutable globals under.
+pub const GLOBAL_EXPORT_PREFIX: &str = "wg_global_";
+
+/// Identifies a global by its index in the original module and its name from the original
+/// module's name section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobalName {
+    pub index: u32,
+    pub name: Option<String>,
+}
+
+impl GlobalName {
+    /// `wg_global_{index}`, or `wg_global_{index}_{name}` if the global is named.
+    pub fn export_name(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{}{}_{}", GLOBAL_EXPORT_PREFIX, self.index, name),
+            None => format!("{}{}", GLOBAL_EXPORT_PREFIX, self.index),
+        }
+    }
+
+    /// The inverse of [GlobalName::export_name].
+    pub fn parse(export_name: &str) -> Option<Self> {
+        let rest = export_name.strip_prefix(GLOBAL_EXPORT_PREFIX)?;
+        let (index, name) = match rest.split_once('_') {
+            Some((index, name)) => (index, Some(name.to_string())),
+            None => (rest, None),
+        };
+        Some(Self {
+            index: index.parse().ok()?,
+            name,
+        })
+    }
+
+    /// Whether `other`, from another build of the module, holds the same state.
+    ///
+    /// Named globals match by name, so they survive globals being added or removed before
+    /// them. Unnamed globals can only match by index.
+    pub fn matches(&self, other: &GlobalName) -> bool {
+        match &self.name {
+            Some(name) => other.name.as_ref() == Some(name),
+            None => other.name.is_none() && other.index == self.index,
+        }
+    }
+}
+
+/// Maps global export names saved from one build of a module to the export names of the same
+/// globals in another build, for loading values saved with an older build.
+///
+/// Returns `(saved name, current name)` pairs. Saved globals without a match in `current`, and
+/// names that weren't made by [GlobalName::export_name], are left out.
+pub fn map_global_exports<'a, 'b>(
+    saved: impl IntoIterator<Item = &'a str>,
+    current: impl IntoIterator<Item = &'b str>,
+) -> Vec<(&'a str, &'b str)> {
+    let current: Vec<(&str, GlobalName)> = current
+        .into_iter()
+        .filter_map(|name| Some((name, GlobalName::parse(name)?)))
+        .collect();
+    saved
+        .into_iter()
+        .filter_map(|saved_name| {
+            let saved_global = GlobalName::parse(saved_name)?;
+            current
+                .iter()
+                .find(|(_, global)| saved_global.matches(global))
+                .map(|(current_name, _)| (saved_name, *current_name))
+        })
+        .collect()
+}
+
+/// Each global's index in the emitted module: imported globals first, then local ones.
+///
+/// The transform only adds local globals, after the module's own, so the module's own globals
+/// keep the indices they had in the original module.
+pub(crate) fn global_indices(module: &walrus::Module) -> HashMap<walrus::GlobalId, u32> {
+    let imported = module
+        .globals
+        .iter()
+        .filter(|g| matches!(g.kind, walrus::GlobalKind::Import(_)));
+    let local = module
+        .globals
+        .iter()
+        .filter(|g| matches!(g.kind, walrus::GlobalKind::Local(_)));
+    imported
+        .chain(local)
+        .enumerate()
+        .map(|(i, g)| (g.id(), i as u32))
+        .collect()
+}
@@ -0,0 +1,44 @@
+//! A hash of a module that only covers the sections that affect how it runs, so that builds
+//! that differ only in names, debug info or producer metadata hash the same.
+
+use crate::binary::{sections, CUSTOM_SECTION_ID, HEADER};
+use crate::provenance::Provenance;
+
+/// Hashes every non-custom section of the module with xxh3.
+///
+/// A module made by [crate::transform_wasm] hashes the same as the module it was transformed
+/// from: its hash is the `original_hash` in its [Provenance], which stands in for hashing its
+/// sections with the instrumentation stripped. That hash isn't checked, so a module can claim
+/// another's hash, which only lets it into rooms it could join by name anyway.
+/// Hashing stops at the first malformed section.
+pub fn semantic_hash(bytes: &[u8]) -> u128 {
+    if let Some(provenance) = Provenance::read(bytes) {
+        return provenance.original_hash;
+    }
+
+    let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+    hasher.update(&HEADER);
+    for section in sections(bytes).filter(|s| s.id != CUSTOM_SECTION_ID) {
+        hasher.update(&[section.id]);
+        hasher.update(&(section.data.len() as u32).to_le_bytes());
+        hasher.update(section.data);
+    }
+    hasher.digest128()
+}
+
+/// Encodes a hash as 22 characters of unpadded URL-safe base64, for use in room names and URLs.
+pub fn room_id(hash: u128) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+    let bytes = hash.to_be_bytes();
+    let mut id = String::with_capacity(22);
+    for chunk in bytes.chunks(3) {
+        let mut group = [0u8; 3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let n = u32::from_be_bytes([0, group[0], group[1], group[2]]);
+        for i in 0..=chunk.len() {
+            id.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+        }
+    }
+    id
+}
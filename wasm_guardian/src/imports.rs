@@ -0,0 +1,55 @@
+//! Helpers for replacing a module's imports with code generated inside the module.
+
+use walrus::{FunctionBuilder, FunctionId, FunctionKind, InstrSeqBuilder, LocalId};
+
+/// Finds a function imported as `import_module.name`.
+pub(crate) fn find_imported_func(
+    module: &walrus::Module,
+    import_module: &str,
+    name: &str,
+) -> Option<FunctionId> {
+    match module
+        .imports
+        .get(module.imports.find(import_module, name)?)
+        .kind
+    {
+        walrus::ImportKind::Function(function) => Some(function),
+        _ => None,
+    }
+}
+
+/// Replaces an imported function with a local function of the same type whose body is written
+/// by `build`, which is passed the function's parameters.
+///
+/// The function keeps its id, so calls, exports and table elements that referred to the
+/// import now refer to the local function.
+pub(crate) fn replace_imported_func(
+    module: &mut walrus::Module,
+    function: FunctionId,
+    build: impl FnOnce(&mut InstrSeqBuilder, &[LocalId]),
+) {
+    let FunctionKind::Import(imported) = &module.funcs.get(function).kind else {
+        panic!("function {:?} is not imported", function);
+    };
+    let import = imported.import;
+    let ty = module.types.get(imported.ty);
+    let (params, results) = (ty.params().to_vec(), ty.results().to_vec());
+
+    let args: Vec<LocalId> = params.iter().map(|p| module.locals.add(*p)).collect();
+    let mut builder = FunctionBuilder::new(&mut module.types, &params, &results);
+    build(&mut builder.func_body(), &args);
+    if let Some(name) = &module.funcs.get(function).name {
+        builder.name(name.clone());
+    }
+    let replacement = builder.finish(args, &mut module.funcs);
+
+    // Move the new body into the imported function's slot and discard the temporary function.
+    let ty = module.funcs.get(replacement).ty();
+    let kind = std::mem::replace(
+        &mut module.funcs.get_mut(replacement).kind,
+        FunctionKind::Uninitialized(ty),
+    );
+    module.funcs.get_mut(function).kind = kind;
+    module.funcs.delete(replacement);
+    module.imports.delete(import);
+}
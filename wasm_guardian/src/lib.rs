@@ -1,38 +1,248 @@
+mod bake;
+mod binary;
+mod call_graph;
+mod coalesce;
+mod context;
+mod debug;
+mod determinism;
+mod dispatch;
+mod filter;
+mod globals;
+mod hash;
+mod imports;
+mod manifest;
+mod provenance;
+mod random;
+mod record;
+mod reload;
+mod statistics;
+mod wasi;
+mod watch;
+
+pub use bake::{
+    bake_snapshot, BakeError, Snapshot, SnapshotValue, INITIALIZER_EXPORTS, WASM_PAGE_SIZE,
+};
+pub use call_graph::SideEffects;
+use coalesce::{Access, Notification};
+pub use context::{CALLER_ID_GLOBAL, EVENT_TIME_GLOBAL};
+
+pub use debug::{rewrite_source_map, CodeOffsets};
+pub use determinism::{check_determinism, DeterminismIssue};
+pub use dispatch::{
+    wildcard_match, DispatchOptions, DISPATCH_ARG_GLOBAL_PREFIX, DISPATCH_BAD_ARGS,
+    DISPATCH_EXPORT_NAME, DISPATCH_NOT_ALLOWED, DISPATCH_OK,
+};
+pub use filter::InstrumentationFilter;
+pub use globals::{map_global_exports, GlobalName, GLOBAL_EXPORT_PREFIX};
+pub use hash::{room_id, semantic_hash};
+pub use manifest::{Export, ExportKind, Manifest};
+pub use provenance::{Provenance, PROVENANCE_SECTION_NAME};
+pub use random::{
+    RANDOM_BYTES_IMPORTS, RANDOM_F64_IMPORTS, RANDOM_STATE_GLOBAL, SEED_RANDOM_EXPORT_NAME,
+};
+pub use record::{RecordedImport, REPLAYING_GLOBAL};
+pub use reload::{compare_modules, Incompatibility, ReloadError, ReloadOutput, ReloadReport};
+pub use statistics::{FunctionStatistics, InstrumentationStatistics};
+pub use wasi::{ERRNO_BADF, ERRNO_NOSYS, ERRNO_SUCCESS, WASI_MODULE};
+pub use watch::{WATCHPOINT_SLOTS, WATCH_EXPORT_NAME};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransformOptions {
+    /// Export every mutable global so the host can snapshot it.
+    pub export_globals: bool,
+    /// Report stores, memory grows and global sets to the host.
+    pub track_changes: bool,
+    /// Report the memory loads read to the host with `on_load(address, size)`, so it can tell
+    /// which calls depend on which others.
+    pub track_reads: bool,
+    /// For debugging: export `wg_watch` for the host to register address ranges with, and call
+    /// `on_watch_hit(func_index, address, size, value)` before any write that overlaps one.
+    pub watchpoints: bool,
+    /// Export `wg_caller_id` and `wg_event_time` globals for the host to set before each call,
+    /// and implement the `wasm_guardian.caller_id` and `wasm_guardian.event_time` imports with them.
+    pub invocation_context: bool,
+    /// Replace imported sources of randomness, like `env.seed`, with a seeded generator in the module.
+    pub deterministic_random: bool,
+    /// Implement WASI preview1 imports deterministically inside the module.
+    /// Implies `invocation_context` and `deterministic_random`.
+    pub wasi: bool,
+    /// Imports whose results are recorded on the peer that makes a call and replayed everywhere
+    /// else, as `module.name` patterns that may use `*` wildcards.
+    pub recorded_imports: Vec<String>,
+    /// Generate a `wg_dispatch` export that only calls allowed exports.
+    pub dispatch: Option<DispatchOptions>,
+    /// Only instrument some functions for `track_changes`. Every function is instrumented if
+    /// this is `None`.
+    pub instrumentation_filter: Option<InstrumentationFilter>,
+}
+
+pub struct TransformOutput {
+    pub wasm: Vec<u8>,
+    /// Where the original module's instructions are in `wasm`, for rewriting source maps.
+    /// `None` if the module was already transformed and is returned unchanged.
+    pub code_offsets: Option<CodeOffsets>,
+    pub manifest: Manifest,
+    /// What the transform instrumented, and how much it grew the code.
+    /// `None` if the module was already transformed and is returned unchanged.
+    pub statistics: Option<InstrumentationStatistics>,
+}
+
 /// Transforms a WebAssembly binary to report to the host environment whenever it makes persistent state changes.
 ///
 /// If memory is modified the imported function `on_store` will be called with an i32 of the
-/// address changed and an i32 of the size of the location modified.
+/// address changed and an i32 of the size of the location modified. Stores through the same
+/// pointer in straight-line code are reported by one call, before the first of them, covering
+/// all of them.
 ///
 /// If the WebAssembly grows the memory the imported function `on_grow` will be called with the
 /// number of WebAssembly pages to be allocated.
 ///
-/// When a global is set "on_global_set" is called with an i32 that corresponds to an exported global
-/// named "wg_global_n" where n is replaced with the i32.
+/// Panics if the module is invalid or was already transformed with different options.
+///
+/// When a global is set "on_global_set" is called with an i32 of the global's index, which for the
+/// module's own globals is the index in the original module. Mutable globals are exported as
+/// "wg_global_n", or "wg_global_n_name" if the name section names them. See [GlobalName].
 pub fn transform_wasm_to_track_changes(
     bytes: &[u8],
     export_globals: bool,
     track_changes: bool,
 ) -> Vec<u8> {
-    let mut module = walrus::Module::from_buffer(&bytes).unwrap();
+    let options = TransformOptions {
+        export_globals,
+        track_changes,
+        ..Default::default()
+    };
+    transform_wasm(bytes, &options).unwrap().wasm
+}
+
+/// The same as [transform_wasm_to_track_changes] but also returns [InstrumentationStatistics]
+/// describing what was instrumented.
+pub fn transform_wasm_to_track_changes_with_statistics(
+    bytes: &[u8],
+    export_globals: bool,
+    track_changes: bool,
+) -> (Vec<u8>, InstrumentationStatistics) {
+    let options = TransformOptions {
+        export_globals,
+        track_changes,
+        ..Default::default()
+    };
+    let output = transform_wasm(bytes, &options).unwrap();
+    (output.wasm, output.statistics.unwrap_or_default())
+}
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransformError {
+    InvalidModule,
+    /// The module was already transformed, with different options. Transform the original
+    /// module instead.
+    AlreadyTransformed(Box<Provenance>),
+    /// The module has DWARF debug info in the named section, which would point at the wrong
+    /// code after the transform. Strip it and use a source map instead.
+    DwarfSection(String),
+    /// The module has a provenance section in a format this version of `wasm_guardian` can't
+    /// read, so it may already be instrumented. Transform the original module instead.
+    UnknownProvenance,
+    /// `module.name` is an import the transform implements or records, but it can't for the
+    /// reason given, like the import having the wrong type.
+    UnsupportedImport {
+        module: String,
+        name: String,
+        reason: String,
+    },
+}
+
+/// The same as [transform_wasm_to_track_changes] but with every option available, and also
+/// returns a [Manifest] describing the transformed module's exports.
+///
+/// The result records its [Provenance] in a custom section. A module that was already
+/// transformed with the same options is returned unchanged, and one that was transformed with
+/// different options is rejected, so instrumentation is never applied twice.
+pub fn transform_wasm(
+    bytes: &[u8],
+    options: &TransformOptions,
+) -> Result<TransformOutput, TransformError> {
+    let mut module = walrus::ModuleConfig::new()
+        .preserve_code_transform(true)
+        .parse(bytes)
+        .map_err(|_| TransformError::InvalidModule)?;
+
+    if let Some(provenance) = Provenance::read(bytes) {
+        if provenance.options != *options {
+            return Err(TransformError::AlreadyTransformed(Box::new(provenance)));
+        }
+        let recorded_imports = record::recorded_imports(&module, &options.recorded_imports)?;
+        let manifest =
+            Manifest::from_module(&module, &binary::global_names(bytes), recorded_imports);
+        return Ok(TransformOutput {
+            wasm: bytes.to_vec(),
+            code_offsets: None,
+            manifest,
+            statistics: None,
+        });
+    }
+    if provenance::has_section(bytes) {
+        return Err(TransformError::UnknownProvenance);
+    }
+    if let Some(name) = debug::dwarf_section(bytes) {
+        return Err(TransformError::DwarfSection(name.to_string()));
+    }
+    let original_hash = hash::semantic_hash(bytes);
+    // Functions added by the passes below have no index in the original module.
+    let original_function_indices: std::collections::HashMap<walrus::FunctionId, u32> = module
+        .funcs
+        .iter()
+        .enumerate()
+        .map(|(index, function)| (function.id(), index as u32))
+        .collect();
+    let mut statistics = InstrumentationStatistics::default();
+
+    // These are added first so that the globals and memory they change are exported and
+    // tracked like the module's own.
+    let context = if options.invocation_context || options.wasi {
+        Some(context::add_invocation_context(&mut module)?)
+    } else {
+        None
+    };
+    if options.deterministic_random || options.wasi {
+        random::replace_random_imports(&mut module);
+    }
+    if let (true, Some(context)) = (options.wasi, context) {
+        wasi::replace_wasi_imports(&mut module, context)?;
+    }
+
+    let global_indices = globals::global_indices(&module);
+    let global_names = binary::global_names(bytes);
     let walrus::Module {
         exports, globals, ..
     } = &mut module;
 
-    if export_globals {
+    if options.export_globals {
+        // Globals generated for the host, like `wg_caller_id`, are already exported.
+        let generated: std::collections::HashSet<walrus::GlobalId> = exports
+            .iter()
+            .filter_map(|export| match export.item {
+                walrus::ExportItem::Global(global) if export.name.starts_with("wg_") => {
+                    Some(global)
+                }
+                _ => None,
+            })
+            .collect();
         for global in globals.iter() {
-            if global.mutable {
-                match global.kind {
-                    walrus::GlobalKind::Local(walrus::InitExpr::Value(..)) => {
-                        exports.add(&format!("wg_global_{:?}", global.id().index()), global.id());
-                    }
-                    _ => {}
+            if global.mutable && !generated.contains(&global.id()) {
+                if let walrus::GlobalKind::Local(walrus::InitExpr::Value(..)) = global.kind {
+                    let index = global_indices[&global.id()];
+                    let name = GlobalName {
+                        index,
+                        name: global_names.get(&index).cloned(),
+                    };
+                    exports.add(&name.export_name(), global.id());
                 }
             }
         }
     }
 
-    if track_changes {
+    if options.track_changes || options.track_reads {
         // Create a unique local identifier, one for each type we'll need to temporarily store.
         let local0 = module.locals.add(walrus::ValType::I32);
         let local1_i32 = module.locals.add(walrus::ValType::I32);
@@ -44,26 +254,50 @@ pub fn transform_wasm_to_track_changes(
         // Used for 3 arg operations that are part of the bulk-memory extension.
         let local2 = module.locals.add(walrus::ValType::I32);
         let local3 = module.locals.add(walrus::ValType::I32);
+        // Used to compute the range a loop accesses.
+        let loop_temp = module.locals.add(walrus::ValType::I64);
 
-        let function_type = module
+        // Hooks are only imported for what's being tracked.
+        let range_type = module
             .types
             .add(&[walrus::ValType::I32, walrus::ValType::I32], &[]);
-        let mem_log_function = module
-            .add_import_func("wasm_guardian", "on_store", function_type)
-            .0;
-
-        let function_type = module.types.add(&[walrus::ValType::I32], &[]);
-        let grow_function = module
-            .add_import_func("wasm_guardian", "on_grow", function_type)
-            .0;
-        let global_set_function = module
-            .add_import_func("wasm_guardian", "on_global_set", function_type)
-            .0;
+        let index_type = module.types.add(&[walrus::ValType::I32], &[]);
+        let mut add_hook = |name, function_type, enabled: bool| {
+            enabled.then(|| {
+                module
+                    .add_import_func("wasm_guardian", name, function_type)
+                    .0
+            })
+        };
+        let mem_log_function = add_hook("on_store", range_type, options.track_changes);
+        let grow_function = add_hook("on_grow", index_type, options.track_changes);
+        let global_set_function = add_hook("on_global_set", index_type, options.track_changes);
+        let load_function = add_hook("on_load", range_type, options.track_reads);
 
         let mut new_instructions = Vec::new();
         let mut blocks = Vec::new();
 
-        for (_, function) in module.funcs.iter_local_mut() {
+        let instrumented_functions = options
+            .instrumentation_filter
+            .as_ref()
+            .map(|filter| filter.instrumented_functions(&module, &original_function_indices));
+        let function_names: std::collections::HashMap<walrus::FunctionId, String> = module
+            .funcs
+            .iter()
+            .filter_map(|function| Some((function.id(), function.name.clone()?)))
+            .collect();
+
+        for (id, function) in module.funcs.iter_local_mut() {
+            if let Some(instrumented_functions) = &instrumented_functions {
+                if !instrumented_functions.contains(&id) {
+                    continue;
+                }
+            }
+            let mut function_statistics = FunctionStatistics {
+                index: original_function_indices.get(&id).copied(),
+                name: function_names.get(&id).cloned(),
+                ..Default::default()
+            };
             blocks.clear();
             blocks.push(function.entry_block());
 
@@ -73,12 +307,80 @@ pub fn transform_wasm_to_track_changes(
 
             walrus::ir::dfs_in_order(&mut visitor, function, function.entry_block());
 
+            // Loops whose accesses through a pointer are reported before they run, with the
+            // plan for the loop's body and the hook that reports them.
+            let mut loop_plans = Vec::new();
+            for block in &blocks {
+                for (instruction, _) in &function.block(*block).instrs {
+                    let walrus::ir::Instr::Loop(walrus::ir::Loop { seq }) = instruction else {
+                        continue;
+                    };
+                    let body = &function.block(*seq).instrs;
+                    for (hook, access) in [
+                        (mem_log_function, Access::Stores),
+                        (load_function, Access::Loads),
+                    ] {
+                        if let Some(hook) = hook {
+                            if let Some(plan) = coalesce::plan_loop(body, *seq, access) {
+                                // Counted like the first access it covers.
+                                let name = body.iter().find_map(|(instr, _)| {
+                                    coalesce::accessed(instr, access)?;
+                                    statistics::instrumented_name(instr, options)
+                                });
+                                loop_plans.push((*seq, access, hook, plan, name));
+                            }
+                        }
+                    }
+                }
+            }
+            let mut loop_notifications: std::collections::HashMap<walrus::ir::InstrSeqId, Vec<_>> =
+                std::collections::HashMap::new();
+            let mut loop_bodies = std::collections::HashMap::new();
+            for (seq, access, hook, plan, name) in loop_plans {
+                let notification =
+                    coalesce::add_loop_notification(function, hook, &plan.range, loop_temp);
+                loop_notifications
+                    .entry(seq)
+                    .or_default()
+                    .push((notification, name));
+                loop_bodies.insert((seq, access), plan.notifications);
+            }
+
             for block in &mut blocks {
                 let instructions = &mut function.block_mut(*block).instrs;
                 new_instructions.clear();
                 new_instructions.reserve(instructions.len());
+                let mut plan = |enabled: bool, access| {
+                    let plan = enabled.then(|| {
+                        loop_bodies
+                            .remove(&(*block, access))
+                            .unwrap_or_else(|| coalesce::plan_block(instructions, access))
+                    });
+                    plan.unwrap_or_default().into_iter()
+                };
+                let mut store_notifications = plan(options.track_changes, Access::Stores);
+                let mut load_notifications = plan(options.track_reads, Access::Loads);
 
                 for instruction in instructions.iter_mut() {
+                    // Injected code shares the location of the instruction it wraps, so traps
+                    // and breakpoints in it map back to the original source line.
+                    let loc = instruction.1;
+                    let instrumented_name = statistics::instrumented_name(&instruction.0, options);
+                    let mut count = |name: Option<&'static str>| {
+                        if let Some(name) = name {
+                            *function_statistics.instructions.entry(name).or_default() += 1;
+                        }
+                    };
+                    // Accesses are counted once their notification is planned, because those
+                    // an earlier notification covers aren't instrumented.
+                    if !matches!(
+                        instruction.0,
+                        walrus::ir::Instr::Store(_)
+                            | walrus::ir::Instr::Load(_)
+                            | walrus::ir::Instr::LoadSimd(_)
+                    ) {
+                        count(instrumented_name);
+                    }
                     match &instruction.0 {
                         // TODO: Handle MemoryCopy
                         walrus::ir::Instr::DataDrop(_)
@@ -86,74 +388,100 @@ pub fn transform_wasm_to_track_changes(
                         | walrus::ir::Instr::ElemDrop(_)
                         | walrus::ir::Instr::TableCopy(_)
                         | walrus::ir::Instr::TableGrow(_)
-                        | walrus::ir::Instr::TableFill(_) => {
+                        | walrus::ir::Instr::TableFill(_)
+                            if options.track_changes =>
+                        {
                             todo!("{:?}", instruction.0)
                         }
                         walrus::ir::Instr::MemoryCopy(_)
                         | walrus::ir::Instr::MemoryInit(_)
                         | walrus::ir::Instr::MemoryFill(_) => {
+                            // `memory.copy` also reads its source range.
+                            let read_source = load_function.filter(|_| {
+                                matches!(instruction.0, walrus::ir::Instr::MemoryCopy(_))
+                            });
+                            if mem_log_function.is_none() && read_source.is_none() {
+                                new_instructions.push(instruction.clone());
+                                continue;
+                            }
+                            let local_get = |local| {
+                                (
+                                    walrus::ir::Instr::LocalGet(walrus::ir::LocalGet { local }),
+                                    loc,
+                                )
+                            };
+                            let call =
+                                |func| (walrus::ir::Instr::Call(walrus::ir::Call { func }), loc);
                             new_instructions.extend_from_slice(&[
-                                // Push both args to the store to temporary locals.
+                                // Push all three args to temporary locals.
                                 // This isn't the most efficient approach but it is simple
                                 // and works for now without more complex analysis.
                                 (
                                     walrus::ir::Instr::LocalSet(walrus::ir::LocalSet {
                                         local: local3,
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                                 (
                                     walrus::ir::Instr::LocalSet(walrus::ir::LocalSet {
                                         local: local2,
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                                 (
                                     walrus::ir::Instr::LocalSet(walrus::ir::LocalSet {
                                         local: local0,
                                     }),
-                                    walrus::InstrLocId::default(),
-                                ),
-                                (
-                                    walrus::ir::Instr::LocalGet(walrus::ir::LocalGet {
-                                        local: local3,
-                                    }),
-                                    walrus::InstrLocId::default(),
-                                ),
-                                (
-                                    walrus::ir::Instr::LocalGet(walrus::ir::LocalGet {
-                                        local: local0,
-                                    }),
-                                    walrus::InstrLocId::default(),
-                                ),
-                                (
-                                    walrus::ir::Instr::Call(walrus::ir::Call {
-                                        func: mem_log_function,
-                                    }),
-                                    walrus::InstrLocId::default(),
-                                ),
-                                (
-                                    walrus::ir::Instr::LocalGet(walrus::ir::LocalGet {
-                                        local: local0,
-                                    }),
-                                    walrus::InstrLocId::default(),
-                                ),
-                                (
-                                    walrus::ir::Instr::LocalGet(walrus::ir::LocalGet {
-                                        local: local2,
-                                    }),
-                                    walrus::InstrLocId::default(),
-                                ),
-                                (
-                                    walrus::ir::Instr::LocalGet(walrus::ir::LocalGet {
-                                        local: local3,
-                                    }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
+                            ]);
+                            if let Some(mem_log_function) = mem_log_function {
+                                new_instructions.extend_from_slice(&[
+                                    local_get(local0),
+                                    local_get(local3),
+                                    call(mem_log_function),
+                                ]);
+                            }
+                            if let Some(load_function) = read_source {
+                                new_instructions.extend_from_slice(&[
+                                    local_get(local2),
+                                    local_get(local3),
+                                    call(load_function),
+                                ]);
+                            }
+                            new_instructions.extend_from_slice(&[
+                                local_get(local0),
+                                local_get(local2),
+                                local_get(local3),
                                 instruction.clone(),
                             ]);
                         }
-                        walrus::ir::Instr::Store(s) => {
+                        walrus::ir::Instr::Store(s) if options.track_changes => {
+                            let mem_log_function = mem_log_function.unwrap();
+                            let notification = store_notifications.next();
+                            if notification != Some(Notification::Covered) {
+                                count(instrumented_name);
+                            }
+                            match notification {
+                                Some(Notification::Range { base, offset, size }) => {
+                                    // The address is in a local that doesn't change before
+                                    // the last store the range covers, so it's reported
+                                    // without moving the store's arguments.
+                                    coalesce::push_range_notification(
+                                        &mut new_instructions,
+                                        mem_log_function,
+                                        (base, offset, size),
+                                        loc,
+                                    );
+                                    new_instructions.push(instruction.clone());
+                                    continue;
+                                }
+                                Some(Notification::Covered) => {
+                                    new_instructions.push(instruction.clone());
+                                    continue;
+                                }
+                                Some(Notification::Separate) | None => {}
+                            }
                             let (local1, size) = match s.kind {
                                 walrus::ir::StoreKind::I32 { .. } => {
                                     (local1_i32, std::mem::size_of::<i32>() as _)
@@ -195,13 +523,13 @@ pub fn transform_wasm_to_track_changes(
                                     walrus::ir::Instr::LocalSet(walrus::ir::LocalSet {
                                         local: local1,
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                                 (
                                     walrus::ir::Instr::LocalTee(walrus::ir::LocalTee {
                                         local: local0,
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                             ]);
 
@@ -215,14 +543,14 @@ pub fn transform_wasm_to_track_changes(
                                         walrus::ir::Instr::Const(walrus::ir::Const {
                                             value: walrus::ir::Value::I32(s.arg.offset as _),
                                         }),
-                                        walrus::InstrLocId::default(),
+                                        loc,
                                     ),
                                     (
                                         // This is operating on memory addresses, is this the correct type of add?
                                         walrus::ir::Instr::Binop(walrus::ir::Binop {
                                             op: walrus::ir::BinaryOp::I32Add,
                                         }),
-                                        walrus::InstrLocId::default(),
+                                        loc,
                                     ),
                                 ]);
                             }
@@ -235,72 +563,183 @@ pub fn transform_wasm_to_track_changes(
                                     walrus::ir::Instr::Const(walrus::ir::Const {
                                         value: walrus::ir::Value::I32(size),
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                                 (
                                     walrus::ir::Instr::Call(walrus::ir::Call {
                                         func: mem_log_function,
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                                 (
                                     walrus::ir::Instr::LocalGet(walrus::ir::LocalGet {
                                         local: local0,
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                                 (
                                     walrus::ir::Instr::LocalGet(walrus::ir::LocalGet {
                                         local: local1,
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                                 instruction.clone(),
                             ]);
                         }
-                        walrus::ir::Instr::MemoryGrow { .. } => {
+                        walrus::ir::Instr::MemoryGrow { .. } if options.track_changes => {
+                            let grow_function = grow_function.unwrap();
                             // Report memory grows
                             new_instructions.extend_from_slice(&[
                                 (
                                     walrus::ir::Instr::LocalTee(walrus::ir::LocalTee {
                                         local: local0,
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                                 (
                                     walrus::ir::Instr::Call(walrus::ir::Call {
                                         func: grow_function,
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                                 (
                                     walrus::ir::Instr::LocalGet(walrus::ir::LocalGet {
                                         local: local0,
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                                 instruction.clone(),
                             ]);
                         }
-                        walrus::ir::Instr::GlobalSet(global_set) => {
+                        walrus::ir::Instr::GlobalSet(global_set) if options.track_changes => {
+                            let global_set_function = global_set_function.unwrap();
                             new_instructions.extend_from_slice(&[
                                 (
                                     walrus::ir::Instr::Const(walrus::ir::Const {
                                         value: walrus::ir::Value::I32(
-                                            global_set.global.index() as i32
+                                            global_indices[&global_set.global] as i32,
                                         ),
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                                 (
                                     walrus::ir::Instr::Call(walrus::ir::Call {
                                         func: global_set_function,
                                     }),
-                                    walrus::InstrLocId::default(),
+                                    loc,
                                 ),
                                 instruction.clone(),
                             ]);
                         }
+                        walrus::ir::Instr::Load(walrus::ir::Load { arg, .. })
+                        | walrus::ir::Instr::LoadSimd(walrus::ir::LoadSimd { arg, .. })
+                            if options.track_reads =>
+                        {
+                            let load_function = load_function.unwrap();
+                            let width = match &instruction.0 {
+                                walrus::ir::Instr::Load(load) => Some((load.kind.width(), 0)),
+                                walrus::ir::Instr::LoadSimd(load) => {
+                                    coalesce::simd_load_width(load.kind)
+                                }
+                                _ => unreachable!(),
+                            };
+                            // Lane stores are planned as neither loads nor stores.
+                            let Some((width, depth)) = width else {
+                                new_instructions.push(instruction.clone());
+                                continue;
+                            };
+                            let notification = load_notifications.next();
+                            if notification != Some(Notification::Covered) {
+                                count(instrumented_name);
+                            }
+                            match notification {
+                                Some(Notification::Range { base, offset, size }) => {
+                                    coalesce::push_range_notification(
+                                        &mut new_instructions,
+                                        load_function,
+                                        (base, offset, size),
+                                        loc,
+                                    );
+                                }
+                                Some(Notification::Covered) => {}
+                                Some(Notification::Separate) | None => {
+                                    // Lane loads have the vector to load into above the address.
+                                    if depth == 1 {
+                                        new_instructions.push((
+                                            walrus::ir::Instr::LocalSet(walrus::ir::LocalSet {
+                                                local: local1_i128,
+                                            }),
+                                            loc,
+                                        ));
+                                    }
+                                    new_instructions.push((
+                                        walrus::ir::Instr::LocalTee(walrus::ir::LocalTee {
+                                            local: local0,
+                                        }),
+                                        loc,
+                                    ));
+                                    if arg.offset != 0 {
+                                        new_instructions.extend_from_slice(&[
+                                            (
+                                                walrus::ir::Instr::Const(walrus::ir::Const {
+                                                    value: walrus::ir::Value::I32(arg.offset as _),
+                                                }),
+                                                loc,
+                                            ),
+                                            (
+                                                walrus::ir::Instr::Binop(walrus::ir::Binop {
+                                                    op: walrus::ir::BinaryOp::I32Add,
+                                                }),
+                                                loc,
+                                            ),
+                                        ]);
+                                    }
+                                    new_instructions.extend_from_slice(&[
+                                        (
+                                            walrus::ir::Instr::Const(walrus::ir::Const {
+                                                value: walrus::ir::Value::I32(width as _),
+                                            }),
+                                            loc,
+                                        ),
+                                        (
+                                            walrus::ir::Instr::Call(walrus::ir::Call {
+                                                func: load_function,
+                                            }),
+                                            loc,
+                                        ),
+                                        (
+                                            walrus::ir::Instr::LocalGet(walrus::ir::LocalGet {
+                                                local: local0,
+                                            }),
+                                            loc,
+                                        ),
+                                    ]);
+                                    if depth == 1 {
+                                        new_instructions.push((
+                                            walrus::ir::Instr::LocalGet(walrus::ir::LocalGet {
+                                                local: local1_i128,
+                                            }),
+                                            loc,
+                                        ));
+                                    }
+                                }
+                            }
+                            new_instructions.push(instruction.clone());
+                        }
+                        walrus::ir::Instr::Loop(walrus::ir::Loop { seq }) => {
+                            for (notification, name) in
+                                loop_notifications.get(seq).into_iter().flatten()
+                            {
+                                count(*name);
+                                new_instructions.push((
+                                    walrus::ir::Instr::Block(walrus::ir::Block {
+                                        seq: *notification,
+                                    }),
+                                    loc,
+                                ));
+                            }
+                            new_instructions.push(instruction.clone());
+                        }
                         _ => {
                             new_instructions.push(instruction.clone());
                         }
@@ -308,10 +747,37 @@ pub fn transform_wasm_to_track_changes(
                 }
                 std::mem::swap(&mut new_instructions, instructions);
             }
+            statistics.add_function(function_statistics);
         }
     }
 
-    module.emit_wasm()
+    // After instrumentation, so stores are checked once `on_store` has reported them.
+    if options.watchpoints {
+        watch::add_watchpoints(&mut module, &original_function_indices);
+    }
+
+    let recorded_imports = record::record_imports(&mut module, &options.recorded_imports)?;
+
+    // This is added after instrumentation, which it doesn't need, and after every other export
+    // so that adding it doesn't change the index of any export the host calls.
+    if let Some(dispatch_options) = &options.dispatch {
+        dispatch::add_dispatch_export(&mut module, dispatch_options);
+    }
+
+    let manifest = Manifest::from_module(&module, &global_names, recorded_imports);
+
+    let (provenance, code_offsets) =
+        debug::RecordCodeOffsets::new(Provenance::new(options, original_hash).to_custom_section());
+    module.customs.add(provenance);
+    let wasm = module.emit_wasm();
+    let code_offsets = std::mem::take(&mut *code_offsets.lock().unwrap());
+    statistics.finish(bytes, &wasm);
+    Ok(TransformOutput {
+        wasm,
+        code_offsets: Some(code_offsets),
+        manifest,
+        statistics: Some(statistics),
+    })
 }
 
 struct AllBlocks<'a> {
@@ -325,6 +791,10 @@ impl<'instr> walrus::ir::Visitor<'instr> for AllBlocks<'instr> {
     fn visit_loop(&mut self, instr: &walrus::ir::Loop) {
         self.blocks.push(instr.seq);
     }
+    fn visit_if_else(&mut self, instr: &walrus::ir::IfElse) {
+        self.blocks.push(instr.consequent);
+        self.blocks.push(instr.alternative);
+    }
 }
 
 /*
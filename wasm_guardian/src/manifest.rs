@@ -0,0 +1,277 @@
+//! A description of a module's exports and memory that hosts can use to validate calls
+//! before running them.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::call_graph::{CallGraph, SideEffects};
+use crate::record::RecordedImport;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// In the order the exports appear in the module, which is the order of
+    /// `Object.keys(instance.exports)` on the host.
+    pub exports: Vec<Export>,
+    /// Active data segments with a constant offset, as `(offset, length)`.
+    pub data_segments: Vec<(u32, u32)>,
+    /// Imports whose results are recorded and replayed, in `recorded_index` order.
+    pub recorded_imports: Vec<RecordedImport>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Export {
+    pub name: String,
+    pub kind: ExportKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExportKind {
+    Function {
+        params: Vec<walrus::ValType>,
+        results: Vec<walrus::ValType>,
+        /// What calling the export can change, found by static analysis. Calls to recorded
+        /// imports aren't counted because every peer sees the same results.
+        side_effects: SideEffects,
+    },
+    Global {
+        value_type: walrus::ValType,
+        mutable: bool,
+        /// The global's index in the original module.
+        index: u32,
+        /// The global's name from the original module's name section, if it had one.
+        original_name: Option<String>,
+    },
+    Memory {
+        initial_pages: u32,
+        maximum_pages: Option<u32>,
+        shared: bool,
+    },
+    Table,
+}
+
+impl Manifest {
+    /// `global_names` should come from the original binary with `binary::global_names`
+    /// because walrus doesn't preserve them.
+    pub(crate) fn from_module(
+        module: &walrus::Module,
+        global_names: &HashMap<u32, String>,
+        recorded_imports: Vec<RecordedImport>,
+    ) -> Self {
+        let global_indices = crate::globals::global_indices(module);
+        let mut call_graph = CallGraph::new(module);
+        for import in module.imports.iter() {
+            if let walrus::ImportKind::Function(function) = import.kind {
+                let recorded = recorded_imports
+                    .iter()
+                    .any(|r| r.module == import.module && r.name == import.name);
+                if recorded {
+                    call_graph.ignore_import(function);
+                }
+            }
+        }
+
+        let exports = module
+            .exports
+            .iter()
+            .map(|export| {
+                let kind = match export.item {
+                    walrus::ExportItem::Function(id) => {
+                        let ty = module.types.get(module.funcs.get(id).ty());
+                        ExportKind::Function {
+                            params: ty.params().to_vec(),
+                            results: ty.results().to_vec(),
+                            side_effects: call_graph.side_effects(id),
+                        }
+                    }
+                    walrus::ExportItem::Global(id) => {
+                        let global = module.globals.get(id);
+                        let index = global_indices[&id];
+                        ExportKind::Global {
+                            value_type: global.ty,
+                            mutable: global.mutable,
+                            index,
+                            original_name: global_names.get(&index).cloned(),
+                        }
+                    }
+                    walrus::ExportItem::Memory(id) => {
+                        let memory = module.memories.get(id);
+                        ExportKind::Memory {
+                            initial_pages: memory.initial,
+                            maximum_pages: memory.maximum,
+                            shared: memory.shared,
+                        }
+                    }
+                    walrus::ExportItem::Table(_) => ExportKind::Table,
+                };
+                Export {
+                    name: export.name.clone(),
+                    kind,
+                }
+            })
+            .collect();
+
+        let data_segments = module
+            .data
+            .iter()
+            .filter_map(|data| match &data.kind {
+                walrus::DataKind::Active(walrus::ActiveData {
+                    location: walrus::ActiveDataLocation::Absolute(offset),
+                    ..
+                }) => Some((*offset, data.value.len() as u32)),
+                _ => None,
+            })
+            .collect();
+
+        Manifest {
+            exports,
+            data_segments,
+            recorded_imports,
+        }
+    }
+
+    /// Serializes the manifest as JSON for the host.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"exports\":[");
+        for (i, export) in self.exports.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"name\":");
+            write_json_string(&mut json, &export.name);
+            match &export.kind {
+                ExportKind::Function {
+                    params,
+                    results,
+                    side_effects,
+                } => {
+                    json.push_str(",\"kind\":\"function\",\"params\":");
+                    write_json_types(&mut json, params);
+                    json.push_str(",\"results\":");
+                    write_json_types(&mut json, results);
+                    json.push_str(",\"side_effects\":");
+                    write_json_side_effects(&mut json, side_effects);
+                }
+                ExportKind::Global {
+                    value_type,
+                    mutable,
+                    index,
+                    original_name,
+                } => {
+                    let _ = write!(
+                        json,
+                        ",\"kind\":\"global\",\"type\":\"{}\",\"mutable\":{},\"index\":{},\"original_name\":",
+                        type_name(*value_type),
+                        mutable,
+                        index
+                    );
+                    match original_name {
+                        Some(name) => write_json_string(&mut json, name),
+                        None => json.push_str("null"),
+                    }
+                }
+                ExportKind::Memory {
+                    initial_pages,
+                    maximum_pages,
+                    shared,
+                } => {
+                    let _ = write!(
+                        json,
+                        ",\"kind\":\"memory\",\"initial_pages\":{},\"maximum_pages\":",
+                        initial_pages
+                    );
+                    match maximum_pages {
+                        Some(pages) => {
+                            let _ = write!(json, "{}", pages);
+                        }
+                        None => json.push_str("null"),
+                    }
+                    let _ = write!(json, ",\"shared\":{}", shared);
+                }
+                ExportKind::Table => json.push_str(",\"kind\":\"table\""),
+            }
+            json.push('}');
+        }
+        json.push_str("],\"data_segments\":[");
+        for (i, (offset, length)) in self.data_segments.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(json, "{{\"offset\":{},\"length\":{}}}", offset, length);
+        }
+        json.push_str("],\"recorded_imports\":[");
+        for (i, import) in self.recorded_imports.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"module\":");
+            write_json_string(&mut json, &import.module);
+            json.push_str(",\"name\":");
+            write_json_string(&mut json, &import.name);
+            json.push_str(",\"result\":");
+            match import.result {
+                Some(result) => {
+                    let _ = write!(json, "\"{}\"", type_name(result));
+                }
+                None => json.push_str("null"),
+            }
+            json.push('}');
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+pub fn type_name(value_type: walrus::ValType) -> &'static str {
+    match value_type {
+        walrus::ValType::I32 => "i32",
+        walrus::ValType::I64 => "i64",
+        walrus::ValType::F32 => "f32",
+        walrus::ValType::F64 => "f64",
+        walrus::ValType::V128 => "v128",
+        walrus::ValType::Externref => "externref",
+        walrus::ValType::Funcref => "funcref",
+    }
+}
+
+/// Writes the labels of `side_effects`, an empty array if it's pure.
+fn write_json_side_effects(json: &mut String, side_effects: &SideEffects) {
+    json.push('[');
+    let labels = [
+        (side_effects.writes_memory, "writes_memory"),
+        (side_effects.writes_globals, "writes_globals"),
+        (side_effects.calls_imports, "calls_imports"),
+    ];
+    for (i, (_, label)) in labels.iter().filter(|(has, _)| *has).enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(json, "\"{}\"", label);
+    }
+    json.push(']');
+}
+
+fn write_json_types(json: &mut String, types: &[walrus::ValType]) {
+    json.push('[');
+    for (i, t) in types.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(json, "\"{}\"", type_name(*t));
+    }
+    json.push(']');
+}
+
+pub(crate) fn write_json_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
@@ -0,0 +1,206 @@
+//! A custom section recording how a module was transformed, so that modules aren't transformed
+//! twice and hosts can tell what a module was built with.
+
+use std::fmt::Write;
+
+use crate::binary::{sections, write_name, write_var_u32, Reader};
+use crate::manifest::write_json_string;
+use crate::{DispatchOptions, InstrumentationFilter, TransformOptions};
+
+pub const PROVENANCE_SECTION_NAME: &str = "wasm_guardian";
+
+/// Incremented when the section's layout changes. Sections with other versions aren't read.
+const FORMAT_VERSION: u8 = 2;
+
+// Flags are written as a LEB128 number, so adding one doesn't change the layout.
+const EXPORT_GLOBALS: u32 = 1 << 0;
+const TRACK_CHANGES: u32 = 1 << 1;
+const INVOCATION_CONTEXT: u32 = 1 << 2;
+const DETERMINISTIC_RANDOM: u32 = 1 << 3;
+const WASI: u32 = 1 << 4;
+const DISPATCH: u32 = 1 << 5;
+const INSTRUMENTATION_FILTER: u32 = 1 << 6;
+const TRACK_READS: u32 = 1 << 7;
+const WATCHPOINTS: u32 = 1 << 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Provenance {
+    /// The version of `wasm_guardian` that transformed the module.
+    pub version: String,
+    pub options: TransformOptions,
+    /// The [crate::semantic_hash] of the module before it was transformed, which is also the
+    /// transformed module's hash. Nothing checks it.
+    pub original_hash: u128,
+}
+
+impl Provenance {
+    pub(crate) fn new(options: &TransformOptions, original_hash: u128) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            options: options.clone(),
+            original_hash,
+        }
+    }
+
+    /// Reads the [PROVENANCE_SECTION_NAME] section of a module, if it has one that this
+    /// version of `wasm_guardian` understands.
+    pub fn read(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader::new(section_data(bytes)?);
+        if reader.read_u8()? != FORMAT_VERSION {
+            return None;
+        }
+        let version = reader.read_name()?.to_string();
+        let original_hash = u128::from_be_bytes(reader.read_bytes(16)?.try_into().ok()?);
+        let flags = reader.read_var_u32()?;
+        let recorded_imports = read_names(&mut reader)?;
+        let dispatch = if flags & DISPATCH != 0 {
+            Some(DispatchOptions {
+                allowlist: read_names(&mut reader)?,
+            })
+        } else {
+            None
+        };
+        let instrumentation_filter = if flags & INSTRUMENTATION_FILTER != 0 {
+            Some(InstrumentationFilter {
+                include: read_names(&mut reader)?,
+                exclude: read_names(&mut reader)?,
+            })
+        } else {
+            None
+        };
+
+        Some(Self {
+            version,
+            options: TransformOptions {
+                export_globals: flags & EXPORT_GLOBALS != 0,
+                track_changes: flags & TRACK_CHANGES != 0,
+                track_reads: flags & TRACK_READS != 0,
+                watchpoints: flags & WATCHPOINTS != 0,
+                invocation_context: flags & INVOCATION_CONTEXT != 0,
+                deterministic_random: flags & DETERMINISTIC_RANDOM != 0,
+                wasi: flags & WASI != 0,
+                recorded_imports,
+                dispatch,
+                instrumentation_filter,
+            },
+            original_hash,
+        })
+    }
+
+    pub(crate) fn to_custom_section(&self) -> walrus::RawCustomSection {
+        let options = &self.options;
+        let mut flags = 0;
+        for (enabled, flag) in [
+            (options.export_globals, EXPORT_GLOBALS),
+            (options.track_changes, TRACK_CHANGES),
+            (options.track_reads, TRACK_READS),
+            (options.watchpoints, WATCHPOINTS),
+            (options.invocation_context, INVOCATION_CONTEXT),
+            (options.deterministic_random, DETERMINISTIC_RANDOM),
+            (options.wasi, WASI),
+            (options.dispatch.is_some(), DISPATCH),
+            (
+                options.instrumentation_filter.is_some(),
+                INSTRUMENTATION_FILTER,
+            ),
+        ] {
+            if enabled {
+                flags |= flag;
+            }
+        }
+
+        let mut data = vec![FORMAT_VERSION];
+        write_name(&mut data, &self.version);
+        data.extend_from_slice(&self.original_hash.to_be_bytes());
+        write_var_u32(&mut data, flags);
+        write_names(&mut data, &options.recorded_imports);
+        if let Some(dispatch) = &options.dispatch {
+            write_names(&mut data, &dispatch.allowlist);
+        }
+        if let Some(filter) = &options.instrumentation_filter {
+            write_names(&mut data, &filter.include);
+            write_names(&mut data, &filter.exclude);
+        }
+
+        walrus::RawCustomSection {
+            name: PROVENANCE_SECTION_NAME.to_string(),
+            data,
+        }
+    }
+
+    /// `original_hash` is written as 32 hex digits because JSON numbers can't hold it.
+    pub fn to_json(&self) -> String {
+        let options = &self.options;
+        let mut json = String::from("{\"version\":");
+        write_json_string(&mut json, &self.version);
+        let _ = write!(
+            json,
+            ",\"original_hash\":\"{:032x}\",\"options\":{{\"export_globals\":{},\"track_changes\":{},\"track_reads\":{},\"watchpoints\":{},\"invocation_context\":{},\"deterministic_random\":{},\"wasi\":{},\"recorded_imports\":",
+            self.original_hash,
+            options.export_globals,
+            options.track_changes,
+            options.track_reads,
+            options.watchpoints,
+            options.invocation_context,
+            options.deterministic_random,
+            options.wasi,
+        );
+        write_json_strings(&mut json, &options.recorded_imports);
+        json.push_str(",\"dispatch_allowlist\":");
+        match &options.dispatch {
+            Some(dispatch) => write_json_strings(&mut json, &dispatch.allowlist),
+            None => json.push_str("null"),
+        }
+        json.push_str(",\"instrumentation_filter\":");
+        match &options.instrumentation_filter {
+            Some(filter) => {
+                json.push_str("{\"include\":");
+                write_json_strings(&mut json, &filter.include);
+                json.push_str(",\"exclude\":");
+                write_json_strings(&mut json, &filter.exclude);
+                json.push('}');
+            }
+            None => json.push_str("null"),
+        }
+        json.push_str("}}");
+        json
+    }
+}
+
+/// Whether the module has a [PROVENANCE_SECTION_NAME] section, even one
+/// [Provenance::read] doesn't understand.
+pub(crate) fn has_section(bytes: &[u8]) -> bool {
+    section_data(bytes).is_some()
+}
+
+fn section_data(bytes: &[u8]) -> Option<&[u8]> {
+    sections(bytes).find_map(|section| match section.custom() {
+        Some((PROVENANCE_SECTION_NAME, data)) => Some(data),
+        _ => None,
+    })
+}
+
+fn read_names(reader: &mut Reader) -> Option<Vec<String>> {
+    let count = reader.read_var_u32()?;
+    (0..count)
+        .map(|_| reader.read_name().map(str::to_string))
+        .collect()
+}
+
+fn write_names(out: &mut Vec<u8>, names: &[String]) {
+    write_var_u32(out, names.len() as u32);
+    for name in names {
+        write_name(out, name);
+    }
+}
+
+fn write_json_strings(json: &mut String, strings: &[String]) {
+    json.push('[');
+    for (i, s) in strings.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write_json_string(json, s);
+    }
+    json.push(']');
+}
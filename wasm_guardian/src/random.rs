@@ -0,0 +1,207 @@
+//! Replaces imported sources of randomness with a PCG32 generator compiled into the module,
+//! so that every peer generates the same numbers.
+//!
+//! The generator's state is an ordinary mutable global. It's exported, snapshotted and rolled
+//! back like the module's own globals, and its changes are tracked like theirs.
+//! The host seeds it by calling `wg_seed_random(seed: i64)`. Only the peer that creates a room
+//! needs to: peers that join later receive the state with the rest of the module.
+
+use walrus::ir::{BinaryOp, MemArg, StoreKind, UnaryOp, Value};
+use walrus::{FunctionBuilder, FunctionId, InitExpr, InstrSeqBuilder, LocalId, ValType};
+
+use crate::imports::{find_imported_func, replace_imported_func};
+
+pub const SEED_RANDOM_EXPORT_NAME: &str = "wg_seed_random";
+/// The generator's state, exported so that it's snapshotted under a name that doesn't depend on
+/// the module's own globals.
+pub const RANDOM_STATE_GLOBAL: &str = "wg_random_state";
+
+const PCG_MULTIPLIER: i64 = 6364136223846793005;
+const PCG_INCREMENT: i64 = 1442695040888963407;
+/// The state used until the host calls `wg_seed_random`.
+const DEFAULT_STATE: i64 = 0x853c49e6748fea9b_u64 as i64;
+
+/// `() -> f64` imports that are replaced with a random number in `[0, 1)`.
+///
+/// AssemblyScript seeds `Math.random` with `env.seed`.
+pub const RANDOM_F64_IMPORTS: &[(&str, &str)] = &[("env", "seed"), ("Math", "random")];
+
+/// `(buf: i32, buf_len: i32) -> i32` imports that are replaced with a function that fills
+/// memory with random bytes and returns 0.
+pub const RANDOM_BYTES_IMPORTS: &[(&str, &str)] = &[
+    ("wasi_snapshot_preview1", "random_get"),
+    ("wasi_unstable", "random_get"),
+];
+
+/// The generator's functions, once it has been added to a module.
+#[derive(Clone, Copy)]
+pub(crate) struct Random {
+    /// `() -> i32`
+    pub next_u32: FunctionId,
+    /// `() -> f64`, in `[0, 1)`.
+    pub next_f64: FunctionId,
+}
+
+/// Replaces the imports in [RANDOM_F64_IMPORTS] and [RANDOM_BYTES_IMPORTS] that have the
+/// expected types. The generator is only added if at least one import is replaced.
+pub(crate) fn replace_random_imports(module: &mut walrus::Module) {
+    let f64_imports: Vec<FunctionId> = RANDOM_F64_IMPORTS
+        .iter()
+        .filter_map(|(m, n)| find_imported_func(module, m, n))
+        .filter(|f| has_type(module, *f, &[], &[ValType::F64]))
+        .collect();
+    let memory = module.memories.iter().next().map(|m| m.id());
+    let bytes_imports: Vec<FunctionId> = RANDOM_BYTES_IMPORTS
+        .iter()
+        .filter_map(|(m, n)| find_imported_func(module, m, n))
+        .filter(|f| {
+            memory.is_some() && has_type(module, *f, &[ValType::I32, ValType::I32], &[ValType::I32])
+        })
+        .collect();
+    if f64_imports.is_empty() && bytes_imports.is_empty() {
+        return;
+    }
+
+    let random = add_random(module);
+    for function in f64_imports {
+        replace_imported_func(module, function, |body, _| {
+            body.call(random.next_f64);
+        });
+    }
+    for function in bytes_imports {
+        let index = module.locals.add(ValType::I32);
+        replace_imported_func(module, function, |body, args| {
+            fill_random_bytes(body, random, memory.unwrap(), args[0], args[1], index);
+            body.i32_const(0);
+        });
+    }
+}
+
+/// Adds the generator's state global, its functions, and the `wg_seed_random` and
+/// `wg_random_state` exports.
+pub(crate) fn add_random(module: &mut walrus::Module) -> Random {
+    let state = module.globals.add_local(
+        ValType::I64,
+        true,
+        InitExpr::Value(Value::I64(DEFAULT_STATE)),
+    );
+
+    // PCG32 XSH RR: advance the LCG and permute the previous state into the output.
+    let old = module.locals.add(ValType::I64);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+    builder
+        .func_body()
+        .global_get(state)
+        .local_tee(old)
+        .i64_const(PCG_MULTIPLIER)
+        .binop(BinaryOp::I64Mul)
+        .i64_const(PCG_INCREMENT)
+        .binop(BinaryOp::I64Add)
+        .global_set(state)
+        // ((old >> 18) ^ old) >> 27
+        .local_get(old)
+        .i64_const(18)
+        .binop(BinaryOp::I64ShrU)
+        .local_get(old)
+        .binop(BinaryOp::I64Xor)
+        .i64_const(27)
+        .binop(BinaryOp::I64ShrU)
+        .unop(UnaryOp::I32WrapI64)
+        // Rotated by old >> 59
+        .local_get(old)
+        .i64_const(59)
+        .binop(BinaryOp::I64ShrU)
+        .unop(UnaryOp::I32WrapI64)
+        .binop(BinaryOp::I32Rotr);
+    builder.name("wg_random_next_u32".to_string());
+    let next_u32 = builder.finish(vec![], &mut module.funcs);
+
+    // 27 bits from one output and 26 from the next make a 53 bit mantissa.
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::F64]);
+    builder
+        .func_body()
+        .call(next_u32)
+        .i32_const(5)
+        .binop(BinaryOp::I32ShrU)
+        .unop(UnaryOp::F64ConvertUI32)
+        .f64_const(67108864.0)
+        .binop(BinaryOp::F64Mul)
+        .call(next_u32)
+        .i32_const(6)
+        .binop(BinaryOp::I32ShrU)
+        .unop(UnaryOp::F64ConvertUI32)
+        .binop(BinaryOp::F64Add)
+        .f64_const(1.0 / 9007199254740992.0)
+        .binop(BinaryOp::F64Mul);
+    builder.name("wg_random_next_f64".to_string());
+    let next_f64 = builder.finish(vec![], &mut module.funcs);
+
+    // The same as PCG's reference `srandom` with the default increment.
+    let seed = module.locals.add(ValType::I64);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I64], &[]);
+    builder
+        .func_body()
+        .local_get(seed)
+        .i64_const(PCG_INCREMENT)
+        .binop(BinaryOp::I64Add)
+        .i64_const(PCG_MULTIPLIER)
+        .binop(BinaryOp::I64Mul)
+        .i64_const(PCG_INCREMENT)
+        .binop(BinaryOp::I64Add)
+        .global_set(state);
+    builder.name(SEED_RANDOM_EXPORT_NAME.to_string());
+    let seed_random = builder.finish(vec![seed], &mut module.funcs);
+    module.exports.add(SEED_RANDOM_EXPORT_NAME, seed_random);
+    module.exports.add(RANDOM_STATE_GLOBAL, state);
+
+    Random { next_u32, next_f64 }
+}
+
+/// Writes a random byte to each address in `[buf, buf + buf_len)`, using `index` as the loop counter.
+pub(crate) fn fill_random_bytes(
+    body: &mut InstrSeqBuilder,
+    random: Random,
+    memory: walrus::MemoryId,
+    buf: LocalId,
+    buf_len: LocalId,
+    index: LocalId,
+) {
+    body.i32_const(0).local_set(index).block(None, |done| {
+        let done_id = done.id();
+        done.loop_(None, |repeat| {
+            let repeat_id = repeat.id();
+            repeat
+                .local_get(index)
+                .local_get(buf_len)
+                .binop(BinaryOp::I32GeU)
+                .br_if(done_id)
+                .local_get(buf)
+                .local_get(index)
+                .binop(BinaryOp::I32Add)
+                .call(random.next_u32)
+                .store(
+                    memory,
+                    StoreKind::I32_8 { atomic: false },
+                    MemArg {
+                        align: 1,
+                        offset: 0,
+                    },
+                )
+                .local_get(index)
+                .i32_const(1)
+                .binop(BinaryOp::I32Add)
+                .local_set(index)
+                .br(repeat_id);
+        });
+    });
+}
+
+fn has_type(
+    module: &walrus::Module,
+    function: FunctionId,
+    params: &[ValType],
+    results: &[ValType],
+) -> bool {
+    let ty = module.types.get(module.funcs.get(function).ty());
+    ty.params() == params && ty.results() == results
+}
@@ -0,0 +1,183 @@
+//! Makes chosen imports safe to call even though their results differ between peers.
+//!
+//! Each recorded import is replaced by a generated function. While the peer that created an
+//! event runs it for the first time the host sets `wg_replaying` to 0: the import is called and
+//! its result is passed to `wasm_guardian.record_result_{type}(recorded_index, value)` so the
+//! host can send it along with the event. In every other run, including rollbacks and other
+//! peers, `wg_replaying` is 1: the import isn't called and its result comes from
+//! `wasm_guardian.replay_result_{type}(recorded_index)` instead.
+//!
+//! Imports without a result are skipped while replaying.
+
+use std::collections::HashMap;
+
+use walrus::{InitExpr, ValType};
+
+use crate::dispatch::wildcard_match;
+use crate::imports::replace_imported_func;
+use crate::manifest::type_name;
+use crate::TransformError;
+
+pub const REPLAYING_GLOBAL: &str = "wg_replaying";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedImport {
+    pub module: String,
+    pub name: String,
+    pub result: Option<ValType>,
+}
+
+/// Records every function import whose `module.name` matches one of `patterns`, which may use
+/// `*` wildcards. The position of each import in the returned list is its `recorded_index`.
+///
+/// Fails if a matching import has more than one result, or a result that isn't a number.
+pub(crate) fn record_imports(
+    module: &mut walrus::Module,
+    patterns: &[String],
+) -> Result<Vec<RecordedImport>, TransformError> {
+    // Checked before anything is added to the module.
+    let matching = matching_imports(module, patterns)
+        .into_iter()
+        .map(|(function, import_module, name)| {
+            let result = result_type(module, function, &import_module, &name)?;
+            Ok((function, import_module, name, result))
+        })
+        .collect::<Result<Vec<_>, TransformError>>()?;
+    if matching.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let replaying = module.globals.add_local(
+        ValType::I32,
+        true,
+        InitExpr::Value(walrus::ir::Value::I32(1)),
+    );
+    module.exports.add(REPLAYING_GLOBAL, replaying);
+
+    let mut host_functions: HashMap<ValType, (walrus::FunctionId, walrus::FunctionId)> =
+        HashMap::new();
+    let mut recorded = Vec::new();
+    for (recorded_index, (function, import_module, name, result)) in
+        matching.into_iter().enumerate()
+    {
+        let ty_id = module.funcs.get(function).ty();
+
+        let host = result.map(|result| {
+            *host_functions.entry(result).or_insert_with(|| {
+                let record = module.types.add(&[ValType::I32, result], &[]);
+                let replay = module.types.add(&[ValType::I32], &[result]);
+                let suffix = type_name(result);
+                let (record, _) = module.add_import_func(
+                    "wasm_guardian",
+                    &format!("record_result_{}", suffix),
+                    record,
+                );
+                let (replay, _) = module.add_import_func(
+                    "wasm_guardian",
+                    &format!("replay_result_{}", suffix),
+                    replay,
+                );
+                (record, replay)
+            })
+        });
+        let result_local = result.map(|result| module.locals.add(result));
+
+        // The original import is replaced by a new import with the same name
+        // that only the generated function calls.
+        let (original, _) = module.add_import_func(&import_module, &name, ty_id);
+        let recorded_index = recorded_index as i32;
+
+        replace_imported_func(module, function, |body, args| {
+            body.global_get(replaying).if_else(
+                result,
+                |then| {
+                    if let Some((_, replay)) = host {
+                        then.i32_const(recorded_index).call(replay);
+                    }
+                },
+                |otherwise| {
+                    for arg in args {
+                        otherwise.local_get(*arg);
+                    }
+                    otherwise.call(original);
+                    if let (Some((record, _)), Some(value)) = (host, result_local) {
+                        otherwise
+                            .local_set(value)
+                            .i32_const(recorded_index)
+                            .local_get(value)
+                            .call(record)
+                            .local_get(value);
+                    }
+                },
+            );
+        });
+
+        recorded.push(RecordedImport {
+            module: import_module,
+            name,
+            result,
+        });
+    }
+    Ok(recorded)
+}
+
+/// The imports that [record_imports] recorded in a module it has already transformed, which
+/// still imports each recorded function under its original name for the generated one to call.
+pub(crate) fn recorded_imports(
+    module: &walrus::Module,
+    patterns: &[String],
+) -> Result<Vec<RecordedImport>, TransformError> {
+    matching_imports(module, patterns)
+        .into_iter()
+        .map(|(function, import_module, name)| {
+            Ok(RecordedImport {
+                result: result_type(module, function, &import_module, &name)?,
+                module: import_module,
+                name,
+            })
+        })
+        .collect()
+}
+
+/// Function imports whose `module.name` matches one of `patterns`, in import order.
+///
+/// `wasm_guardian` imports are the host's hooks, including the ones this transform adds, so
+/// they're never recorded.
+fn matching_imports(
+    module: &walrus::Module,
+    patterns: &[String],
+) -> Vec<(walrus::FunctionId, String, String)> {
+    module
+        .imports
+        .iter()
+        .filter(|import| import.module != "wasm_guardian")
+        .filter_map(|import| match import.kind {
+            walrus::ImportKind::Function(function) => {
+                let full_name = format!("{}.{}", import.module, import.name);
+                patterns
+                    .iter()
+                    .any(|pattern| wildcard_match(pattern, &full_name))
+                    .then(|| (function, import.module.clone(), import.name.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn result_type(
+    module: &walrus::Module,
+    function: walrus::FunctionId,
+    import_module: &str,
+    name: &str,
+) -> Result<Option<ValType>, TransformError> {
+    let ty = module.types.get(module.funcs.get(function).ty());
+    match ty.results() {
+        [] => Ok(None),
+        [result @ (ValType::I32 | ValType::I64 | ValType::F32 | ValType::F64)] => Ok(Some(*result)),
+        _ => Err(TransformError::UnsupportedImport {
+            module: import_module.to_string(),
+            name: name.to_string(),
+            reason: "it can't be recorded because it doesn't return a single number".to_string(),
+        }),
+    }
+}
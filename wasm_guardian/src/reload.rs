@@ -0,0 +1,332 @@
+//! Compares two versions of a module to decide if a running instance's state can be carried
+//! over to the new version, so that a room can switch builds without restarting.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use walrus::{ActiveDataLocation, DataKind, ExportItem, GlobalKind, InitExpr};
+
+use crate::bake::{remove_active_data, remove_initializers, INITIALIZER_EXPORTS};
+use crate::binary;
+use crate::globals::{global_indices, GlobalName};
+use crate::manifest::write_json_string;
+use crate::provenance::{self, Provenance};
+use crate::{transform_wasm, TransformError};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Incompatibility {
+    /// Calls to the export in the event history couldn't be replayed.
+    ExportRemoved(String),
+    /// Calls to the export in the event history couldn't be replayed.
+    ExportSignatureChanged(String),
+    /// The old global's value would be lost. Indices are in the old module.
+    GlobalRemoved(u32),
+    GlobalTypeChanged {
+        old_index: u32,
+        new_index: u32,
+    },
+    MemoryRemoved,
+    /// The new memory's maximum is smaller than the running memory's size.
+    MemoryTooSmall,
+    SharedMemoryChanged,
+    /// Initial memory contents differ, so the running memory holds the old module's data.
+    DataSegmentsChanged,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReloadError {
+    /// The old module failed to parse.
+    InvalidOldModule,
+    /// The new module failed to parse.
+    InvalidNewModule,
+    /// The old module was transformed, and the new module couldn't be transformed with the same
+    /// options.
+    Transform(TransformError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReloadReport {
+    /// Reasons the old module's state can't be carried over. Empty if it can.
+    pub incompatibilities: Vec<Incompatibility>,
+    /// `(old global index, new global index)` for each of the module's own mutable globals,
+    /// whose values carry over. Globals are matched by the name in their [GlobalName] export or
+    /// the name section, or by index if they're unnamed. The globals the transform adds, like
+    /// `wg_caller_id`, aren't included.
+    pub globals: Vec<(u32, u32)>,
+    /// `(old export index, new export index)` for each export in both modules, matched by name.
+    /// [INITIALIZER_EXPORTS] aren't included because they do nothing in the returned module, and
+    /// [GlobalName] exports are in `globals` instead.
+    pub exports: Vec<(u32, u32)>,
+}
+
+pub struct ReloadOutput {
+    pub report: ReloadReport,
+    /// The new module, transformed with the old module's options if the old module was
+    /// transformed and the new one wasn't, without active data segments for memory 0 or a start function, and with
+    /// [INITIALIZER_EXPORTS] that do nothing, so that instantiating it doesn't overwrite the
+    /// carried over state.
+    pub wasm: Vec<u8>,
+}
+
+impl ReloadReport {
+    pub fn is_compatible(&self) -> bool {
+        self.incompatibilities.is_empty()
+    }
+
+    /// Serializes the report as JSON for the host.
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"compatible\":{},\"incompatibilities\":[",
+            self.is_compatible()
+        );
+        for (i, incompatibility) in self.incompatibilities.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = match incompatibility {
+                Incompatibility::ExportRemoved(name) => {
+                    json.push_str("{\"kind\":\"export_removed\",\"name\":");
+                    write_json_string(&mut json, name);
+                    write!(json, "}}")
+                }
+                Incompatibility::ExportSignatureChanged(name) => {
+                    json.push_str("{\"kind\":\"export_signature_changed\",\"name\":");
+                    write_json_string(&mut json, name);
+                    write!(json, "}}")
+                }
+                Incompatibility::GlobalRemoved(index) => write!(
+                    json,
+                    "{{\"kind\":\"global_removed\",\"old_index\":{}}}",
+                    index
+                ),
+                Incompatibility::GlobalTypeChanged {
+                    old_index,
+                    new_index,
+                } => write!(
+                    json,
+                    "{{\"kind\":\"global_type_changed\",\"old_index\":{},\"new_index\":{}}}",
+                    old_index, new_index
+                ),
+                Incompatibility::MemoryRemoved => write!(json, "{{\"kind\":\"memory_removed\"}}"),
+                Incompatibility::MemoryTooSmall => {
+                    write!(json, "{{\"kind\":\"memory_too_small\"}}")
+                }
+                Incompatibility::SharedMemoryChanged => {
+                    write!(json, "{{\"kind\":\"shared_memory_changed\"}}")
+                }
+                Incompatibility::DataSegmentsChanged => {
+                    write!(json, "{{\"kind\":\"data_segments_changed\"}}")
+                }
+            };
+        }
+        json.push_str("],\"globals\":");
+        write_json_pairs(&mut json, &self.globals);
+        json.push_str(",\"exports\":");
+        write_json_pairs(&mut json, &self.exports);
+        json.push('}');
+        json
+    }
+}
+
+/// Writes `[old, new]` pairs.
+fn write_json_pairs(json: &mut String, pairs: &[(u32, u32)]) {
+    json.push('[');
+    for (i, (old, new)) in pairs.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(json, "[{},{}]", old, new);
+    }
+    json.push(']');
+}
+
+/// Compares the `old` module a room is running with a `new` version of it.
+///
+/// `memory_pages` is the running instance's memory size in pages, which is larger than the old
+/// module's initial size once the guest has grown its memory.
+///
+/// If the old module was transformed, the new module is transformed with the same options
+/// first, which returns it unchanged if it already was.
+pub fn compare_modules(
+    old: &[u8],
+    new: &[u8],
+    memory_pages: u32,
+) -> Result<ReloadOutput, ReloadError> {
+    let old_module = walrus::Module::from_buffer(old).map_err(|_| ReloadError::InvalidOldModule)?;
+    let new = match Provenance::read(old) {
+        Some(provenance) => match transform_wasm(new, &provenance.options) {
+            Ok(output) => output.wasm,
+            Err(TransformError::InvalidModule) => return Err(ReloadError::InvalidNewModule),
+            Err(e) => return Err(ReloadError::Transform(e)),
+        },
+        None => new.to_vec(),
+    };
+    let new = new.as_slice();
+    let mut new_module =
+        walrus::Module::from_buffer(new).map_err(|_| ReloadError::InvalidNewModule)?;
+
+    let mut incompatibilities = Vec::new();
+
+    // The initializers have already run.
+    remove_initializers(&mut new_module);
+
+    // Exports
+    let new_exports: HashMap<&str, (u32, ExportItem)> = new_module
+        .exports
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (e.name.as_str(), (i as u32, e.item)))
+        .collect();
+    let mut exports = Vec::new();
+    for (old_index, export) in old_module.exports.iter().enumerate() {
+        // Globals are matched below.
+        if INITIALIZER_EXPORTS.contains(&export.name.as_str())
+            || GlobalName::parse(&export.name).is_some()
+        {
+            continue;
+        }
+        let Some((new_index, new_item)) = new_exports.get(export.name.as_str()) else {
+            incompatibilities.push(Incompatibility::ExportRemoved(export.name.clone()));
+            continue;
+        };
+        if let (ExportItem::Function(old_function), ExportItem::Function(new_function)) =
+            (export.item, *new_item)
+        {
+            let old_ty = old_module
+                .types
+                .get(old_module.funcs.get(old_function).ty());
+            let new_ty = new_module
+                .types
+                .get(new_module.funcs.get(new_function).ty());
+            if old_ty.params() != new_ty.params() || old_ty.results() != new_ty.results() {
+                incompatibilities
+                    .push(Incompatibility::ExportSignatureChanged(export.name.clone()));
+            }
+        }
+        exports.push((old_index as u32, *new_index));
+    }
+
+    // Globals
+    let old_globals = state_globals(&old_module, old);
+    let new_globals = state_globals(&new_module, new);
+    let mut globals = Vec::new();
+    for old_global in &old_globals {
+        let matching = new_globals
+            .iter()
+            .find(|new_global| old_global.name.matches(&new_global.name));
+        match matching {
+            None => incompatibilities.push(Incompatibility::GlobalRemoved(old_global.index)),
+            Some(new_global) if new_global.ty != old_global.ty => {
+                incompatibilities.push(Incompatibility::GlobalTypeChanged {
+                    old_index: old_global.index,
+                    new_index: new_global.index,
+                })
+            }
+            Some(new_global) => globals.push((old_global.index, new_global.index)),
+        }
+    }
+
+    // Memory
+    if let Some(old_memory) = old_module.memories.iter().next() {
+        match new_module.memories.iter().next() {
+            None => incompatibilities.push(Incompatibility::MemoryRemoved),
+            Some(new_memory) => {
+                if new_memory
+                    .maximum
+                    .is_some_and(|maximum| maximum < memory_pages)
+                {
+                    incompatibilities.push(Incompatibility::MemoryTooSmall);
+                }
+                if new_memory.shared != old_memory.shared {
+                    incompatibilities.push(Incompatibility::SharedMemoryChanged);
+                }
+            }
+        }
+    }
+    if active_data_segments(&old_module) != active_data_segments(&new_module) {
+        incompatibilities.push(Incompatibility::DataSegmentsChanged);
+    }
+
+    // The carried over memory already holds the data segments' contents.
+    let memory = new_module.memories.iter().next().map(|m| m.id());
+    if let Some(memory) = memory {
+        remove_active_data(&mut new_module, memory);
+    }
+
+    Ok(ReloadOutput {
+        report: ReloadReport {
+            incompatibilities,
+            globals,
+            exports,
+        },
+        wasm: new_module.emit_wasm(),
+    })
+}
+
+struct StateGlobal {
+    /// Index in the module being compared.
+    index: u32,
+    name: GlobalName,
+    ty: walrus::ValType,
+}
+
+/// The module's own mutable globals, which `export_globals` exports.
+///
+/// A transformed module's globals are only known by their [GlobalName] exports: its name
+/// section has no global names, and its own globals can't be told apart from the ones the
+/// transform added otherwise. Without `export_globals` none of them can be read to carry them
+/// over, so none are returned. Other modules' globals are named from the name section.
+fn state_globals(module: &walrus::Module, bytes: &[u8]) -> Vec<StateGlobal> {
+    let indices = global_indices(module);
+    if provenance::has_section(bytes) {
+        return module
+            .exports
+            .iter()
+            .filter_map(|export| {
+                let ExportItem::Global(global) = export.item else {
+                    return None;
+                };
+                Some(StateGlobal {
+                    index: indices[&global],
+                    name: GlobalName::parse(&export.name)?,
+                    ty: module.globals.get(global).ty,
+                })
+            })
+            .collect();
+    }
+    let names = binary::global_names(bytes);
+    module
+        .globals
+        .iter()
+        .filter(|g| g.mutable && matches!(g.kind, GlobalKind::Local(InitExpr::Value(..))))
+        .map(|g| {
+            let index = indices[&g.id()];
+            StateGlobal {
+                index,
+                name: GlobalName {
+                    index,
+                    name: names.get(&index).cloned(),
+                },
+                ty: g.ty,
+            }
+        })
+        .collect()
+}
+
+/// Active data segments with a constant offset, as `(offset, contents)`.
+fn active_data_segments(module: &walrus::Module) -> Vec<(u32, &[u8])> {
+    let mut segments: Vec<_> = module
+        .data
+        .iter()
+        .filter_map(|data| match &data.kind {
+            DataKind::Active(active) => match active.location {
+                ActiveDataLocation::Absolute(offset) => Some((offset, data.value.as_slice())),
+                // Offsets from imported globals can't be compared.
+                ActiveDataLocation::Relative(_) => Some((u32::MAX, data.value.as_slice())),
+            },
+            DataKind::Passive => None,
+        })
+        .collect();
+    segments.sort();
+    segments
+}
@@ -0,0 +1,172 @@
+//! Counts what the transform instrumented, to show where its overhead comes from.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use walrus::ir::{Instr, LoadKind, LoadSimdKind, StoreKind};
+
+use crate::binary::sections;
+use crate::manifest::write_json_string;
+use crate::TransformOptions;
+
+const CODE_SECTION_ID: u8 = 10;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InstrumentationStatistics {
+    /// Size of the code section in bytes, before and after the transform.
+    pub code_size_before: u32,
+    pub code_size_after: u32,
+    /// Functions with any instrumentation, most instrumented first.
+    pub functions: Vec<FunctionStatistics>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionStatistics {
+    /// The function's index in the original module. `None` for functions the transform added.
+    pub index: Option<u32>,
+    /// The function's name from the name section.
+    pub name: Option<String>,
+    /// How many notifications the function has, by the name of the instruction they're for,
+    /// like `i32.store8`, `i32.load` or `memory.copy`. Accesses that another notification
+    /// covers aren't counted, and a loop's range is counted as its first access.
+    pub instructions: BTreeMap<&'static str, u32>,
+}
+
+impl FunctionStatistics {
+    pub fn total(&self) -> u32 {
+        self.instructions.values().sum()
+    }
+}
+
+impl InstrumentationStatistics {
+    /// Totals for each instruction across every function.
+    pub fn totals(&self) -> BTreeMap<&'static str, u32> {
+        let mut totals = BTreeMap::new();
+        for function in &self.functions {
+            for (name, count) in &function.instructions {
+                *totals.entry(*name).or_default() += count;
+            }
+        }
+        totals
+    }
+
+    pub(crate) fn add_function(&mut self, function: FunctionStatistics) {
+        if function.total() > 0 {
+            self.functions.push(function);
+        }
+    }
+
+    /// Sorts functions and records code sizes once the module is emitted.
+    pub(crate) fn finish(&mut self, before: &[u8], after: &[u8]) {
+        self.code_size_before = code_size(before);
+        self.code_size_after = code_size(after);
+        self.functions
+            .sort_by_key(|function| std::cmp::Reverse(function.total()));
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"code_size_before\":{},\"code_size_after\":{},\"totals\":",
+            self.code_size_before, self.code_size_after
+        );
+        write_json_counts(&mut json, &self.totals());
+        json.push_str(",\"functions\":[");
+        for (i, function) in self.functions.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"index\":");
+            match function.index {
+                Some(index) => {
+                    let _ = write!(json, "{}", index);
+                }
+                None => json.push_str("null"),
+            }
+            json.push_str(",\"name\":");
+            match &function.name {
+                Some(name) => write_json_string(&mut json, name),
+                None => json.push_str("null"),
+            }
+            let _ = write!(json, ",\"total\":{},\"instructions\":", function.total());
+            write_json_counts(&mut json, &function.instructions);
+            json.push('}');
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+/// The name of an instruction the transform instruments with `options`, or `None` for other
+/// instructions.
+pub(crate) fn instrumented_name(instr: &Instr, options: &TransformOptions) -> Option<&'static str> {
+    let changes = options.track_changes;
+    let reads = options.track_reads;
+    Some(match instr {
+        Instr::Store(store) if changes => match store.kind {
+            StoreKind::I32 { .. } => "i32.store",
+            StoreKind::I64 { .. } => "i64.store",
+            StoreKind::F32 => "f32.store",
+            StoreKind::F64 => "f64.store",
+            StoreKind::V128 => "v128.store",
+            StoreKind::I32_8 { .. } => "i32.store8",
+            StoreKind::I32_16 { .. } => "i32.store16",
+            StoreKind::I64_8 { .. } => "i64.store8",
+            StoreKind::I64_16 { .. } => "i64.store16",
+            StoreKind::I64_32 { .. } => "i64.store32",
+        },
+        Instr::MemoryCopy(_) if changes || reads => "memory.copy",
+        Instr::MemoryFill(_) if changes => "memory.fill",
+        Instr::MemoryInit(_) if changes => "memory.init",
+        Instr::MemoryGrow(_) if changes => "memory.grow",
+        Instr::GlobalSet(_) if changes => "global.set",
+        Instr::Load(load) if reads => match load.kind {
+            LoadKind::I32 { .. } => "i32.load",
+            LoadKind::I64 { .. } => "i64.load",
+            LoadKind::F32 => "f32.load",
+            LoadKind::F64 => "f64.load",
+            LoadKind::V128 => "v128.load",
+            LoadKind::I32_8 { .. } => "i32.load8",
+            LoadKind::I32_16 { .. } => "i32.load16",
+            LoadKind::I64_8 { .. } => "i64.load8",
+            LoadKind::I64_16 { .. } => "i64.load16",
+            LoadKind::I64_32 { .. } => "i64.load32",
+        },
+        Instr::LoadSimd(load) if reads => match load.kind {
+            LoadSimdKind::Splat8 => "v128.load8_splat",
+            LoadSimdKind::Splat16 => "v128.load16_splat",
+            LoadSimdKind::Splat32 => "v128.load32_splat",
+            LoadSimdKind::Splat64 => "v128.load64_splat",
+            LoadSimdKind::V128Load8x8S | LoadSimdKind::V128Load8x8U => "v128.load8x8",
+            LoadSimdKind::V128Load16x4S | LoadSimdKind::V128Load16x4U => "v128.load16x4",
+            LoadSimdKind::V128Load32x2S | LoadSimdKind::V128Load32x2U => "v128.load32x2",
+            LoadSimdKind::V128Load32Zero => "v128.load32_zero",
+            LoadSimdKind::V128Load64Zero => "v128.load64_zero",
+            LoadSimdKind::V128Load8Lane(_) => "v128.load8_lane",
+            LoadSimdKind::V128Load16Lane(_) => "v128.load16_lane",
+            LoadSimdKind::V128Load32Lane(_) => "v128.load32_lane",
+            LoadSimdKind::V128Load64Lane(_) => "v128.load64_lane",
+            LoadSimdKind::V128Store8Lane(_)
+            | LoadSimdKind::V128Store16Lane(_)
+            | LoadSimdKind::V128Store32Lane(_)
+            | LoadSimdKind::V128Store64Lane(_) => return None,
+        },
+        _ => return None,
+    })
+}
+
+fn code_size(bytes: &[u8]) -> u32 {
+    sections(bytes)
+        .find(|section| section.id == CODE_SECTION_ID)
+        .map_or(0, |section| section.data.len() as u32)
+}
+
+fn write_json_counts(json: &mut String, counts: &BTreeMap<&'static str, u32>) {
+    json.push('{');
+    for (i, (name, count)) in counts.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(json, "\"{}\":{}", name, count);
+    }
+    json.push('}');
+}
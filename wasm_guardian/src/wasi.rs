@@ -0,0 +1,242 @@
+//! Implements WASI preview1 imports inside the module so that programs built for `wasm32-wasi`
+//! behave the same on every peer.
+//!
+//! * `fd_write` to stdout or stderr calls the host's `env.external_log(ptr, len)` once per buffer.
+//! * Clocks return the time of the current event, from the invocation context.
+//! * `random_get` is replaced by the generator in [crate::random].
+//! * There are no arguments or environment variables.
+//! * `proc_exit` traps.
+//! * Every other `fd_` and `path_` function fails with `ERRNO_BADF`, so there's no filesystem,
+//!   and anything else fails with `ERRNO_NOSYS`.
+
+use walrus::ir::{BinaryOp, LoadKind, MemArg, StoreKind, UnaryOp};
+use walrus::{FunctionId, InstrSeqBuilder, LocalId, MemoryId, ValType};
+
+use crate::context::InvocationContext;
+use crate::imports::replace_imported_func;
+use crate::TransformError;
+
+pub const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+pub const ERRNO_SUCCESS: i32 = 0;
+pub const ERRNO_BADF: i32 = 8;
+pub const ERRNO_NOSYS: i32 = 52;
+
+const STDOUT: i32 = 1;
+const STDERR: i32 = 2;
+
+/// Replaces every function imported from [WASI_MODULE].
+///
+/// Fails if the module imports a WASI function with the wrong type, or imports WASI functions
+/// that need memory without having any.
+pub(crate) fn replace_wasi_imports(
+    module: &mut walrus::Module,
+    context: InvocationContext,
+) -> Result<(), TransformError> {
+    let wasi_imports: Vec<(FunctionId, String)> = module
+        .imports
+        .iter()
+        .filter(|import| import.module == WASI_MODULE)
+        .filter_map(|import| match import.kind {
+            walrus::ImportKind::Function(function) => Some((function, import.name.clone())),
+            _ => None,
+        })
+        .collect();
+    if wasi_imports.is_empty() {
+        return Ok(());
+    }
+
+    let memory = module.memories.iter().next().map(|m| m.id());
+
+    for (function, name) in wasi_imports {
+        let ty = module.types.get(module.funcs.get(function).ty());
+        let (params, results) = (ty.params().to_vec(), ty.results().to_vec());
+        let unsupported = |reason: &str| TransformError::UnsupportedImport {
+            module: WASI_MODULE.to_string(),
+            name: name.clone(),
+            reason: reason.to_string(),
+        };
+        let expect_type = |expected_params: &[ValType], expected_results: &[ValType]| {
+            if params == expected_params && results == expected_results {
+                Ok(())
+            } else {
+                Err(unsupported("it has the wrong type"))
+            }
+        };
+        let memory = || memory.ok_or_else(|| unsupported("the module has no memory"));
+
+        use ValType::{I32, I64};
+        match name.as_str() {
+            "fd_write" => {
+                expect_type(&[I32, I32, I32, I32], &[I32])?;
+                let log = external_log(module)?;
+                let memory = memory()?;
+                let locals = [(); 3].map(|_| module.locals.add(I32));
+                replace_imported_func(module, function, |body, args| {
+                    fd_write(body, memory, log, args, locals);
+                });
+            }
+            "clock_time_get" => {
+                expect_type(&[I32, I64, I32], &[I32])?;
+                let memory = memory()?;
+                replace_imported_func(module, function, |body, args| {
+                    // Milliseconds to nanoseconds.
+                    body.local_get(args[2])
+                        .global_get(context.event_time)
+                        .f64_const(1_000_000.0)
+                        .binop(BinaryOp::F64Mul)
+                        .unop(UnaryOp::I64TruncSSatF64)
+                        .store(memory, StoreKind::I64 { atomic: false }, mem_arg(0))
+                        .i32_const(ERRNO_SUCCESS);
+                });
+            }
+            "clock_res_get" => {
+                expect_type(&[I32, I32], &[I32])?;
+                let memory = memory()?;
+                replace_imported_func(module, function, |body, args| {
+                    body.local_get(args[1])
+                        .i64_const(1_000_000)
+                        .store(memory, StoreKind::I64 { atomic: false }, mem_arg(0))
+                        .i32_const(ERRNO_SUCCESS);
+                });
+            }
+            "args_sizes_get" | "environ_sizes_get" => {
+                expect_type(&[I32, I32], &[I32])?;
+                let memory = memory()?;
+                replace_imported_func(module, function, |body, args| {
+                    for arg in args {
+                        body.local_get(*arg).i32_const(0).store(
+                            memory,
+                            StoreKind::I32 { atomic: false },
+                            mem_arg(0),
+                        );
+                    }
+                    body.i32_const(ERRNO_SUCCESS);
+                });
+            }
+            "args_get" | "environ_get" | "sched_yield" => {
+                if name == "sched_yield" {
+                    expect_type(&[], &[I32])?;
+                } else {
+                    expect_type(&[I32, I32], &[I32])?;
+                }
+                replace_imported_func(module, function, |body, _| {
+                    body.i32_const(ERRNO_SUCCESS);
+                });
+            }
+            "proc_exit" => {
+                expect_type(&[I32], &[])?;
+                replace_imported_func(module, function, |body, _| {
+                    body.unreachable();
+                });
+            }
+            _ => {
+                expect_type(&params, &[I32])?;
+                let errno = if name.starts_with("fd_") || name.starts_with("path_") {
+                    ERRNO_BADF
+                } else {
+                    ERRNO_NOSYS
+                };
+                replace_imported_func(module, function, |body, _| {
+                    body.i32_const(errno);
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `fd_write(fd, iovs, iovs_len, nwritten) -> errno`
+fn fd_write(
+    body: &mut InstrSeqBuilder,
+    memory: MemoryId,
+    log: FunctionId,
+    args: &[LocalId],
+    [index, iov, total]: [LocalId; 3],
+) {
+    let (fd, iovs, iovs_len, nwritten) = (args[0], args[1], args[2], args[3]);
+    let load = LoadKind::I32 { atomic: false };
+
+    body.local_get(fd)
+        .i32_const(STDOUT)
+        .binop(BinaryOp::I32Ne)
+        .local_get(fd)
+        .i32_const(STDERR)
+        .binop(BinaryOp::I32Ne)
+        .binop(BinaryOp::I32And)
+        .if_else(
+            None,
+            |then| {
+                then.i32_const(ERRNO_BADF).return_();
+            },
+            |_| {},
+        );
+
+    // Each iovec is a pointer and length pair.
+    body.i32_const(0)
+        .local_set(total)
+        .i32_const(0)
+        .local_set(index)
+        .block(None, |done| {
+            let done_id = done.id();
+            done.loop_(None, |repeat| {
+                let repeat_id = repeat.id();
+                repeat
+                    .local_get(index)
+                    .local_get(iovs_len)
+                    .binop(BinaryOp::I32GeU)
+                    .br_if(done_id)
+                    .local_get(iovs)
+                    .local_get(index)
+                    .i32_const(8)
+                    .binop(BinaryOp::I32Mul)
+                    .binop(BinaryOp::I32Add)
+                    .local_set(iov)
+                    .local_get(iov)
+                    .load(memory, load, mem_arg(0))
+                    .local_get(iov)
+                    .load(memory, load, mem_arg(4))
+                    .call(log)
+                    .local_get(total)
+                    .local_get(iov)
+                    .load(memory, load, mem_arg(4))
+                    .binop(BinaryOp::I32Add)
+                    .local_set(total)
+                    .local_get(index)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add)
+                    .local_set(index)
+                    .br(repeat_id);
+            });
+        })
+        .local_get(nwritten)
+        .local_get(total)
+        .store(memory, StoreKind::I32 { atomic: false }, mem_arg(0))
+        .i32_const(ERRNO_SUCCESS);
+}
+
+/// Finds or imports `env.external_log(ptr: i32, len: i32)`, the host's log hook.
+///
+/// Fails if the module already imports it as something else.
+fn external_log(module: &mut walrus::Module) -> Result<FunctionId, TransformError> {
+    let params = [ValType::I32, ValType::I32];
+    let Some(import) = module.imports.find("env", "external_log") else {
+        let ty = module.types.add(&params, &[]);
+        return Ok(module.add_import_func("env", "external_log", ty).0);
+    };
+    if let walrus::ImportKind::Function(function) = module.imports.get(import).kind {
+        let ty = module.types.get(module.funcs.get(function).ty());
+        if ty.params() == params && ty.results().is_empty() {
+            return Ok(function);
+        }
+    }
+    Err(TransformError::UnsupportedImport {
+        module: "env".to_string(),
+        name: "external_log".to_string(),
+        reason: "`fd_write` calls it, but it isn't a `(i32, i32)` function".to_string(),
+    })
+}
+
+fn mem_arg(offset: u32) -> MemArg {
+    MemArg { align: 1, offset }
+}
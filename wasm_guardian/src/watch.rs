@@ -0,0 +1,317 @@
+//! Data watchpoints, for finding which call wrote a value that diverged between peers.
+//!
+//! The host fills a table of [WATCHPOINT_SLOTS] address ranges by calling
+//! `wg_watch(slot: i32, address: i32, size: i32)`, where a size of 0 clears the slot. Every
+//! store, `memory.fill`, `memory.copy` and `memory.init` that overlaps a watched range then
+//! calls `wasm_guardian.on_watch_hit(func_index: i32, address: i32, size: i32, value: i64)`
+//! before it writes.
+//!
+//! `func_index` is the writing function's index in the original module, or -1 for functions
+//! the transform generated. `value` is the stored value's bits zero-extended, which for a
+//! narrowing store like `i32.store8` includes bits that aren't written. For `v128.store` it's
+//! the low 8 bytes, for `memory.fill` the fill byte, and for `memory.copy` and `memory.init`
+//! it's 0.
+
+use std::collections::HashMap;
+
+use walrus::ir::{BinaryOp, Instr, LoadSimdKind, StoreKind, UnaryOp, Value};
+use walrus::{FunctionBuilder, FunctionId, GlobalId, InitExpr, LocalId, ValType};
+
+pub const WATCH_EXPORT_NAME: &str = "wg_watch";
+/// How many ranges can be watched at once.
+pub const WATCHPOINT_SLOTS: u32 = 8;
+
+/// Checks stores to the module's first memory against the watched ranges.
+pub(crate) fn add_watchpoints(
+    module: &mut walrus::Module,
+    original_function_indices: &HashMap<FunctionId, u32>,
+) {
+    let slots: Vec<(GlobalId, GlobalId)> = (0..WATCHPOINT_SLOTS)
+        .map(|_| {
+            let mut add = || {
+                module
+                    .globals
+                    .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)))
+            };
+            (add(), add())
+        })
+        .collect();
+    add_watch_export(module, &slots);
+    let check = add_check(module, &slots);
+
+    let Some(memory) = module.memories.iter().next().map(|memory| memory.id()) else {
+        return;
+    };
+    let address = module.locals.add(ValType::I32);
+    // Store values, by type.
+    let mut locals = HashMap::new();
+    for ty in [
+        ValType::I32,
+        ValType::I64,
+        ValType::F32,
+        ValType::F64,
+        ValType::V128,
+    ] {
+        locals.insert(ty, module.locals.add(ty));
+    }
+    // The other arguments of bulk memory instructions.
+    let fill_value = module.locals.add(ValType::I32);
+    let fill_size = module.locals.add(ValType::I32);
+
+    let mut new_instructions = Vec::new();
+    let mut blocks = Vec::new();
+    for (id, function) in module.funcs.iter_local_mut() {
+        if id == check {
+            continue;
+        }
+        let func_index = original_function_indices
+            .get(&id)
+            .map_or(-1, |index| *index as i32);
+        blocks.clear();
+        blocks.push(function.entry_block());
+        let mut visitor = crate::AllBlocks {
+            blocks: &mut blocks,
+        };
+        walrus::ir::dfs_in_order(&mut visitor, function, function.entry_block());
+
+        for block in &blocks {
+            let instructions = &mut function.block_mut(*block).instrs;
+            new_instructions.clear();
+            new_instructions.reserve(instructions.len());
+            for instruction in instructions.iter() {
+                let loc = instruction.1;
+                let mut push = |instr: Instr| new_instructions.push((instr, loc));
+                match &instruction.0 {
+                    Instr::MemoryFill(walrus::ir::MemoryFill { memory: target })
+                    | Instr::MemoryCopy(walrus::ir::MemoryCopy { dst: target, .. })
+                    | Instr::MemoryInit(walrus::ir::MemoryInit { memory: target, .. })
+                        if *target == memory =>
+                    {
+                        push(local_set(fill_size));
+                        push(local_set(fill_value));
+                        push(local_set(address));
+                        push(const_i32(func_index));
+                        push(local_get(address));
+                        push(local_get(fill_size));
+                        if let Instr::MemoryFill(_) = instruction.0 {
+                            push(local_get(fill_value));
+                            push(Instr::Unop(walrus::ir::Unop {
+                                op: UnaryOp::I64ExtendUI32,
+                            }));
+                        } else {
+                            push(Instr::Const(walrus::ir::Const {
+                                value: Value::I64(0),
+                            }));
+                        }
+                        push(Instr::Call(walrus::ir::Call { func: check }));
+                        push(local_get(address));
+                        push(local_get(fill_value));
+                        push(local_get(fill_size));
+                    }
+                    Instr::Store(store) if store.memory == memory => {
+                        let (ty, to_i64) = store_value(store.kind);
+                        push_check(
+                            &mut push,
+                            (address, locals[&ty]),
+                            (func_index, store.arg.offset, store.kind.width()),
+                            to_i64,
+                            check,
+                        );
+                    }
+                    Instr::LoadSimd(store) if store.memory == memory => {
+                        if let Some((width, to_i64)) = lane_store_value(store.kind) {
+                            push_check(
+                                &mut push,
+                                (address, locals[&ValType::V128]),
+                                (func_index, store.arg.offset, width),
+                                to_i64,
+                                check,
+                            );
+                        }
+                    }
+                    _ => {}
+                }
+                new_instructions.push(instruction.clone());
+            }
+            std::mem::swap(&mut new_instructions, instructions);
+        }
+    }
+}
+
+/// Adds the `wg_watch` export, which traps if the slot doesn't exist.
+fn add_watch_export(module: &mut walrus::Module, slots: &[(GlobalId, GlobalId)]) {
+    let slot = module.locals.add(ValType::I32);
+    let address = module.locals.add(ValType::I32);
+    let size = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(
+        &mut module.types,
+        &[ValType::I32, ValType::I32, ValType::I32],
+        &[],
+    );
+    let mut body = builder.func_body();
+    for (i, (start, length)) in slots.iter().enumerate() {
+        body.local_get(slot)
+            .i32_const(i as i32)
+            .binop(BinaryOp::I32Eq)
+            .if_else(
+                None,
+                |then| {
+                    then.local_get(address)
+                        .global_set(*start)
+                        .local_get(size)
+                        .global_set(*length)
+                        .return_();
+                },
+                |_| {},
+            );
+    }
+    body.unreachable();
+    builder.name(WATCH_EXPORT_NAME.to_string());
+    let watch = builder.finish(vec![slot, address, size], &mut module.funcs);
+    module.exports.add(WATCH_EXPORT_NAME, watch);
+}
+
+/// Adds `(func_index: i32, address: i32, size: i32, value: i64)`, which calls `on_watch_hit`
+/// with its arguments once if the range overlaps any watched range.
+fn add_check(module: &mut walrus::Module, slots: &[(GlobalId, GlobalId)]) -> FunctionId {
+    let hook_type = module.types.add(
+        &[ValType::I32, ValType::I32, ValType::I32, ValType::I64],
+        &[],
+    );
+    let on_watch_hit = module
+        .add_import_func("wasm_guardian", "on_watch_hit", hook_type)
+        .0;
+
+    let func_index = module.locals.add(ValType::I32);
+    let address = module.locals.add(ValType::I32);
+    let size = module.locals.add(ValType::I32);
+    let value = module.locals.add(ValType::I64);
+    let end = module.locals.add(ValType::I64);
+    let mut builder = FunctionBuilder::new(
+        &mut module.types,
+        &[ValType::I32, ValType::I32, ValType::I32, ValType::I64],
+        &[],
+    );
+    let mut body = builder.func_body();
+    // Ends are computed in 64 bits so ranges reaching the top of memory don't wrap.
+    body.local_get(address)
+        .unop(UnaryOp::I64ExtendUI32)
+        .local_get(size)
+        .unop(UnaryOp::I64ExtendUI32)
+        .binop(BinaryOp::I64Add)
+        .local_set(end)
+        // Whether the write isn't empty, and'ed with whether it overlaps each used slot.
+        .local_get(size)
+        .i32_const(0)
+        .binop(BinaryOp::I32Ne)
+        .i32_const(0);
+    for (start, length) in slots {
+        body.global_get(*length)
+            .i32_const(0)
+            .binop(BinaryOp::I32Ne)
+            .local_get(end)
+            .global_get(*start)
+            .unop(UnaryOp::I64ExtendUI32)
+            .binop(BinaryOp::I64GtU)
+            .binop(BinaryOp::I32And)
+            .global_get(*start)
+            .unop(UnaryOp::I64ExtendUI32)
+            .global_get(*length)
+            .unop(UnaryOp::I64ExtendUI32)
+            .binop(BinaryOp::I64Add)
+            .local_get(address)
+            .unop(UnaryOp::I64ExtendUI32)
+            .binop(BinaryOp::I64GtU)
+            .binop(BinaryOp::I32And)
+            .binop(BinaryOp::I32Or);
+    }
+    body.binop(BinaryOp::I32And).if_else(
+        None,
+        |then| {
+            then.local_get(func_index)
+                .local_get(address)
+                .local_get(size)
+                .local_get(value)
+                .call(on_watch_hit);
+        },
+        |_| {},
+    );
+    builder.name("wg_watch_check".to_string());
+    builder.finish(vec![func_index, address, size, value], &mut module.funcs)
+}
+
+/// Spills a store's address and value to `locals`, calls `check` with them and puts them back.
+fn push_check(
+    push: &mut impl FnMut(Instr),
+    (address, value): (LocalId, LocalId),
+    (func_index, offset, width): (i32, u32, u32),
+    to_i64: Vec<UnaryOp>,
+    check: FunctionId,
+) {
+    push(local_set(value));
+    push(local_set(address));
+    push(const_i32(func_index));
+    push(local_get(address));
+    if offset != 0 {
+        push(const_i32(offset as i32));
+        push(Instr::Binop(walrus::ir::Binop {
+            op: BinaryOp::I32Add,
+        }));
+    }
+    push(const_i32(width as i32));
+    push(local_get(value));
+    for op in to_i64 {
+        push(Instr::Unop(walrus::ir::Unop { op }));
+    }
+    push(Instr::Call(walrus::ir::Call { func: check }));
+    push(local_get(address));
+    push(local_get(value));
+}
+
+/// The type of a store's value and the instructions that turn it into an i64.
+fn store_value(kind: StoreKind) -> (ValType, Vec<UnaryOp>) {
+    match kind {
+        StoreKind::I32 { .. } | StoreKind::I32_8 { .. } | StoreKind::I32_16 { .. } => {
+            (ValType::I32, vec![UnaryOp::I64ExtendUI32])
+        }
+        StoreKind::I64 { .. }
+        | StoreKind::I64_8 { .. }
+        | StoreKind::I64_16 { .. }
+        | StoreKind::I64_32 { .. } => (ValType::I64, vec![]),
+        StoreKind::F32 => (
+            ValType::F32,
+            vec![UnaryOp::I32ReinterpretF32, UnaryOp::I64ExtendUI32],
+        ),
+        StoreKind::F64 => (ValType::F64, vec![UnaryOp::I64ReinterpretF64]),
+        StoreKind::V128 => (ValType::V128, vec![UnaryOp::I64x2ExtractLane { idx: 0 }]),
+    }
+}
+
+/// For lane stores, the number of bytes written and the instructions that turn the stored
+/// lane into an i64.
+fn lane_store_value(kind: LoadSimdKind) -> Option<(u32, Vec<UnaryOp>)> {
+    let (width, extract) = match kind {
+        LoadSimdKind::V128Store8Lane(idx) => (1, UnaryOp::I8x16ExtractLaneU { idx }),
+        LoadSimdKind::V128Store16Lane(idx) => (2, UnaryOp::I16x8ExtractLaneU { idx }),
+        LoadSimdKind::V128Store32Lane(idx) => (4, UnaryOp::I32x4ExtractLane { idx }),
+        LoadSimdKind::V128Store64Lane(idx) => {
+            return Some((8, vec![UnaryOp::I64x2ExtractLane { idx }]))
+        }
+        _ => return None,
+    };
+    Some((width, vec![extract, UnaryOp::I64ExtendUI32]))
+}
+
+fn local_get(local: LocalId) -> Instr {
+    Instr::LocalGet(walrus::ir::LocalGet { local })
+}
+
+fn local_set(local: LocalId) -> Instr {
+    Instr::LocalSet(walrus::ir::LocalSet { local })
+}
+
+fn const_i32(value: i32) -> Instr {
+    Instr::Const(walrus::ir::Const {
+        value: Value::I32(value),
+    })
+}
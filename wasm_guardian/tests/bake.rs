@@ -0,0 +1,65 @@
+//! Bakes a snapshot into `bake.wat` and runs the result.
+
+#[path = "differential/harness.rs"]
+mod harness;
+
+use wasm_guardian::{BakeError, Snapshot, SnapshotValue};
+
+#[test]
+fn bake() {
+    let path = format!("{}/tests/fixtures/bake.wat", env!("CARGO_MANIFEST_DIR"));
+    let original = wat::parse_file(&path).unwrap();
+    let mut memory = vec![0; 65536];
+    memory[64] = 7;
+    // Exports are `memory`, `counter`, `scale`, `main`, `get` and `get_scale`.
+    let snapshot = Snapshot {
+        memory,
+        globals: vec![(1, SnapshotValue::F64(20.0)), (2, SnapshotValue::F64(0.5))],
+    };
+    let baked = wasm_guardian::bake_snapshot(&original, &snapshot).unwrap();
+
+    let export_names = |wasm: &[u8]| -> Vec<String> {
+        let module = walrus::Module::from_buffer(wasm).unwrap();
+        module.exports.iter().map(|e| e.name.clone()).collect()
+    };
+    assert_eq!(export_names(&baked), export_names(&original));
+
+    // `main` no longer overwrites the baked counter.
+    let results = harness::call_exports(&baked, &["get", "main", "get", "get_scale"]);
+    assert_eq!(
+        results,
+        [
+            Ok(vec![27]),
+            Ok(vec![]),
+            Ok(vec![27]),
+            Ok(vec![0.5f32.to_bits() as u64])
+        ]
+    );
+
+    let lossy = Snapshot {
+        memory: Vec::new(),
+        globals: vec![(2, SnapshotValue::F64(0.1))],
+    };
+    assert_eq!(
+        wasm_guardian::bake_snapshot(&original, &lossy),
+        Err(BakeError::WrongValueType(2))
+    );
+
+    let unexported = wat::parse_str(
+        r#"(module
+            (global (export "constant") i32 (i32.const 1))
+            (global $hidden (mut i32) (i32.const 0)))"#,
+    )
+    .unwrap();
+    let empty = Snapshot {
+        memory: Vec::new(),
+        globals: Vec::new(),
+    };
+    assert_eq!(
+        wasm_guardian::bake_snapshot(&unexported, &empty),
+        Err(BakeError::UnexportedGlobal(1))
+    );
+    // `export_globals` exports it, so a host's snapshot covers it.
+    let exported = wasm_guardian::transform_wasm_to_track_changes(&unexported, true, false);
+    assert!(wasm_guardian::bake_snapshot(&exported, &empty).is_ok());
+}
@@ -0,0 +1,70 @@
+//! Runs the `wasm-guardian` binary.
+
+use std::process::Command;
+
+use wasm_guardian::TransformOptions;
+
+/// `--tangle` has to give the same module `TimeMachine.setup` would, or Tangle rejects it as
+/// transformed with different options.
+#[test]
+fn tangle_matches_time_machine() {
+    let original = wat::parse_str(
+        r#"(module
+            (import "wasi_snapshot_preview1" "clock_time_get"
+                (func $clock_time_get (param i32 i64 i32) (result i32)))
+            (import "env" "seed" (func $seed (result f64)))
+            (import "env" "now" (func $now (result f64)))
+            (memory (export "memory") 1)
+            (global $counter (mut i32) (i32.const 0))
+            (func (export "main")
+                (drop (call $clock_time_get (i32.const 0) (i64.const 0) (i32.const 0)))
+                (drop (call $seed))
+                (drop (call $now))
+                (global.set $counter (i32.const 1))
+                (i32.store (i32.const 0) (i32.const 2))))"#,
+    )
+    .unwrap();
+    let directory = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("cli_tangle");
+    std::fs::create_dir_all(&directory).unwrap();
+    let input = directory.join("input.wasm");
+    std::fs::write(&input, &original).unwrap();
+
+    let run = |extra: &[&str]| {
+        let output = directory.join("output.wasm");
+        let result = Command::new(env!("CARGO_BIN_EXE_wasm-guardian"))
+            .arg("transform")
+            .arg(&input)
+            .arg("-o")
+            .arg(&output)
+            .arg("--tangle")
+            .args(extra)
+            .output()
+            .unwrap();
+        assert!(result.status.success(), "{:?}", result);
+        std::fs::read(&output).unwrap()
+    };
+    // The options `TimeMachine.setup` passes to `process_binary`.
+    let time_machine = |recorded_imports: Vec<String>| TransformOptions {
+        export_globals: true,
+        track_changes: false,
+        track_reads: false,
+        watchpoints: false,
+        invocation_context: true,
+        deterministic_random: true,
+        wasi: true,
+        recorded_imports,
+        dispatch: None,
+        instrumentation_filter: None,
+    };
+    let expected = |recorded_imports: Vec<String>| {
+        wasm_guardian::transform_wasm(&original, &time_machine(recorded_imports))
+            .unwrap()
+            .wasm
+    };
+
+    assert_eq!(run(&[]), expected(Vec::new()));
+    assert_eq!(
+        run(&["--record-import", "env.now"]),
+        expected(vec!["env.now".to_string()])
+    );
+}
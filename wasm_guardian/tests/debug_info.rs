@@ -0,0 +1,41 @@
+//! Code offsets for source maps, and modules with DWARF sections.
+
+use wasm_guardian::{CodeOffsets, TransformError, TransformOptions};
+
+#[test]
+fn debug_info() {
+    let wasm = wat::parse_str(
+        r#"(module
+            (memory 1)
+            (func (export "a") (i32.store (i32.const 0) (i32.const 1))))"#,
+    )
+    .unwrap();
+    let options = TransformOptions {
+        track_changes: true,
+        ..Default::default()
+    };
+    let code_offsets = wasm_guardian::transform_wasm(&wasm, &options)
+        .unwrap()
+        .code_offsets
+        .unwrap();
+    assert_eq!(
+        CodeOffsets::from_bytes(&code_offsets.to_bytes()),
+        Some(code_offsets)
+    );
+    // Original offsets must be increasing.
+    assert_eq!(CodeOffsets::from_bytes(&[2, 0, 1, 0]), None);
+
+    // walrus only emits DWARF sections if the module was parsed to keep them.
+    let mut with_dwarf = walrus::ModuleConfig::new()
+        .generate_dwarf(true)
+        .parse(&wasm)
+        .unwrap();
+    with_dwarf.customs.add(walrus::RawCustomSection {
+        name: ".debug_info".to_string(),
+        data: vec![0],
+    });
+    assert_eq!(
+        wasm_guardian::transform_wasm(&with_dwarf.emit_wasm(), &options).err(),
+        Some(TransformError::DwarfSection(".debug_info".to_string()))
+    );
+}
@@ -0,0 +1,222 @@
+//! Runs each WAT fixture in `tests/fixtures` through [harness::check_transform].
+//! There's a fixture for each class of instruction the transform instruments, and `watch.wat`
+//! also checks the code generated for watchpoints.
+
+#[path = "differential/harness.rs"]
+mod harness;
+
+use wasm_guardian::TransformOptions;
+
+fn check_fixture(name: &str) {
+    let path = format!("{}/tests/fixtures/{}.wat", env!("CARGO_MANIFEST_DIR"), name);
+    let original = wat::parse_file(&path).unwrap();
+    let options = TransformOptions {
+        export_globals: true,
+        track_changes: true,
+        track_reads: true,
+        ..Default::default()
+    };
+    let transformed = wasm_guardian::transform_wasm(&original, &options)
+        .unwrap()
+        .wasm;
+    harness::check_transform(name, &original, &transformed, None);
+}
+
+#[test]
+fn reads() {
+    check_fixture("reads");
+
+    let path = format!("{}/tests/fixtures/reads.wat", env!("CARGO_MANIFEST_DIR"));
+    let original = wat::parse_file(&path).unwrap();
+    let options = TransformOptions {
+        track_reads: true,
+        ..Default::default()
+    };
+    let transformed = wasm_guardian::transform_wasm(&original, &options)
+        .unwrap()
+        .wasm;
+    let loads = harness::reported_loads(&transformed);
+    let expected: [(&str, &[(u32, u32)]); 5] = [
+        ("load_struct", &[(64, 16)]),
+        ("computed_address", &[(123, 8)]),
+        ("copy", &[(200, 16)]),
+        ("store_only", &[]),
+        ("sum", &[(800, 40)]),
+    ];
+    for (function, expected_loads) in expected {
+        let (_, loads) = loads.iter().find(|(name, _)| name == function).unwrap();
+        assert_eq!(loads, expected_loads, "{}", function);
+    }
+
+    let path = format!(
+        "{}/tests/fixtures/reads_simd.wat",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let original = wat::parse_file(&path).unwrap();
+    let transformed = wasm_guardian::transform_wasm(&original, &options)
+        .unwrap()
+        .wasm;
+    walrus::Module::from_buffer(&transformed).unwrap();
+}
+
+#[test]
+fn watchpoints() {
+    let path = format!("{}/tests/fixtures/watch.wat", env!("CARGO_MANIFEST_DIR"));
+    let original = wat::parse_file(&path).unwrap();
+    let options = TransformOptions {
+        track_changes: true,
+        watchpoints: true,
+        ..Default::default()
+    };
+    let transformed = wasm_guardian::transform_wasm(&original, &options)
+        .unwrap()
+        .wasm;
+    harness::check_transform("watch", &original, &transformed, None);
+
+    let watches = [(0, 100, 4), (3, 511, 1), (5, 300, 4), (5, 300, 0)];
+    let hits = harness::watch_hits(&transformed, &watches);
+    // Functions are reported by their index in the original module, so stores in `$set_field`
+    // are reported as function 0.
+    let expected: [(&str, &[harness::WatchHit]); 7] = [
+        ("store_field", &[(0, 100, 4, 1.5f32.to_bits() as u64)]),
+        ("store_nearby", &[]),
+        ("store_across", &[(3, 98, 8, u64::MAX)]),
+        ("fill", &[(4, 0, 200, 0xab)]),
+        ("empty_fill", &[]),
+        ("narrow_store", &[(6, 510, 2, 0x12345)]),
+        ("cleared", &[]),
+    ];
+    assert_eq!(hits.len(), expected.len());
+    for (function, expected_hits) in expected {
+        let (_, hits) = hits.iter().find(|(name, _)| name == function).unwrap();
+        assert_eq!(hits, expected_hits, "{}", function);
+    }
+}
+
+#[test]
+fn stores() {
+    check_fixture("stores");
+}
+
+#[test]
+fn bulk_memory() {
+    check_fixture("bulk_memory");
+}
+
+#[test]
+fn grow() {
+    check_fixture("grow");
+}
+
+#[test]
+fn globals() {
+    check_fixture("globals");
+}
+
+#[test]
+fn coalesce() {
+    check_fixture("coalesce");
+}
+
+/// Stores through an unchanged pointer share one `on_store` call.
+#[test]
+fn coalesced_store_calls() {
+    let path = format!("{}/tests/fixtures/coalesce.wat", env!("CARGO_MANIFEST_DIR"));
+    let original = wat::parse_file(&path).unwrap();
+    let options = TransformOptions {
+        track_changes: true,
+        ..Default::default()
+    };
+    let transformed = wasm_guardian::transform_wasm(&original, &options)
+        .unwrap()
+        .wasm;
+    let module = walrus::Module::from_buffer(&transformed).unwrap();
+    let on_store = module
+        .imports
+        .find("wasm_guardian", "on_store")
+        .map(|import| match module.imports.get(import).kind {
+            walrus::ImportKind::Function(function) => function,
+            _ => unreachable!(),
+        })
+        .unwrap();
+    let on_store_calls = |export: &str| {
+        let walrus::ExportItem::Function(function) = module
+            .exports
+            .iter()
+            .find(|e| e.name == export)
+            .unwrap()
+            .item
+        else {
+            unreachable!()
+        };
+        let mut counter = CountCalls {
+            function: on_store,
+            calls: 0,
+        };
+        let local = module.funcs.get(function).kind.unwrap_local();
+        walrus::ir::dfs_in_order(&mut counter, local, local.entry_block());
+        counter.calls
+    };
+
+    assert_eq!(on_store_calls("write_struct"), 1);
+    assert_eq!(on_store_calls("pointer_changes"), 2);
+    assert_eq!(on_store_calls("pointer_changes_in_value"), 2);
+    assert_eq!(on_store_calls("far_apart"), 2);
+    assert_eq!(on_store_calls("call_between"), 2);
+
+    // Loops report what they write before they run.
+    let stores = harness::reported_stores(&transformed);
+    let expected: [(&str, &[(u32, u32)]); 5] = [
+        ("loop", &[(4096, 64)]),
+        // $clobber's store comes after, on each iteration.
+        (
+            "loop_to_local",
+            &[
+                (5001, 30),
+                (2048, 4),
+                (2048, 4),
+                (2048, 4),
+                (2048, 4),
+                (2048, 4),
+            ],
+        ),
+        ("loop_tee", &[(7100, 18)]),
+        ("loop_runs_once", &[(6000, 8)]),
+        // Stepping wraps around, so all of the one page of memory is reported.
+        ("loop_wraps", &[(0, 65536)]),
+    ];
+    for (function, expected_stores) in expected {
+        let (_, stores) = stores.iter().find(|(name, _)| name == function).unwrap();
+        assert_eq!(stores, expected_stores, "{}", function);
+    }
+
+    let statistics = wasm_guardian::transform_wasm(&original, &options)
+        .unwrap()
+        .statistics
+        .unwrap();
+    // Stores a notification covers aren't counted.
+    assert_eq!(statistics.totals()["i32.store"], 12);
+}
+
+struct CountCalls {
+    function: walrus::FunctionId,
+    calls: usize,
+}
+
+impl<'instr> walrus::ir::Visitor<'instr> for CountCalls {
+    fn visit_call(&mut self, instr: &walrus::ir::Call) {
+        if instr.func == self.function {
+            self.calls += 1;
+        }
+    }
+}
+
+#[test]
+fn control() {
+    check_fixture("control");
+}
+
+#[test]
+fn traps() {
+    check_fixture("traps");
+}
@@ -0,0 +1,431 @@
+//! Runs a module before and after `transform_wasm` side by side in wasmi and checks that the
+//! transform doesn't change behavior and that the reported events cover every change.
+//!
+//! Shared by the fixture tests and the `transform` fuzz target, which each use part of it.
+
+#![allow(dead_code)]
+
+use wasm_guardian::GlobalName;
+use wasmi::{Caller, Config, Engine, Extern, Instance, Linker, Module, Store, Value};
+
+#[derive(Debug, Clone)]
+enum Event {
+    Store {
+        address: u32,
+        size: u32,
+    },
+    Load {
+        address: u32,
+        size: u32,
+    },
+    Grow {
+        pages: u32,
+    },
+    GlobalSet {
+        index: u32,
+    },
+    WatchHit {
+        func_index: i32,
+        address: u32,
+        size: u32,
+        value: u64,
+    },
+    /// The bytes passed to `env.external_log`.
+    Log(Vec<u8>),
+}
+
+struct Run {
+    store: Store<Vec<Event>>,
+    instance: Instance,
+}
+
+impl Run {
+    /// Fails if the module doesn't instantiate, including if its start function traps.
+    fn new(wasm: &[u8], fuel: Option<u64>) -> Result<Self, String> {
+        let mut config = Config::default();
+        config.consume_fuel(fuel.is_some());
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(|e| e.to_string())?;
+        let mut store = Store::new(&engine, Vec::new());
+        if let Some(fuel) = fuel {
+            store.add_fuel(fuel).unwrap();
+        }
+        let mut linker = Linker::<Vec<Event>>::new(&engine);
+        linker
+            .func_wrap(
+                "wasm_guardian",
+                "on_store",
+                |mut caller: Caller<'_, Vec<Event>>, address: i32, size: i32| {
+                    caller.data_mut().push(Event::Store {
+                        address: address as u32,
+                        size: size as u32,
+                    });
+                },
+            )
+            .unwrap();
+        linker
+            .func_wrap(
+                "wasm_guardian",
+                "on_load",
+                |mut caller: Caller<'_, Vec<Event>>, address: i32, size: i32| {
+                    caller.data_mut().push(Event::Load {
+                        address: address as u32,
+                        size: size as u32,
+                    });
+                },
+            )
+            .unwrap();
+        linker
+            .func_wrap(
+                "wasm_guardian",
+                "on_grow",
+                |mut caller: Caller<'_, Vec<Event>>, pages: i32| {
+                    caller.data_mut().push(Event::Grow {
+                        pages: pages as u32,
+                    });
+                },
+            )
+            .unwrap();
+        linker
+            .func_wrap(
+                "wasm_guardian",
+                "on_global_set",
+                |mut caller: Caller<'_, Vec<Event>>, index: i32| {
+                    caller.data_mut().push(Event::GlobalSet {
+                        index: index as u32,
+                    });
+                },
+            )
+            .unwrap();
+        linker
+            .func_wrap(
+                "wasm_guardian",
+                "on_watch_hit",
+                |mut caller: Caller<'_, Vec<Event>>,
+                 func_index: i32,
+                 address: i32,
+                 size: i32,
+                 value: i64| {
+                    caller.data_mut().push(Event::WatchHit {
+                        func_index,
+                        address: address as u32,
+                        size: size as u32,
+                        value: value as u64,
+                    });
+                },
+            )
+            .unwrap();
+        linker
+            .func_wrap(
+                "env",
+                "external_log",
+                |mut caller: Caller<'_, Vec<Event>>, ptr: i32, len: i32| {
+                    let memory = caller.get_export("memory").and_then(Extern::into_memory);
+                    let bytes = memory
+                        .and_then(|memory| {
+                            let data = memory.data(&caller);
+                            data.get(ptr as usize..)?.get(..len as usize)
+                        })
+                        .unwrap_or_default()
+                        .to_vec();
+                    caller.data_mut().push(Event::Log(bytes));
+                },
+            )
+            .unwrap();
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(|e| e.to_string())?;
+        Ok(Self { store, instance })
+    }
+
+    /// Exported function names, in export order.
+    fn functions(&self) -> Vec<String> {
+        self.instance
+            .exports(&self.store)
+            .filter_map(|export| {
+                let name = export.name().to_string();
+                export.into_func().map(|_| name)
+            })
+            .collect()
+    }
+
+    /// Calls with every argument zero. Returns the results as raw bits so that NaNs compare by
+    /// their payload, or the trap message.
+    fn call(&mut self, name: &str) -> Result<Vec<u64>, String> {
+        let function = self.instance.get_func(&self.store, name).unwrap();
+        let ty = function.ty(&self.store);
+        let args: Vec<Value> = ty.params().iter().map(|ty| Value::default(*ty)).collect();
+        let mut results: Vec<Value> = ty.results().iter().map(|ty| Value::default(*ty)).collect();
+        function
+            .call(&mut self.store, &args, &mut results)
+            .map_err(|e| e.to_string())?;
+        Ok(results.iter().filter_map(bits).collect())
+    }
+
+    /// The contents of the first exported memory.
+    fn memory(&self) -> Vec<u8> {
+        self.instance
+            .exports(&self.store)
+            .find_map(|export| export.into_memory())
+            .map(|memory| memory.data(&self.store).to_vec())
+            .unwrap_or_default()
+    }
+
+    /// The values of globals exported by `export_globals`, by their index in the original module.
+    fn globals(&self) -> Vec<(u32, u64)> {
+        self.instance
+            .exports(&self.store)
+            .filter_map(|export| {
+                let index = GlobalName::parse(export.name())?.index;
+                let Extern::Global(global) = export.into_extern() else {
+                    return None;
+                };
+                Some((index, bits(&global.get(&self.store))?))
+            })
+            .collect()
+    }
+}
+
+fn bits(value: &Value) -> Option<u64> {
+    match value {
+        Value::I32(v) => Some(*v as u32 as u64),
+        Value::I64(v) => Some(*v as u64),
+        Value::F32(v) => Some(v.to_bits() as u64),
+        Value::F64(v) => Some(v.to_bits()),
+        _ => None,
+    }
+}
+
+fn out_of_fuel(result: &Result<Vec<u64>, String>) -> bool {
+    matches!(result, Err(message) if message.contains("fuel"))
+}
+
+/// Calls every exported function once, in export order, on the same instance of each module.
+///
+/// After each call the results or trap, and memory, must match the original module's.
+/// Every byte that changed must be inside a reported `on_store`, memory size changes must be
+/// reported with `on_grow`, and every exported global that changed must be reported with
+/// `on_global_set`.
+///
+/// With `fuel`, checking stops at the first call that runs out in either module, because the
+/// transformed module runs more instructions.
+pub fn check_transform(context: &str, original: &[u8], transformed: &[u8], fuel: Option<u64>) {
+    let mut transformed = match (Run::new(original, fuel), Run::new(transformed, fuel)) {
+        (Ok(original), Ok(transformed)) => (original, transformed),
+        (Err(_), Err(_)) => return,
+        (Ok(_), Err(e)) => panic!(
+            "{}: only the transformed module failed to start: {}",
+            context, e
+        ),
+        (Err(e), Ok(_)) => panic!(
+            "{}: only the original module failed to start: {}",
+            context, e
+        ),
+    };
+    let (original, transformed) = (&mut transformed.0, &mut transformed.1);
+    let functions = original.functions();
+    assert_eq!(
+        functions,
+        transformed.functions()[..functions.len()],
+        "{}: exports differ",
+        context
+    );
+
+    for function in &functions {
+        let context = format!("{}: {}", context, function);
+        let memory_before = transformed.memory();
+        let globals_before = transformed.globals();
+        transformed.store.data_mut().clear();
+
+        let expected = original.call(function);
+        let actual = transformed.call(function);
+        if fuel.is_some() && (out_of_fuel(&expected) || out_of_fuel(&actual)) {
+            return;
+        }
+        assert_eq!(expected, actual, "{}: results differ", context);
+
+        let memory_after = transformed.memory();
+        assert!(
+            original.memory() == memory_after,
+            "{}: memory differs",
+            context
+        );
+
+        let events = transformed.store.data().clone();
+        if memory_after.len() != memory_before.len() {
+            let grown = events.iter().any(|event| match event {
+                Event::Grow { pages } => {
+                    memory_before.len() + *pages as usize * 65536 == memory_after.len()
+                }
+                _ => false,
+            });
+            assert!(grown, "{}: memory grew without on_grow", context);
+        }
+
+        // New pages start zeroed, so any non-zero byte in them was written.
+        for (address, byte) in memory_after.iter().enumerate() {
+            let changed = memory_before.get(address).copied().unwrap_or(0) != *byte;
+            let covered = events.iter().any(|event| match event {
+                Event::Store {
+                    address: start,
+                    size,
+                } => {
+                    let start = *start as usize;
+                    start <= address && address < start + *size as usize
+                }
+                _ => false,
+            });
+            assert!(
+                !changed || covered,
+                "{}: byte {} changed without on_store",
+                context,
+                address
+            );
+        }
+
+        for ((index, before), (_, after)) in globals_before.iter().zip(transformed.globals()) {
+            let reported = events
+                .iter()
+                .any(|event| matches!(event, Event::GlobalSet { index: i } if i == index));
+            assert!(
+                *before == after || reported,
+                "{}: global {} changed without on_global_set",
+                context,
+                index
+            );
+        }
+    }
+}
+
+/// The ranges reported with `on_load` by each exported function of a transformed module, as
+/// `(address, size)`. The functions are called one after another on the same instance.
+pub fn reported_loads(transformed: &[u8]) -> Vec<(String, Vec<(u32, u32)>)> {
+    reported_ranges(transformed, |event| match event {
+        Event::Load { address, size } => Some((*address, *size)),
+        _ => None,
+    })
+}
+
+/// The same as [reported_loads] for `on_store`.
+pub fn reported_stores(transformed: &[u8]) -> Vec<(String, Vec<(u32, u32)>)> {
+    reported_ranges(transformed, |event| match event {
+        Event::Store { address, size } => Some((*address, *size)),
+        _ => None,
+    })
+}
+
+fn reported_ranges(
+    transformed: &[u8],
+    range: fn(&Event) -> Option<(u32, u32)>,
+) -> Vec<(String, Vec<(u32, u32)>)> {
+    let mut run = Run::new(transformed, None).unwrap();
+    run.functions()
+        .into_iter()
+        .map(|function| {
+            run.store.data_mut().clear();
+            let _ = run.call(&function);
+            let ranges = run.store.data().iter().filter_map(range).collect();
+            (function, ranges)
+        })
+        .collect()
+}
+
+/// An `on_watch_hit` call's `(func_index, address, size, value)`.
+pub type WatchHit = (i32, u32, u32, u64);
+
+/// The `on_watch_hit` calls made by each exported function of a transformed module, after
+/// calling `wg_watch` with each of `watches`.
+pub fn watch_hits(transformed: &[u8], watches: &[(i32, i32, i32)]) -> Vec<(String, Vec<WatchHit>)> {
+    let mut run = Run::new(transformed, None).unwrap();
+    let watch = run
+        .instance
+        .get_typed_func::<(i32, i32, i32), ()>(&run.store, wasm_guardian::WATCH_EXPORT_NAME)
+        .unwrap();
+    for watched in watches {
+        watch.call(&mut run.store, *watched).unwrap();
+    }
+    run.functions()
+        .into_iter()
+        .filter(|function| function != wasm_guardian::WATCH_EXPORT_NAME)
+        .map(|function| {
+            run.store.data_mut().clear();
+            run.call(&function).unwrap();
+            let hits = run
+                .store
+                .data()
+                .iter()
+                .filter_map(|event| match event {
+                    Event::WatchHit {
+                        func_index,
+                        address,
+                        size,
+                        value,
+                    } => Some((*func_index, *address, *size, *value)),
+                    _ => None,
+                })
+                .collect();
+            (function, hits)
+        })
+        .collect()
+}
+
+/// Calls `wg_dispatch` with each `(index, args)` in turn, after setting the argument globals,
+/// and returns the status codes and the memory afterwards.
+pub fn dispatch(transformed: &[u8], calls: &[(i32, &[i64])]) -> (Vec<i32>, Vec<u8>) {
+    let mut run = Run::new(transformed, None).unwrap();
+    let dispatch = run
+        .instance
+        .get_typed_func::<(i32, i32), i32>(&run.store, wasm_guardian::DISPATCH_EXPORT_NAME)
+        .unwrap();
+    let statuses = calls
+        .iter()
+        .map(|(index, args)| {
+            for (i, arg) in args.iter().enumerate() {
+                let name = format!("{}{}", wasm_guardian::DISPATCH_ARG_GLOBAL_PREFIX, i);
+                if let Some(global) = run.instance.get_global(&run.store, &name) {
+                    global.set(&mut run.store, Value::I64(*arg)).unwrap();
+                }
+            }
+            dispatch
+                .call(&mut run.store, (*index, args.len() as i32))
+                .unwrap()
+        })
+        .collect();
+    (statuses, run.memory())
+}
+
+/// A function's results as raw bits, or its trap message.
+pub type CallResult = Result<Vec<u64>, String>;
+
+/// Calls exported functions with every argument zero, one after another on the same instance,
+/// and returns their results as raw bits or their trap messages.
+pub fn call_exports(wasm: &[u8], names: &[&str]) -> Vec<CallResult> {
+    let mut run = Run::new(wasm, None).unwrap();
+    names.iter().map(|name| run.call(name)).collect()
+}
+
+/// Sets exported globals, then calls exported functions like [call_exports]. Also returns the
+/// bytes passed to each `env.external_log` call.
+pub fn call_exports_logged(
+    wasm: &[u8],
+    globals: &[(&str, Value)],
+    names: &[&str],
+) -> (Vec<CallResult>, Vec<Vec<u8>>) {
+    let mut run = Run::new(wasm, None).unwrap();
+    for (name, value) in globals {
+        let global = run.instance.get_global(&run.store, name).unwrap();
+        global.set(&mut run.store, value.clone()).unwrap();
+    }
+    let results = names.iter().map(|name| run.call(name)).collect();
+    let logs = run
+        .store
+        .data()
+        .iter()
+        .filter_map(|event| match event {
+            Event::Log(bytes) => Some(bytes.clone()),
+            _ => None,
+        })
+        .collect();
+    (results, logs)
+}
@@ -0,0 +1,45 @@
+//! Calls `wg_dispatch` in the transformed `dispatch.wat` fixture.
+
+#[path = "differential/harness.rs"]
+mod harness;
+
+use wasm_guardian::{
+    DispatchOptions, TransformOptions, DISPATCH_BAD_ARGS, DISPATCH_NOT_ALLOWED, DISPATCH_OK,
+};
+
+#[test]
+fn dispatch() {
+    let path = format!("{}/tests/fixtures/dispatch.wat", env!("CARGO_MANIFEST_DIR"));
+    let original = wat::parse_file(&path).unwrap();
+    let options = TransformOptions {
+        dispatch: Some(DispatchOptions {
+            allowlist: vec!["store_args".to_string()],
+        }),
+        ..Default::default()
+    };
+    let transformed = wasm_guardian::transform_wasm(&original, &options)
+        .unwrap()
+        .wasm;
+
+    // Exports are indexed in the original module's order: `memory`, `store_args`, `hidden`.
+    let args = [7, 2.5f64.to_bits() as i64, 1.5f32.to_bits() as i64, -3];
+    let (statuses, memory) = harness::dispatch(
+        &transformed,
+        &[(1, &args), (1, &args[..3]), (2, &[]), (0, &[]), (99, &[])],
+    );
+    assert_eq!(
+        statuses,
+        [
+            DISPATCH_OK,
+            DISPATCH_BAD_ARGS,
+            DISPATCH_NOT_ALLOWED,
+            DISPATCH_NOT_ALLOWED,
+            DISPATCH_NOT_ALLOWED
+        ]
+    );
+    assert_eq!(memory[0..4], 7i32.to_le_bytes());
+    assert_eq!(memory[8..16], 2.5f64.to_le_bytes());
+    assert_eq!(memory[16..20], 1.5f32.to_le_bytes());
+    assert_eq!(memory[24..32], (-3i64).to_le_bytes());
+    assert_eq!(memory[32], 0);
+}
@@ -0,0 +1,15 @@
+;; A module whose start function and `main` initialize state, for baking snapshots into.
+(module
+  (memory (export "memory") 1)
+  (global $counter (export "counter") (mut i32) (i32.const 0))
+  (global $scale (export "scale") (mut f32) (f32.const 1))
+  (func $init
+    (global.set $counter (i32.const 10))
+    (i32.store (i32.const 64) (i32.const 5)))
+  (start $init)
+  (func (export "main")
+    (global.set $counter (i32.const 100)))
+  (func (export "get") (result i32)
+    (i32.add (global.get $counter) (i32.load (i32.const 64))))
+  (func (export "get_scale") (result f32)
+    (global.get $scale)))
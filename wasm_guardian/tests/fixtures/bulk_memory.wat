@@ -0,0 +1,22 @@
+;; memory.fill, memory.copy and memory.init, including zero length and overlapping copies, and
+;; lengths larger than the destination address.
+(module
+  (memory (export "memory") 1)
+  (data $greeting "hello, world")
+  (func (export "fill")
+    (memory.fill (i32.const 100) (i32.const 0x5A) (i32.const 50))
+    (memory.fill (i32.const 300) (i32.const 1) (i32.const 0)))
+  (func (export "fill_longer_than_address")
+    (memory.fill (i32.const 10) (i32.const 7) (i32.const 40)))
+  (func (export "copy")
+    (memory.copy (i32.const 200) (i32.const 100) (i32.const 20))
+    ;; Overlapping in both directions.
+    (memory.copy (i32.const 105) (i32.const 100) (i32.const 30))
+    (memory.copy (i32.const 95) (i32.const 100) (i32.const 30)))
+  (func (export "copy_longer_than_address")
+    (memory.copy (i32.const 2) (i32.const 100) (i32.const 60)))
+  (func (export "init")
+    (memory.init $greeting (i32.const 400) (i32.const 0) (i32.const 12))
+    (memory.init $greeting (i32.const 500) (i32.const 7) (i32.const 5)))
+  (func (export "fill_out_of_bounds")
+    (memory.fill (i32.const 65530) (i32.const 9) (i32.const 10))))
@@ -0,0 +1,80 @@
+;; Stores through the same pointer, which the transform reports together when it can.
+(module
+  (memory (export "memory") 1)
+  (func (export "write_struct")
+    (local $base i32)
+    (local.set $base (i32.const 64))
+    (i32.store offset=0 (local.get $base) (i32.const 1))
+    (i32.store16 offset=4 (local.get $base) (i32.const 2))
+    (i64.store offset=8 (local.get $base) (i64.load (i32.const 0)))
+    (i32.store8 offset=6 (local.get $base) (i32.const 3)))
+  (func (export "pointer_changes")
+    (local $p i32)
+    (local.set $p (i32.const 128))
+    (i32.store (local.get $p) (i32.const 1))
+    (local.set $p (i32.add (local.get $p) (i32.const 100)))
+    (i32.store (local.get $p) (i32.const 2)))
+  (func (export "pointer_changes_in_value")
+    (local $p i32)
+    (local.set $p (i32.const 256))
+    (i32.store (local.get $p) (i32.const 1))
+    ;; The address is read before $p changes.
+    (i32.store offset=4 (local.get $p) (local.tee $p (i32.const 512))))
+  (func (export "far_apart")
+    (local $p i32)
+    (local.set $p (i32.const 1024))
+    (i32.store (local.get $p) (i32.const 1))
+    (i32.store offset=1000 (local.get $p) (i32.const 2)))
+  (func $clobber (i32.store (i32.const 2048) (i32.const 9)))
+  (func (export "call_between")
+    (local $p i32)
+    (local.set $p (i32.const 3000))
+    (i32.store (local.get $p) (i32.const 1))
+    (call $clobber)
+    (i32.store offset=4 (local.get $p) (i32.const 2)))
+  (func (export "loop")
+    (local $p i32)
+    (local.set $p (i32.const 4096))
+    (loop $continue
+      (i32.store (local.get $p) (local.get $p))
+      (i32.store offset=4 (local.get $p) (i32.const 7))
+      (local.set $p (i32.add (local.get $p) (i32.const 8)))
+      (br_if $continue (i32.lt_u (local.get $p) (i32.const 4160)))))
+  (func (export "loop_to_local")
+    (local $p i32)
+    (local $end i32)
+    (local.set $p (i32.const 5000))
+    (local.set $end (i32.const 5030))
+    (loop $continue
+      (i32.store8 offset=1 (local.get $p) (i32.const 1))
+      (call $clobber)
+      (local.set $p (i32.add (local.get $p) (i32.const 6)))
+      ;; After stepping, so this writes the next iteration's first byte.
+      (i32.store8 (local.get $p) (i32.const 2))
+      (br_if $continue (i32.lt_u (local.get $p) (local.get $end)))))
+  (func (export "loop_tee")
+    (local $p i32)
+    (local.set $p (i32.const 7100))
+    (loop $continue
+      (i32.store16 (local.get $p) (i32.const 3))
+      (br_if $continue
+        (i32.lt_u (local.tee $p (i32.add (i32.const 4) (local.get $p))) (i32.const 7120)))))
+  (func (export "loop_runs_once")
+    (local $p i32)
+    (local.set $p (i32.const 6000))
+    (loop $continue
+      (i64.store (local.get $p) (i64.const -1))
+      (local.set $p (i32.add (local.get $p) (i32.const 8)))
+      (br_if $continue (i32.lt_u (local.get $p) (i32.const 100)))))
+  (func (export "loop_wraps")
+    (local $p i32)
+    (local.set $p (i32.const -8))
+    (loop $continue
+      (local.set $p (i32.add (local.get $p) (i32.const 8)))
+      (i64.store offset=7000 (local.get $p) (i64.const -1))
+      (br_if $continue (i32.lt_u (local.get $p) (i32.const 16)))))
+  (func (export "last_store_traps")
+    (local $p i32)
+    (local.set $p (i32.const 65530))
+    (i32.store (local.get $p) (i32.const 1))
+    (i32.store offset=4 (local.get $p) (i32.const 2))))
@@ -0,0 +1,42 @@
+;; Stores nested in blocks, loops, branches and calls, which are instrumented separately.
+(module
+  (memory (export "memory") 1)
+  (global $depth (mut i32) (i32.const 0))
+  (table 2 funcref)
+  (elem (i32.const 0) $store_pair $recurse)
+  (type $unary (func (param i32)))
+  (func $store_pair (param $at i32)
+    (i32.store (local.get $at) (local.get $at))
+    (i32.store offset=4 (local.get $at) (i32.const -1)))
+  (func $recurse (param $n i32)
+    (global.set $depth (local.get $n))
+    (if (local.get $n)
+      (then
+        (i32.store8 offset=1000 (local.get $n) (local.get $n))
+        (call $recurse (i32.sub (local.get $n) (i32.const 1))))))
+  (func (export "loop")
+    (local $i i32)
+    (loop $next
+      (i32.store16 offset=2000 (i32.shl (local.get $i) (i32.const 1)) (local.get $i))
+      (br_if $next (i32.lt_u (local.tee $i (i32.add (local.get $i) (i32.const 1))) (i32.const 64)))))
+  (func (export "branches")
+    (block $done
+      (block $two
+        (block $one
+          (br_table $one $two $done (i32.const 1)))
+        (i32.store (i32.const 3000) (i32.const 1)))
+      (i32.store (i32.const 3004) (i32.const 2)))
+    (if (i32.const 0)
+      (then (i32.store (i32.const 3008) (i32.const 3)))
+      (else (i32.store (i32.const 3012) (i32.const 4)))))
+  (func (export "calls")
+    (call $store_pair (i32.const 4000))
+    (call_indirect (type $unary) (i32.const 4100) (i32.const 0))
+    (call_indirect (type $unary) (i32.const 10) (i32.const 1)))
+  (func (export "stack_values") (result i32)
+    ;; Stores in the middle of an expression, with values left on the stack around them.
+    (i32.add
+      (i32.const 5)
+      (block (result i32)
+        (i32.store (i32.const 5000) (i32.const 6))
+        (i32.load (i32.const 5000))))))
@@ -0,0 +1,10 @@
+;; Exports called through `wg_dispatch`. Only `store_args` is on the allowlist.
+(module
+  (memory (export "memory") 1)
+  (func (export "store_args") (param i32 f64 f32 i64)
+    (i32.store (i32.const 0) (local.get 0))
+    (f64.store (i32.const 8) (local.get 1))
+    (f32.store (i32.const 16) (local.get 2))
+    (i64.store (i32.const 24) (local.get 3)))
+  (func (export "hidden")
+    (i32.store (i32.const 32) (i32.const 1))))
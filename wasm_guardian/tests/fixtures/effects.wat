@@ -0,0 +1,28 @@
+;; One export for each side effect, found through calls and tables as well as directly.
+;; `now` is recorded, so calling it isn't counted.
+(module
+  (import "env" "log" (func $log (param i32)))
+  (import "env" "now" (func $now (result f64)))
+  (memory (export "memory") 1)
+  (global $counter (mut i32) (i32.const 0))
+  (table 1 funcref)
+  (elem (i32.const 0) $store)
+  (type $void (func))
+  (func $store
+    (i32.store (i32.const 0) (i32.const 1)))
+  (func (export "pure") (result i32)
+    (i32.add (i32.load (i32.const 0)) (global.get $counter)))
+  (func (export "writes_memory")
+    (call $store))
+  (func (export "writes_memory_indirectly")
+    (call_indirect (type $void) (i32.const 0)))
+  (func (export "stores_lane")
+    (v128.store8_lane 0 (i32.const 0) (v128.const i64x2 0 0)))
+  (func (export "grows") (result i32)
+    (memory.grow (i32.const 1)))
+  (func (export "writes_globals")
+    (global.set $counter (i32.const 1)))
+  (func (export "calls_imports")
+    (call $log (i32.const 0)))
+  (func (export "calls_recorded_import") (result f64)
+    (call $now)))
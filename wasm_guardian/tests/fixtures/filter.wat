@@ -0,0 +1,20 @@
+;; `draw` is excluded from instrumentation. `shared` is still instrumented because `update`
+;; calls it, `draw_only` isn't, and `indirect` is because `update` can reach it through the table.
+(module
+  (memory (export "memory") 1)
+  (table 1 funcref)
+  (elem (i32.const 0) $indirect)
+  (type $void (func))
+  (func $shared
+    (i32.store (i32.const 0) (i32.const 1)))
+  (func $draw_only
+    (i32.store (i32.const 4) (i32.const 2)))
+  (func $indirect
+    (i32.store (i32.const 8) (i32.const 3)))
+  (func $update (export "update")
+    (call $shared)
+    (call_indirect (type $void) (i32.const 0)))
+  (func $draw (export "draw")
+    (call $shared)
+    (call $draw_only)
+    (i32.store (i32.const 12) (i32.const 4))))
@@ -0,0 +1,17 @@
+;; global.set on each value type. Immutable globals aren't exported or reported.
+(module
+  (memory (export "memory") 1)
+  (global $counter (mut i32) (i32.const 0))
+  (global $big (mut i64) (i64.const 0))
+  (global $f (mut f32) (f32.const 0))
+  (global $d (mut f64) (f64.const 0))
+  (global $constant i32 (i32.const 42))
+  (func (export "set_all")
+    (global.set $counter (i32.add (global.get $counter) (global.get $constant)))
+    (global.set $big (i64.const 0x100000000))
+    (global.set $f (f32.const -0))
+    (global.set $d (f64.const nan:0x4)))
+  (func (export "set_same_value")
+    (global.set $counter (global.get $counter)))
+  (func (export "get") (result i32 i64 f32 f64)
+    (global.get $counter) (global.get $big) (global.get $f) (global.get $d)))
@@ -0,0 +1,14 @@
+;; memory.grow and stores into the new pages.
+(module
+  (memory (export "memory") 1 4)
+  (func (export "grow") (result i32)
+    (memory.grow (i32.const 1)))
+  (func (export "store_in_new_page")
+    (i64.store (i32.const 70000) (i64.const -2))
+    (i32.store8 (i32.const 131071) (i32.const 1)))
+  (func (export "grow_past_maximum") (result i32)
+    (memory.grow (i32.const 10)))
+  (func (export "grow_zero") (result i32)
+    (memory.grow (i32.const 0)))
+  (func (export "size") (result i32)
+    (memory.size)))
@@ -0,0 +1,24 @@
+;; Loads of each kind, reported with `on_load` when reads are tracked.
+(module
+  (memory (export "memory") 1)
+  (func (export "load_struct") (result i32)
+    (local $base i32)
+    (local.set $base (i32.const 64))
+    (i32.add
+      (i32.add (i32.load offset=0 (local.get $base)) (i32.load16_u offset=4 (local.get $base)))
+      (i32.wrap_i64 (i64.load offset=8 (local.get $base)))))
+  (func (export "computed_address") (result f64)
+    (f64.load offset=3 (i32.add (i32.const 100) (i32.const 20))))
+  (func (export "copy")
+    (memory.copy (i32.const 300) (i32.const 200) (i32.const 16)))
+  (func (export "store_only")
+    (i32.store (i32.const 600) (i32.const 1)))
+  (func (export "sum") (result i32)
+    (local $p i32)
+    (local $sum i32)
+    (local.set $p (i32.const 800))
+    (loop $continue
+      (local.set $sum (i32.add (local.get $sum) (i32.load (local.get $p))))
+      (local.set $p (i32.add (local.get $p) (i32.const 4)))
+      (br_if $continue (i32.lt_u (local.get $p) (i32.const 840))))
+    (local.get $sum)))
@@ -0,0 +1,7 @@
+;; SIMD loads, which wasmi can't run, so the transformed module is only validated.
+(module
+  (memory (export "memory") 1)
+  (func (export "simd") (result v128)
+    (v128.load8_lane 2 (i32.const 400) (v128.load32_splat offset=4 (i32.const 500))))
+  (func (export "store_lane") (param v128)
+    (v128.store16_lane 1 (i32.const 400) (local.get 0))))
@@ -0,0 +1,27 @@
+;; Every store instruction, with and without an offset.
+(module
+  (memory (export "memory") 1)
+  (func (export "i32_store")
+    (i32.store (i32.const 0) (i32.const 0x01020304))
+    (i32.store offset=100 (i32.const 4) (i32.const -1)))
+  (func (export "i64_store")
+    (i64.store (i32.const 8) (i64.const 0x0102030405060708))
+    (i64.store offset=100 (i32.const 16) (i64.const -1)))
+  (func (export "f32_store")
+    (f32.store (i32.const 24) (f32.const 1.5))
+    (f32.store offset=3 (i32.const 28) (f32.const -nan:0x200001)))
+  (func (export "f64_store")
+    (f64.store (i32.const 40) (f64.const 2.25))
+    (f64.store offset=1 (i32.const 48) (f64.const inf)))
+  (func (export "narrow_stores")
+    (i32.store8 (i32.const 64) (i32.const 0xAB))
+    (i32.store16 (i32.const 66) (i32.const 0xABCD))
+    (i64.store8 (i32.const 70) (i64.const 0x12))
+    (i64.store16 (i32.const 72) (i64.const 0x1234))
+    (i64.store32 offset=4 (i32.const 72) (i64.const 0x12345678)))
+  (func (export "last_byte")
+    (i32.store8 (i32.const 65535) (i32.const 7)))
+  (func (export "read_back") (result i64)
+    (i64.add
+      (i64.load (i32.const 8))
+      (i64.extend_i32_u (i32.load16_u (i32.const 66))))))
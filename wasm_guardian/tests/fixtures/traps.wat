@@ -0,0 +1,18 @@
+;; Traps must happen in the same place, and stores before them must be kept.
+(module
+  (memory (export "memory") 1)
+  (global $g (mut i32) (i32.const 0))
+  (func (export "store_out_of_bounds")
+    (i32.store (i32.const 10) (i32.const 1))
+    (i32.store (i32.const 65533) (i32.const 2)))
+  (func (export "offset_out_of_bounds")
+    (i64.store offset=65535 (i32.const 0) (i64.const 3)))
+  (func (export "unreachable_after_writes")
+    (global.set $g (i32.const 9))
+    (i32.store (i32.const 20) (i32.const 4))
+    (unreachable))
+  (func (export "divide_by_zero") (result i32)
+    (i32.store (i32.const 30) (i32.const 5))
+    (i32.div_s (i32.const 1) (i32.const 0)))
+  (func (export "after_traps") (result i32)
+    (i32.add (global.get $g) (i32.load (i32.const 20)))))
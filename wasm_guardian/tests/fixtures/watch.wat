@@ -0,0 +1,21 @@
+;; Writes inside, next to and across watched ranges, checked with `on_watch_hit` when
+;; watchpoints are enabled. The tests watch [100, 104), [511, 512) and a cleared slot at 300.
+(module
+  (memory (export "memory") 1)
+  (func $set_field (param i32 f32)
+    (f32.store offset=4 (local.get 0) (local.get 1)))
+  (func (export "store_field")
+    (call $set_field (i32.const 96) (f32.const 1.5)))
+  (func (export "store_nearby")
+    (i32.store (i32.const 96) (i32.const 7))
+    (i64.store8 (i32.const 104) (i64.const 0x1ff)))
+  (func (export "store_across")
+    (i64.store (i32.const 98) (i64.const -1)))
+  (func (export "fill")
+    (memory.fill (i32.const 0) (i32.const 0xab) (i32.const 200)))
+  (func (export "empty_fill")
+    (memory.fill (i32.const 100) (i32.const 1) (i32.const 0)))
+  (func (export "narrow_store")
+    (i32.store16 (i32.const 510) (i32.const 0x12345)))
+  (func (export "cleared")
+    (i32.store (i32.const 300) (i32.const 1))))
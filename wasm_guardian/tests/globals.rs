@@ -0,0 +1,94 @@
+//! Export names for globals, and matching them between builds of a module.
+
+use wasm_guardian::{GlobalName, TransformOptions};
+
+#[test]
+fn global_names() {
+    let named = GlobalName {
+        index: 3,
+        name: Some("player_x".to_string()),
+    };
+    assert_eq!(named.export_name(), "wg_global_3_player_x");
+    assert_eq!(GlobalName::parse("wg_global_3_player_x"), Some(named));
+    assert_eq!(
+        GlobalName::parse("wg_global_12"),
+        Some(GlobalName {
+            index: 12,
+            name: None
+        })
+    );
+    for not_global in [
+        "wg_global_",
+        "wg_global_x",
+        "wg_global_x_1",
+        "wg_caller_id",
+        "a",
+    ] {
+        assert_eq!(GlobalName::parse(not_global), None, "{}", not_global);
+    }
+}
+
+/// The names of the globals `transform_wasm` exports from `wat`.
+fn global_exports(wat: &str) -> Vec<String> {
+    let options = TransformOptions {
+        export_globals: true,
+        invocation_context: true,
+        ..Default::default()
+    };
+    let wasm = wat::parse_str(wat).unwrap();
+    let wasm = wasm_guardian::transform_wasm(&wasm, &options).unwrap().wasm;
+    let module = walrus::Module::from_buffer(&wasm).unwrap();
+    module
+        .exports
+        .iter()
+        .filter(|e| matches!(e.item, walrus::ExportItem::Global(_)))
+        .map(|e| e.name.clone())
+        .collect()
+}
+
+#[test]
+fn map_global_exports() {
+    let saved = global_exports(
+        r#"(module
+            (global $a (mut i32) (i32.const 0))
+            (global (mut i32) (i32.const 0))
+            (global $c (mut i32) (i32.const 0))
+            (global $d (mut i32) (i32.const 0)))"#,
+    );
+    // `$a` moved after a new global, `$c` was renamed to `$e`, and the unnamed global kept its
+    // index.
+    let current = global_exports(
+        r#"(module
+            (global $new (mut i32) (i32.const 0))
+            (global (mut i32) (i32.const 0))
+            (global $a (mut i32) (i32.const 0))
+            (global $e (mut i32) (i32.const 0))
+            (global $d (mut i32) (i32.const 0)))"#,
+    );
+    assert_eq!(
+        wasm_guardian::map_global_exports(
+            saved.iter().map(String::as_str),
+            current.iter().map(String::as_str)
+        ),
+        [
+            ("wg_global_0_a", "wg_global_2_a"),
+            ("wg_global_1", "wg_global_1"),
+            ("wg_global_3_d", "wg_global_4_d"),
+        ]
+    );
+
+    // An unnamed global that moved can't be matched.
+    let moved = global_exports(
+        r#"(module
+            (global $new (mut i32) (i32.const 0))
+            (global $a (mut i32) (i32.const 0))
+            (global (mut i32) (i32.const 0)))"#,
+    );
+    assert_eq!(
+        wasm_guardian::map_global_exports(
+            saved.iter().map(String::as_str),
+            moved.iter().map(String::as_str)
+        ),
+        [("wg_global_0_a", "wg_global_1_a")]
+    );
+}
@@ -0,0 +1,100 @@
+//! Imports the transform can't handle, and the imports it adds.
+
+use wasm_guardian::{RecordedImport, TransformError, TransformOptions};
+
+#[test]
+fn unsupported_imports() {
+    let check = |wat: &str, options: TransformOptions, (module, name): (&str, &str)| {
+        let original = wat::parse_str(wat).unwrap();
+        match wasm_guardian::transform_wasm(&original, &options) {
+            Err(TransformError::UnsupportedImport {
+                module: m, name: n, ..
+            }) => assert_eq!((m.as_str(), n.as_str()), (module, name)),
+            Err(e) => panic!("{}.{}: {:?}", module, name, e),
+            Ok(_) => panic!("{}.{} was transformed", module, name),
+        }
+    };
+
+    check(
+        r#"(module (import "wasm_guardian" "caller_id" (func (result i32))))"#,
+        TransformOptions {
+            invocation_context: true,
+            ..Default::default()
+        },
+        ("wasm_guardian", "caller_id"),
+    );
+    check(
+        r#"(module (import "env" "pair" (func (result i32 i32))))"#,
+        TransformOptions {
+            recorded_imports: vec!["env.*".to_string()],
+            ..Default::default()
+        },
+        ("env", "pair"),
+    );
+    check(
+        r#"(module
+            (import "env" "external_log" (func (param i32)))
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func (param i32 i32 i32 i32) (result i32)))
+            (memory 1))"#,
+        TransformOptions {
+            wasi: true,
+            ..Default::default()
+        },
+        ("env", "external_log"),
+    );
+    for (wat, name) in [
+        (
+            r#"(module (import "wasi_snapshot_preview1" "fd_write" (func (param i32) (result i32))))"#,
+            "fd_write",
+        ),
+        (
+            r#"(module (import "wasi_snapshot_preview1" "clock_res_get" (func (param i32 i32) (result i32))))"#,
+            "clock_res_get",
+        ),
+        (
+            r#"(module (import "wasi_snapshot_preview1" "sched_yield" (func)))"#,
+            "sched_yield",
+        ),
+    ] {
+        let options = TransformOptions {
+            wasi: true,
+            ..Default::default()
+        };
+        check(wat, options, ("wasi_snapshot_preview1", name));
+    }
+}
+
+/// A `*` pattern records the module's own imports but not the `wasm_guardian` hooks.
+#[test]
+fn record_everything() {
+    let original = wat::parse_str(
+        r#"(module
+            (import "env" "now" (func (result f64)))
+            (memory 1)
+            (global $counter (mut i32) (i32.const 0))
+            (func (export "tick")
+                (global.set $counter (i32.const 1))
+                (drop (memory.grow (i32.const 1)))
+                (i32.store (i32.const 0) (i32.const 1))))"#,
+    )
+    .unwrap();
+    let options = TransformOptions {
+        export_globals: true,
+        track_changes: true,
+        recorded_imports: vec!["*".to_string()],
+        ..Default::default()
+    };
+    let now = RecordedImport {
+        module: "env".to_string(),
+        name: "now".to_string(),
+        result: Some(walrus::ValType::F64),
+    };
+    let output = wasm_guardian::transform_wasm(&original, &options).unwrap();
+    assert_eq!(output.manifest.recorded_imports, vec![now.clone()]);
+
+    // An already transformed module imports `record_result_f64` and `replay_result_f64` too.
+    let again = wasm_guardian::transform_wasm(&output.wasm, &options).unwrap();
+    assert_eq!(again.wasm, output.wasm);
+    assert_eq!(again.manifest.recorded_imports, [now]);
+}
@@ -0,0 +1,49 @@
+//! Checks which functions in `filter.wat` an [InstrumentationFilter] instruments.
+
+use wasm_guardian::{InstrumentationFilter, TransformOptions};
+
+#[test]
+fn instrumentation_filter() {
+    let path = format!("{}/tests/fixtures/filter.wat", env!("CARGO_MANIFEST_DIR"));
+    let original = wat::parse_file(&path).unwrap();
+    let instrumented = |filter: InstrumentationFilter| {
+        let options = TransformOptions {
+            track_changes: true,
+            instrumentation_filter: Some(filter),
+            ..Default::default()
+        };
+        let statistics = wasm_guardian::transform_wasm(&original, &options)
+            .unwrap()
+            .statistics
+            .unwrap();
+        let mut names: Vec<String> = statistics
+            .functions
+            .into_iter()
+            .filter_map(|function| function.name)
+            .collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(
+        instrumented(InstrumentationFilter {
+            exclude: vec!["draw".to_string()],
+            ..Default::default()
+        }),
+        ["indirect", "shared"]
+    );
+    assert_eq!(
+        instrumented(InstrumentationFilter {
+            include: vec!["#1".to_string()],
+            ..Default::default()
+        }),
+        ["draw_only"]
+    );
+    assert_eq!(
+        instrumented(InstrumentationFilter {
+            exclude: vec!["up*".to_string()],
+            ..Default::default()
+        }),
+        ["draw", "draw_only", "shared"]
+    );
+}
@@ -0,0 +1,39 @@
+//! Checks the JSON hosts read a transformed module's [Manifest] from.
+
+use wasm_guardian::{Manifest, TransformOptions};
+
+#[test]
+fn manifest_json() {
+    let original = wat::parse_str(
+        r#"(module
+            (import "env" "now" (func $now (result f64)))
+            (memory (export "memory") 1 4)
+            (table (export "table") 1 funcref)
+            (global $score (export "score") (mut i32) (i32.const 0))
+            (data (i32.const 16) "abc")
+            (func (export "quoted \"name\"\n") (param i32 i64) (result f64)
+                (global.set $score (local.get 0))
+                (call $now)))"#,
+    )
+    .unwrap();
+    let options = TransformOptions {
+        recorded_imports: vec!["env.now".to_string()],
+        ..Default::default()
+    };
+    let manifest: Manifest = wasm_guardian::transform_wasm(&original, &options)
+        .unwrap()
+        .manifest;
+    assert_eq!(
+        manifest.to_json(),
+        concat!(
+            r#"{"exports":["#,
+            r#"{"name":"memory","kind":"memory","initial_pages":1,"maximum_pages":4,"shared":false},"#,
+            r#"{"name":"table","kind":"table"},"#,
+            r#"{"name":"score","kind":"global","type":"i32","mutable":true,"index":0,"original_name":"score"},"#,
+            r#"{"name":"quoted \"name\"\u000a","kind":"function","params":["i32","i64"],"results":["f64"],"side_effects":["writes_globals"]},"#,
+            r#"{"name":"wg_replaying","kind":"global","type":"i32","mutable":true,"index":1,"original_name":null}"#,
+            r#"],"data_segments":[{"offset":16,"length":3}],"#,
+            r#""recorded_imports":[{"module":"env","name":"now","result":"f64"}]}"#,
+        )
+    );
+}
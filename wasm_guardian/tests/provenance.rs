@@ -0,0 +1,43 @@
+//! Reads back the provenance section `transform_wasm` adds.
+
+use wasm_guardian::{DispatchOptions, InstrumentationFilter, TransformError, TransformOptions};
+
+#[test]
+fn provenance() {
+    let wasm =
+        wat::parse_str(r#"(module (memory (export "memory") 1) (func (export "a")))"#).unwrap();
+    let options = TransformOptions {
+        export_globals: true,
+        track_reads: true,
+        watchpoints: true,
+        recorded_imports: vec!["env.*".to_string()],
+        dispatch: Some(DispatchOptions {
+            allowlist: vec!["a".to_string()],
+        }),
+        instrumentation_filter: Some(InstrumentationFilter {
+            include: vec!["a".to_string()],
+            exclude: vec!["#0".to_string()],
+        }),
+        ..Default::default()
+    };
+    let transformed = wasm_guardian::transform_wasm(&wasm, &options).unwrap().wasm;
+    let provenance = wasm_guardian::Provenance::read(&transformed).unwrap();
+    assert_eq!(provenance.options, options);
+    assert_eq!(
+        provenance.original_hash,
+        wasm_guardian::semantic_hash(&wasm)
+    );
+
+    // A section from an older format isn't read, but still stops the module being transformed.
+    let mut old_format = walrus::Module::from_buffer(&wasm).unwrap();
+    old_format.customs.add(walrus::RawCustomSection {
+        name: wasm_guardian::PROVENANCE_SECTION_NAME.to_string(),
+        data: vec![1, 0],
+    });
+    let old_format = old_format.emit_wasm();
+    assert_eq!(wasm_guardian::Provenance::read(&old_format), None);
+    assert_eq!(
+        wasm_guardian::transform_wasm(&old_format, &options).err(),
+        Some(TransformError::UnknownProvenance)
+    );
+}
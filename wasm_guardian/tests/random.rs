@@ -0,0 +1,65 @@
+//! Runs the PCG32 generator that `deterministic_random` compiles into a module.
+
+#[path = "differential/harness.rs"]
+mod harness;
+
+use wasm_guardian::TransformOptions;
+
+/// `bytes` fills 4 bytes with `random_get` and returns them, and `number` calls `Math.random`.
+fn transformed() -> Vec<u8> {
+    let original = wat::parse_str(
+        r#"(module
+            (import "Math" "random" (func $random (result f64)))
+            (import "wasi_snapshot_preview1" "random_get"
+                (func $random_get (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "bytes") (result i32)
+                (drop (call $random_get (i32.const 0) (i32.const 4)))
+                (i32.load (i32.const 0)))
+            (func (export "number") (result f64)
+                (call $random)))"#,
+    )
+    .unwrap();
+    let options = TransformOptions {
+        deterministic_random: true,
+        ..Default::default()
+    };
+    wasm_guardian::transform_wasm(&original, &options)
+        .unwrap()
+        .wasm
+}
+
+/// The draws after seeding with 0, from PCG's reference `pcg32_srandom_r(0, ..)` with the
+/// generator's increment: `0xe823a24e`, `0x7a7ecbd9`, `0x89fd6c06`, `0xae646aa8`, then
+/// `0xcd3cf945` and `0x6204b303` for the `f64`.
+#[test]
+fn golden_sequence() {
+    let results = harness::call_exports(
+        &transformed(),
+        &[wasm_guardian::SEED_RANDOM_EXPORT_NAME, "bytes", "number"],
+    );
+    assert_eq!(
+        results,
+        [
+            Ok(vec![]),
+            // The low byte of each draw.
+            Ok(vec![0xa806d94e]),
+            Ok(vec![0.8017116366541699f64.to_bits()])
+        ]
+    );
+}
+
+#[test]
+fn same_seed_same_draws() {
+    let transformed = transformed();
+    let draws = [
+        wasm_guardian::SEED_RANDOM_EXPORT_NAME,
+        "number",
+        "bytes",
+        "number",
+    ];
+    let first = harness::call_exports(&transformed, &draws);
+    assert_eq!(harness::call_exports(&transformed, &draws), first);
+    // Without seeding the generator starts from another state.
+    assert_ne!(harness::call_exports(&transformed, &draws[1..]), first[1..]);
+}
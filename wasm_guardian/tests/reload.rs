@@ -0,0 +1,123 @@
+//! Compares versions of a module with [wasm_guardian::compare_modules].
+
+use wasm_guardian::{Incompatibility, ReloadError, TransformError, TransformOptions};
+
+/// The options Tangle transforms modules with.
+fn transform(wat: &str) -> Vec<u8> {
+    let options = TransformOptions {
+        export_globals: true,
+        invocation_context: true,
+        deterministic_random: true,
+        ..Default::default()
+    };
+    let wasm = wat::parse_str(wat).unwrap();
+    wasm_guardian::transform_wasm(&wasm, &options).unwrap().wasm
+}
+
+#[test]
+fn reload() {
+    let old = wat::parse_str(r#"(module (memory (export "memory") 1))"#).unwrap();
+    let new = wat::parse_str(r#"(module (memory (export "memory") 1 2))"#).unwrap();
+
+    let report = wasm_guardian::compare_modules(&old, &new, 2)
+        .unwrap()
+        .report;
+    assert!(report.is_compatible());
+    // The running memory has grown past the new maximum.
+    let report = wasm_guardian::compare_modules(&old, &new, 3)
+        .unwrap()
+        .report;
+    assert_eq!(report.incompatibilities, [Incompatibility::MemoryTooSmall]);
+
+    assert_eq!(
+        wasm_guardian::compare_modules(b"not wasm", &new, 1).err(),
+        Some(ReloadError::InvalidOldModule)
+    );
+    assert_eq!(
+        wasm_guardian::compare_modules(&old, b"not wasm", 1).err(),
+        Some(ReloadError::InvalidNewModule)
+    );
+}
+
+#[test]
+fn reload_globals() {
+    let old = transform(
+        r#"(module
+            (global $a (mut i32) (i32.const 1))
+            (global $c (mut f64) (f64.const 0)))"#,
+    );
+    let compare = |new: &str| {
+        let new = wat::parse_str(new).unwrap();
+        wasm_guardian::compare_modules(&old, &new, 0)
+            .unwrap()
+            .report
+    };
+
+    // `$b` comes before `$a`, and before the globals the transform adds.
+    let inserted = compare(
+        r#"(module
+            (global $b (mut i32) (i32.const 0))
+            (global $a (mut i32) (i32.const 1))
+            (global $c (mut f64) (f64.const 0)))"#,
+    );
+    assert!(inserted.is_compatible());
+    assert_eq!(inserted.globals, [(0, 1), (1, 2)]);
+
+    let removed = compare(r#"(module (global $c (mut f64) (f64.const 0)))"#);
+    assert_eq!(
+        removed.incompatibilities,
+        [Incompatibility::GlobalRemoved(0)]
+    );
+    assert_eq!(removed.globals, [(1, 0)]);
+
+    let type_changed = compare(
+        r#"(module
+            (global $a (mut i64) (i64.const 1))
+            (global $c (mut f64) (f64.const 0)))"#,
+    );
+    assert_eq!(
+        type_changed.incompatibilities,
+        [Incompatibility::GlobalTypeChanged {
+            old_index: 0,
+            new_index: 0
+        }]
+    );
+}
+
+#[test]
+fn reload_exports() {
+    let old = transform(r#"(module (func (export "run")))"#);
+    let new = wat::parse_str(r#"(module (func (export "start")))"#).unwrap();
+    let output = wasm_guardian::compare_modules(&old, &new, 0).unwrap();
+    assert_eq!(
+        output.report.incompatibilities,
+        [Incompatibility::ExportRemoved("run".to_string())]
+    );
+
+    // The new module is transformed like the old one.
+    let new = walrus::Module::from_buffer(&output.wasm).unwrap();
+    let exports: Vec<&str> = new.exports.iter().map(|e| e.name.as_str()).collect();
+    assert!(exports.contains(&wasm_guardian::CALLER_ID_GLOBAL));
+    assert_eq!(
+        wasm_guardian::Provenance::read(&output.wasm)
+            .unwrap()
+            .options,
+        wasm_guardian::Provenance::read(&old).unwrap().options
+    );
+
+    // A new module transformed with other options isn't transformed again.
+    let other_options = TransformOptions {
+        track_changes: true,
+        ..Default::default()
+    };
+    let new = wat::parse_str(r#"(module (func (export "run")))"#).unwrap();
+    let new = wasm_guardian::transform_wasm(&new, &other_options)
+        .unwrap()
+        .wasm;
+    assert!(matches!(
+        wasm_guardian::compare_modules(&old, &new, 0).err(),
+        Some(ReloadError::Transform(TransformError::AlreadyTransformed(
+            _
+        )))
+    ));
+}
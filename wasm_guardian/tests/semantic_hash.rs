@@ -0,0 +1,46 @@
+//! Checks which changes to a module change its [wasm_guardian::semantic_hash].
+
+use wasm_guardian::TransformOptions;
+
+#[test]
+fn semantic_hash() {
+    let a = wat::parse_str(r#"(module (func $a (export "a")))"#).unwrap();
+    let unnamed_a = wat::parse_str(r#"(module (func (export "a")))"#).unwrap();
+    let b = wat::parse_str(r#"(module (func (export "b")))"#).unwrap();
+    let options = TransformOptions {
+        export_globals: true,
+        ..Default::default()
+    };
+    let transform = |wasm: &[u8]| wasm_guardian::transform_wasm(wasm, &options).unwrap().wasm;
+
+    assert_eq!(
+        wasm_guardian::semantic_hash(&a),
+        wasm_guardian::semantic_hash(&unnamed_a)
+    );
+    assert_eq!(
+        wasm_guardian::semantic_hash(&transform(&a)),
+        wasm_guardian::semantic_hash(&transform(&unnamed_a))
+    );
+
+    assert_eq!(
+        wasm_guardian::semantic_hash(&transform(&a)),
+        wasm_guardian::semantic_hash(&a)
+    );
+    assert_ne!(
+        wasm_guardian::semantic_hash(&a),
+        wasm_guardian::semantic_hash(&b)
+    );
+
+    // The recorded hash isn't checked, so a module can claim another module's hash.
+    let mut transformed_a = walrus::Module::from_buffer(&transform(&a)).unwrap();
+    let provenance = transformed_a
+        .customs
+        .remove_raw(wasm_guardian::PROVENANCE_SECTION_NAME)
+        .unwrap();
+    let mut forged = walrus::Module::from_buffer(&b).unwrap();
+    forged.customs.add(provenance);
+    assert_eq!(
+        wasm_guardian::semantic_hash(&forged.emit_wasm()),
+        wasm_guardian::semantic_hash(&a)
+    );
+}
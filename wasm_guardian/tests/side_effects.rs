@@ -0,0 +1,50 @@
+//! Checks the [SideEffects] found for each export of `effects.wat`.
+
+use wasm_guardian::{ExportKind, SideEffects, TransformOptions};
+
+#[test]
+fn side_effects() {
+    let path = format!("{}/tests/fixtures/effects.wat", env!("CARGO_MANIFEST_DIR"));
+    let original = wat::parse_file(&path).unwrap();
+    let options = TransformOptions {
+        export_globals: true,
+        track_changes: true,
+        recorded_imports: vec!["env.now".to_string()],
+        ..Default::default()
+    };
+    let manifest = wasm_guardian::transform_wasm(&original, &options)
+        .unwrap()
+        .manifest;
+    let side_effects = |name: &str| {
+        let export = manifest.exports.iter().find(|e| e.name == name).unwrap();
+        match &export.kind {
+            ExportKind::Function { side_effects, .. } => *side_effects,
+            kind => panic!("{} isn't a function: {:?}", name, kind),
+        }
+    };
+    let writes_memory = SideEffects {
+        writes_memory: true,
+        ..Default::default()
+    };
+
+    assert!(side_effects("pure").is_pure());
+    assert!(side_effects("calls_recorded_import").is_pure());
+    assert_eq!(side_effects("writes_memory"), writes_memory);
+    assert_eq!(side_effects("writes_memory_indirectly"), writes_memory);
+    assert_eq!(side_effects("stores_lane"), writes_memory);
+    assert_eq!(side_effects("grows"), writes_memory);
+    assert_eq!(
+        side_effects("writes_globals"),
+        SideEffects {
+            writes_globals: true,
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        side_effects("calls_imports"),
+        SideEffects {
+            calls_imports: true,
+            ..Default::default()
+        }
+    );
+}
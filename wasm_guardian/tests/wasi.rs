@@ -0,0 +1,59 @@
+//! Runs the WASI imports that the `wasi` option implements inside the module.
+
+#[path = "differential/harness.rs"]
+mod harness;
+
+use wasm_guardian::{TransformOptions, ERRNO_BADF, EVENT_TIME_GLOBAL};
+use wasmi::Value;
+
+#[test]
+fn wasi() {
+    let original = wat::parse_str(
+        r#"(module
+            (import "wasi_snapshot_preview1" "fd_write"
+                (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "clock_time_get"
+                (func $clock_time_get (param i32 i64 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 16) "hello, world")
+            ;; Two iovecs at 0, with the number of bytes written at 32.
+            (func $write (param $fd i32) (result i32)
+                (i32.store (i32.const 0) (i32.const 16))
+                (i32.store (i32.const 4) (i32.const 5))
+                (i32.store (i32.const 8) (i32.const 21))
+                (i32.store (i32.const 12) (i32.const 7))
+                (call $fd_write (local.get $fd) (i32.const 0) (i32.const 2) (i32.const 32)))
+            (func (export "write_stdout") (result i32 i32)
+                (call $write (i32.const 1))
+                (i32.load (i32.const 32)))
+            (func (export "write_file") (result i32)
+                (call $write (i32.const 3)))
+            (func (export "now") (result i32 i64)
+                (call $clock_time_get (i32.const 0) (i64.const 0) (i32.const 40))
+                (i64.load (i32.const 40))))"#,
+    )
+    .unwrap();
+    let options = TransformOptions {
+        wasi: true,
+        ..Default::default()
+    };
+    let transformed = wasm_guardian::transform_wasm(&original, &options)
+        .unwrap()
+        .wasm;
+
+    let (results, logs) = harness::call_exports_logged(
+        &transformed,
+        &[(EVENT_TIME_GLOBAL, Value::F64(1.5.into()))],
+        &["write_stdout", "write_file", "now"],
+    );
+    assert_eq!(
+        results,
+        [
+            Ok(vec![0, 12]),
+            Ok(vec![ERRNO_BADF as u64]),
+            // The event time is in milliseconds and the clock in nanoseconds.
+            Ok(vec![0, 1_500_000])
+        ]
+    );
+    assert_eq!(logs, [b"hello".to_vec(), b", world".to_vec()]);
+}